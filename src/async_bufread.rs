@@ -0,0 +1,267 @@
+//! Implementations of `AsyncRead` which take their input from an
+//! `AsyncBufRead`, like those in `bufread`.  Requires the `futures-io`
+//! feature.
+//!
+//! These coders work directly from the source's buffer whenever it holds
+//! a complete chunk, instead of copying input into a buffer of their own.
+//! This saves a copy per chunk when reading from a `BufReader` or an
+//! in-memory slice.
+
+use futures_io::{AsyncBufRead, AsyncRead};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use buffer::copy_output;
+use bufread::take_chunk;
+use compression::Compression;
+use read::CrcMode;
+use sans_io::{ChunkDecoder, ChunkEncoder, DecoderEvent};
+
+/// Decode a Snappy framed stream read from an `AsyncBufRead`.
+pub struct SnappyFramedDecoder<R: AsyncBufRead + Unpin> {
+    source: R,
+    /// Interprets each chunk, and holds any chunk which was split across
+    /// calls to `poll_fill_buf`.
+    state: ChunkDecoder,
+    /// Decompressed data.
+    output: Vec<u8>,
+    /// How much of `output` has been returned.
+    pos: usize
+}
+
+impl<R: AsyncBufRead + Unpin> SnappyFramedDecoder<R> {
+    /// Create a new decoder wrapping the specified `source`, and using the
+    /// CRC verification options indicated by `mode`.
+    pub fn new(source: R, mode: CrcMode) -> Self {
+        SnappyFramedDecoder{
+            source: source,
+            state: ChunkDecoder::new(mode),
+            output: vec!(),
+            pos: 0
+        }
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// Get a mutable reference to our source.  Reading from it directly
+    /// will corrupt our stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// The state machine which interprets our input.  Use this to set any
+    /// of the decoding options it supports.
+    pub fn chunk_decoder_mut(&mut self) -> &mut ChunkDecoder {
+        &mut self.state
+    }
+
+    /// Unwrap this decoder, returning our source.  Any data which we've
+    /// decompressed but not yet returned is lost.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Decode chunks until we find one containing data.  Returns `false`
+    /// at the end of the stream.
+    fn poll_fill_output(&mut self, cx: &mut Context) ->
+        Poll<io::Result<bool>>
+    {
+        self.output.clear();
+        self.pos = 0;
+        while self.output.is_empty() {
+            // Decode any chunk we've finished copying.
+            match self.state.next_event() {
+                Ok(Some(DecoderEvent::Data(data))) => {
+                    self.output = data;
+                    continue;
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => {}
+                Err(err) => return Poll::Ready(Err(err.into()))
+            }
+            let used = {
+                let available =
+                    match Pin::new(&mut self.source).poll_fill_buf(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Ready(Ok(available)) => available
+                    };
+                if available.is_empty() {
+                    let finished = self.state.finish();
+                    return Poll::Ready(finished.map(|()| false)
+                                       .map_err(|err| err.into()));
+                }
+                let taken =
+                    take_chunk(&mut self.state, &mut self.output, available);
+                match taken {
+                    Ok(used) => used,
+                    Err(err) => return Poll::Ready(Err(err))
+                }
+            };
+            Pin::new(&mut self.source).consume(used);
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for SnappyFramedDecoder<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) ->
+        Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        if this.pos == this.output.len() {
+            match this.poll_fill_output(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(false)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(true)) => {}
+            }
+        }
+        Poll::Ready(Ok(copy_output(&this.output, &mut this.pos, buf)))
+    }
+}
+
+/// Compress data read from an `AsyncBufRead`, so that reading from the
+/// encoder returns a Snappy framed stream.
+pub struct SnappyFramedEncoder<R: AsyncBufRead + Unpin> {
+    source: R,
+    /// Compresses whole chunks straight from our source's buffer, and
+    /// holds any chunk which was split across calls to `poll_fill_buf`.
+    encoder: ChunkEncoder,
+    /// Compressed chunks.
+    output: Vec<u8>,
+    /// How much of `output` has been returned.
+    pos: usize
+}
+
+impl<R: AsyncBufRead + Unpin> SnappyFramedEncoder<R> {
+    /// Create a new encoder reading from `source`.
+    pub fn new(source: R) -> Self {
+        SnappyFramedEncoder::with_compression(source, Compression::default())
+    }
+
+    /// Create a new encoder reading from `source`, and using the options
+    /// in `compression`.
+    pub fn with_compression(source: R, compression: Compression) -> Self {
+        SnappyFramedEncoder{
+            source: source,
+            encoder: ChunkEncoder::with_compression(compression),
+            output: vec!(),
+            pos: 0
+        }
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// Get a mutable reference to our source.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// Unwrap this encoder, returning our source.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Encode input until we have some output.  Returns `false` at the
+    /// end of our input.
+    fn poll_fill_output(&mut self, cx: &mut Context) ->
+        Poll<io::Result<bool>>
+    {
+        while !self.encoder.has_output() {
+            let used = {
+                let available =
+                    match Pin::new(&mut self.source).poll_fill_buf(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Ready(Ok(available)) => available
+                    };
+                if available.is_empty() {
+                    self.encoder.flush();
+                    if !self.encoder.has_output() {
+                        return Poll::Ready(Ok(false));
+                    }
+                    break;
+                }
+                self.encoder.take_input(available)
+            };
+            Pin::new(&mut self.source).consume(used);
+        }
+        self.output = self.encoder.take_output();
+        self.pos = 0;
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for SnappyFramedEncoder<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) ->
+        Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        if this.pos == this.output.len() {
+            match this.poll_fill_output(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(false)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(true)) => {}
+            }
+        }
+        Poll::Ready(Ok(copy_output(&this.output, &mut this.pos, buf)))
+    }
+}
+
+#[test]
+fn async_round_trip_through_small_buffers() {
+    use std::io::Read;
+    use bufread;
+    use test_helpers::*;
+
+    /// Read everything from `reader`.
+    fn read_all<R: AsyncRead + Unpin>(mut reader: R) -> io::Result<Vec<u8>> {
+        let mut output = vec!();
+        let mut buf = [0; 5000];
+        loop {
+            let n = try!(poll_until_ready(|cx| {
+                Pin::new(&mut reader).poll_read(cx, &mut buf)
+            }));
+            if n == 0 { return Ok(output); }
+            output.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    let input = mixed_data(100_000);
+    let compression = Compression::default().with_chunk_size(10_000);
+    let mut expected = vec!();
+    bufread::SnappyFramedEncoder::with_compression(&input as &[u8],
+                                                   compression)
+        .read_to_end(&mut expected).unwrap();
+
+    // Use buffers much smaller than a chunk, so every chunk is split.
+    let source = Stuttering::new(&input as &[u8], 1000);
+    let encoder = SnappyFramedEncoder::with_compression(source, compression);
+    let compressed = read_all(encoder).unwrap();
+    assert_eq!(expected, compressed);
+
+    let source = Stuttering::new(&compressed as &[u8], 1000);
+    let decoder = SnappyFramedDecoder::new(source, CrcMode::Verify);
+    assert_eq!(input, read_all(decoder).unwrap());
+
+    // Slices hold everything, so chunks are always read in place.
+    let encoder = SnappyFramedEncoder::with_compression(&input as &[u8],
+                                                        compression);
+    assert_eq!(expected, read_all(encoder).unwrap());
+    let decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                           CrcMode::Verify);
+    assert_eq!(input, read_all(decoder).unwrap());
+
+    let truncated = &compressed[..compressed.len() - 1];
+    let decoder = SnappyFramedDecoder::new(truncated, CrcMode::Verify);
+    assert!(read_all(decoder).is_err());
+}
@@ -0,0 +1,813 @@
+//! Encoders and decoders implementing the `AsyncRead` and `AsyncWrite`
+//! traits from `futures-io`, for use with any async runtime.  Requires
+//! the `futures-io` feature.
+//!
+//! These are thin wrappers around the state machines in `sans_io`.
+//! By default, compression and decompression happen inline, one chunk at
+//! a time, so each call to `poll_read` or `poll_write` does at most one
+//! chunk's worth of CPU work.  To keep even that off the executor's
+//! threads, pass an `Offload` to `set_offload`.
+//!
+//! To make sure that data written slowly doesn't sit in the encoder's
+//! buffer for long, use `set_max_linger` and poll `poll_linger`.
+//!
+//! With the `sink` feature, the encoder is also a `Sink<Bytes>`, which
+//! writes each payload sent to it as one or more data chunks of its own.
+
+#[cfg(feature = "sink")] use bytes::Bytes;
+use futures_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "sink")] use futures_sink::Sink;
+use std::cmp::{max, min};
+use std::future::Future;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use buffer::copy_output;
+use compression::Compression;
+use read::CrcMode;
+use error::SnappyFramedError;
+use sans_io::{ChunkDecoder, ChunkEncoder, DecoderEvent};
+
+/// How much we try to read from our source at once.
+const READ_SIZE: usize = 16 * 1024;
+
+/// Runs CPU-bound jobs away from the async executor's threads.  With
+/// tokio, for example:
+///
+/// ```ignore
+/// struct SpawnBlocking;
+///
+/// impl Offload for SpawnBlocking {
+///     fn spawn(&self, job: Box<dyn FnOnce() + Send>) {
+///         tokio::task::spawn_blocking(job);
+///     }
+/// }
+/// ```
+pub trait Offload {
+    /// Run `job` on some other thread.  It wakes the task waiting for it
+    /// when it's done.
+    fn spawn(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+/// A future which completes when a `Timer` goes off.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+/// Creates timers for `SnappyFramedEncoder::set_max_linger`.  With
+/// tokio, for example:
+///
+/// ```ignore
+/// struct TokioTimer;
+///
+/// impl Timer for TokioTimer {
+///     fn sleep(&self, duration: Duration) -> Sleep {
+///         Box::pin(tokio::time::sleep(duration))
+///     }
+/// }
+/// ```
+pub trait Timer {
+    /// Return a future which completes after `duration`.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+fn worker_failed() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "offloaded Snappy job failed")
+}
+
+/// What an offloaded job sends back to the task polling it.
+struct JobSlot<T> {
+    /// `Some(None)` if the job panicked.
+    result: Option<Option<T>>,
+    waker: Option<Waker>
+}
+
+/// A job handed to an `Offload`, which we can poll for its result.
+struct Job<T> {
+    slot: Arc<Mutex<JobSlot<T>>>
+}
+
+impl<T: Send + 'static> Job<T> {
+    fn spawn<F>(offload: &dyn Offload, work: F) -> Job<T>
+        where F: FnOnce() -> T + Send + 'static
+    {
+        let slot = Arc::new(Mutex::new(JobSlot{result: None, waker: None}));
+        let job_slot = slot.clone();
+        offload.spawn(Box::new(move || {
+            // Report panics, or the task would wait forever.
+            let result = panic::catch_unwind(AssertUnwindSafe(work)).ok();
+            let waker = match job_slot.lock() {
+                Ok(mut slot) => {
+                    slot.result = Some(result);
+                    slot.waker.take()
+                }
+                Err(_) => None
+            };
+            if let Some(waker) = waker { waker.wake(); }
+        }));
+        Job{slot: slot}
+    }
+
+    fn poll(&self, cx: &mut Context) -> Poll<io::Result<T>> {
+        let mut slot = match self.slot.lock() {
+            Ok(slot) => slot,
+            Err(_) => return Poll::Ready(Err(worker_failed()))
+        };
+        match slot.result.take() {
+            Some(Some(result)) => Poll::Ready(Ok(result)),
+            Some(None) => Poll::Ready(Err(worker_failed())),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A decoder coming back from an offloaded job, with what it decoded.
+type Decoded = (ChunkDecoder, Result<Option<DecoderEvent>, SnappyFramedError>);
+
+/// Decode a Snappy framed stream from an `AsyncRead` source.
+pub struct SnappyFramedDecoder<R: AsyncRead + Unpin> {
+    source: R,
+    /// This is `None` while `job` is using it, or if that job failed.
+    decoder: Option<ChunkDecoder>,
+    offload: Option<Box<dyn Offload + Send + Sync>>,
+    /// Decoding a chunk elsewhere.
+    job: Option<Job<Decoded>>,
+    /// Space for reading from `source`.
+    input: Vec<u8>,
+    /// Decompressed data.
+    output: Vec<u8>,
+    /// How much of `output` has been returned.
+    pos: usize
+}
+
+impl<R: AsyncRead + Unpin> SnappyFramedDecoder<R> {
+    /// Create a new decoder wrapping `source`, using the CRC verification
+    /// options indicated by `mode`.
+    pub fn new(source: R, mode: CrcMode) -> Self {
+        SnappyFramedDecoder{
+            source: source,
+            decoder: Some(ChunkDecoder::new(mode)),
+            offload: None,
+            job: None,
+            input: vec!(),
+            output: vec!(),
+            pos: 0
+        }
+    }
+
+    /// Decode each chunk using `offload`, instead of on the thread
+    /// polling us.  Reading from our source still happens here.
+    pub fn set_offload<O>(&mut self, offload: O)
+        where O: Offload + Send + Sync + 'static
+    {
+        self.offload = Some(Box::new(offload));
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R { &self.source }
+
+    /// Get a mutable reference to our source.
+    pub fn get_mut(&mut self) -> &mut R { &mut self.source }
+
+    /// Unwrap this decoder, returning our source.  Any data we've read
+    /// but not returned is discarded.
+    pub fn into_inner(self) -> R { self.source }
+
+    /// Decode the next chunk we've buffered, if any, using our `Offload`
+    /// if we have one and there's a whole chunk to decode.
+    fn poll_next_event(&mut self, cx: &mut Context) ->
+        Poll<io::Result<Option<DecoderEvent>>>
+    {
+        if let Some(job) = self.job.take() {
+            return match job.poll(cx) {
+                Poll::Pending => {
+                    self.job = Some(job);
+                    Poll::Pending
+                }
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Ready(Ok((decoder, event))) => {
+                    self.decoder = Some(decoder);
+                    Poll::Ready(event.map_err(|err| err.into()))
+                }
+            };
+        }
+        let mut decoder = match self.decoder.take() {
+            Some(decoder) => decoder,
+            None => return Poll::Ready(Err(worker_failed()))
+        };
+        match self.offload {
+            Some(ref offload)
+                if decoder.wanted() == 0 && decoder.buffered_len() > 0 =>
+            {
+                self.job = Some(Job::spawn(&**offload, move || {
+                    let event = decoder.next_event();
+                    (decoder, event)
+                }));
+                self.poll_next_event(cx)
+            }
+            _ => {
+                let event = decoder.next_event();
+                self.decoder = Some(decoder);
+                Poll::Ready(event.map_err(|err| err.into()))
+            }
+        }
+    }
+
+    /// Read and decode chunks until we have some output.  Returns `false`
+    /// at the end of the stream.
+    fn poll_fill_output(&mut self, cx: &mut Context) ->
+        Poll<io::Result<bool>>
+    {
+        loop {
+            match self.poll_next_event(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(Some(DecoderEvent::Data(data)))) => {
+                    self.output = data;
+                    self.pos = 0;
+                    return Poll::Ready(Ok(true));
+                }
+                Poll::Ready(Ok(Some(_))) => continue,
+                Poll::Ready(Ok(None)) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err))
+            }
+            // We only get here when we have our decoder back.
+            let decoder = self.decoder.as_mut().expect("decoder is home");
+            let len = max(decoder.wanted(), READ_SIZE);
+            self.input.resize(len, 0);
+            let source = Pin::new(&mut self.source);
+            match source.poll_read(cx, &mut self.input) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(ref err))
+                    if err.kind() == io::ErrorKind::Interrupted => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(0)) => {
+                    let finished = decoder.finish();
+                    return Poll::Ready(finished.map(|()| false)
+                                       .map_err(|err| err.into()));
+                }
+                Poll::Ready(Ok(n)) => decoder.push_input(&self.input[..n])
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SnappyFramedDecoder<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) ->
+        Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        while this.pos == this.output.len() {
+            match this.poll_fill_output(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(false)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(true)) => {}
+            }
+        }
+        Poll::Ready(Ok(copy_output(&this.output, &mut this.pos, buf)))
+    }
+}
+
+/// Encode data as a Snappy framed stream, writing it to an `AsyncWrite`
+/// destination.  Call `poll_close` (or `close` from `futures`) when
+/// you're done, to write the final chunk.
+pub struct SnappyFramedEncoder<W: AsyncWrite + Unpin> {
+    dest: W,
+    /// This is `None` while `job` is using it, or if that job failed.
+    encoder: Option<ChunkEncoder>,
+    offload: Option<Box<dyn Offload + Send + Sync>>,
+    /// Compressing a chunk elsewhere.
+    job: Option<Job<ChunkEncoder>>,
+    /// Compressed chunks waiting to be written.
+    output: Vec<u8>,
+    /// How much of `output` has been written.
+    written: usize,
+    linger: Option<Linger>
+}
+
+/// How we flush data which has been buffered for too long.
+struct Linger {
+    timer: Box<dyn Timer + Send + Sync>,
+    max: Duration,
+    /// Running while we have unflushed data and aren't being written to.
+    sleep: Option<Sleep>,
+    /// Has anything been written since we last flushed?
+    unflushed: bool,
+    /// Did `sleep` go off, so that we're flushing?
+    expired: bool
+}
+
+impl Linger {
+    /// Restart the clock, unless it's too late.
+    fn wrote(&mut self) {
+        self.unflushed = true;
+        if !self.expired { self.sleep = None; }
+    }
+
+    fn flushed(&mut self) {
+        self.unflushed = false;
+        self.expired = false;
+        self.sleep = None;
+    }
+}
+
+impl<W: AsyncWrite + Unpin> SnappyFramedEncoder<W> {
+    /// Create a new encoder wrapping `dest`.
+    pub fn new(dest: W) -> Self {
+        SnappyFramedEncoder::with_compression(dest, Compression::default())
+    }
+
+    /// Create a new encoder wrapping `dest`, and using the options in
+    /// `compression`.
+    pub fn with_compression(dest: W, compression: Compression) -> Self {
+        SnappyFramedEncoder{
+            dest: dest,
+            encoder: Some(ChunkEncoder::with_compression(compression)),
+            offload: None,
+            job: None,
+            output: vec!(),
+            written: 0,
+            linger: None
+        }
+    }
+
+    /// Compress each chunk using `offload`, instead of on the thread
+    /// polling us.  Writing to our destination still happens here.  The
+    /// output is the same either way.
+    pub fn set_offload<O>(&mut self, offload: O)
+        where O: Offload + Send + Sync + 'static
+    {
+        self.offload = Some(Box::new(offload));
+    }
+
+    /// Once nothing has been written to us for `max`, flush any data
+    /// written since we last flushed, using `timer` to measure the time.
+    /// This only happens while something is polling `poll_linger`.
+    pub fn set_max_linger<T>(&mut self, timer: T, max: Duration)
+        where T: Timer + Send + Sync + 'static
+    {
+        self.linger = Some(Linger{
+            timer: Box::new(timer),
+            max: max,
+            sleep: None,
+            unflushed: false,
+            expired: false
+        });
+    }
+
+    /// Flush our output if it's been lingering for longer than we were
+    /// told to allow by `set_max_linger`.  Returns `Ready` once we've
+    /// flushed.  This is `Pending` until then, and while we have nothing
+    /// to flush, in which case it isn't woken until after we're written
+    /// to and it's polled again.  Poll this alongside whatever provides
+    /// the data written to us, using `select!` or similar.
+    pub fn poll_linger(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.linger {
+            Some(ref mut linger) if linger.unflushed => {
+                if !linger.expired {
+                    let timer = &linger.timer;
+                    let max = linger.max;
+                    let sleep =
+                        linger.sleep.get_or_insert_with(|| timer.sleep(max));
+                    match sleep.as_mut().poll(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(()) => linger.expired = true
+                    }
+                }
+            }
+            _ => return Poll::Pending
+        }
+        AsyncWrite::poll_flush(Pin::new(self), cx)
+    }
+
+    /// Get a reference to our destination.
+    pub fn get_ref(&self) -> &W { &self.dest }
+
+    /// Get a mutable reference to our destination.
+    pub fn get_mut(&mut self) -> &mut W { &mut self.dest }
+
+    /// Unwrap this encoder, returning our destination.  Any data which
+    /// hasn't been flushed is discarded.
+    pub fn into_inner(self) -> W { self.dest }
+
+    fn encoder_mut(&mut self) -> io::Result<&mut ChunkEncoder> {
+        self.encoder.as_mut().ok_or_else(worker_failed)
+    }
+
+    /// Run `work` on our encoder, using our `Offload` if we have one.
+    /// Poll for the result with `poll_job`.
+    fn start_job<F>(&mut self, work: F)
+        where F: FnOnce(&mut ChunkEncoder) + Send + 'static
+    {
+        let mut encoder = self.encoder.take().expect("encoder is home");
+        match self.offload {
+            Some(ref offload) => {
+                self.job = Some(Job::spawn(&**offload, move || {
+                    work(&mut encoder);
+                    encoder
+                }));
+            }
+            None => {
+                work(&mut encoder);
+                self.encoder = Some(encoder);
+            }
+        }
+    }
+
+    /// Wait for any offloaded job to give us back our encoder.
+    fn poll_job(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if let Some(job) = self.job.take() {
+            match job.poll(cx) {
+                Poll::Pending => {
+                    self.job = Some(job);
+                    return Poll::Pending;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(encoder)) => self.encoder = Some(encoder)
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Compress any input we've buffered, and write all of our output to
+    /// `dest`, without flushing it.
+    fn poll_flush_buffer(&mut self, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        loop {
+            match self.poll_write_output(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other
+            }
+            match self.encoder_mut() {
+                Ok(encoder) =>
+                    if encoder.buffered_len() == 0 {
+                        return Poll::Ready(Ok(()));
+                    },
+                Err(err) => return Poll::Ready(Err(err))
+            }
+            self.start_job(|encoder| encoder.flush());
+        }
+    }
+
+    /// Write all of our compressed output to `dest`.
+    fn poll_write_output(&mut self, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        match self.poll_job(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other
+        }
+        loop {
+            while self.written < self.output.len() {
+                let dest = Pin::new(&mut self.dest);
+                match dest.poll_write(cx, &self.output[self.written..]) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(ref err))
+                        if err.kind() == io::ErrorKind::Interrupted => {}
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Ready(Ok(0)) =>
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "could not write Snappy chunk"))),
+                    Poll::Ready(Ok(n)) => self.written += n
+                }
+            }
+            let encoder = match self.encoder_mut() {
+                Ok(encoder) => encoder,
+                Err(err) => return Poll::Ready(Err(err))
+            };
+            if !encoder.has_output() { return Poll::Ready(Ok(())); }
+            self.output = encoder.take_output();
+            self.written = 0;
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SnappyFramedEncoder<W> {
+    /// Accept at most one chunk's worth of input at a time, so that we
+    /// never buffer more than one compressed chunk.
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) ->
+        Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        match this.poll_write_output(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other.map(|result| result.map(|()| 0))
+        }
+        let space = match this.encoder_mut() {
+            Ok(encoder) => encoder.chunk_size() - encoder.buffered_len(),
+            Err(err) => return Poll::Ready(Err(err))
+        };
+        let to_copy = min(space, buf.len());
+        if this.offload.is_some() && to_copy == space {
+            // This fills a chunk, so it's time to compress.
+            let data = buf[..to_copy].to_vec();
+            this.start_job(move |encoder| encoder.write_input(&data));
+        } else {
+            let encoder = this.encoder.as_mut().expect("encoder is home");
+            encoder.write_input(&buf[..to_copy]);
+        }
+        if let Some(ref mut linger) = this.linger {
+            if to_copy > 0 { linger.wrote(); }
+        }
+        Poll::Ready(Ok(to_copy))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        let this = self.get_mut();
+        match this.poll_flush_buffer(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other
+        }
+        let result = Pin::new(&mut this.dest).poll_flush(cx);
+        if let (Poll::Ready(Ok(())), Some(ref mut linger)) =
+            (&result, this.linger.as_mut())
+        {
+            linger.flushed();
+        }
+        result
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        match AsyncWrite::poll_flush(self.as_mut(), cx) {
+            Poll::Ready(Ok(())) =>
+                Pin::new(&mut self.get_mut().dest).poll_close(cx),
+            other => other
+        }
+    }
+}
+
+/// Each payload is compressed on its own, after any data written using
+/// `AsyncWrite`, and split into chunks only if it's longer than our
+/// chunk size.  Empty payloads don't produce any chunks.  We accept a
+/// payload once the previous one has been written to our destination.
+#[cfg(feature = "sink")]
+impl<W: AsyncWrite + Unpin> Sink<Bytes> for SnappyFramedEncoder<W> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        self.get_mut().poll_flush_buffer(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> io::Result<()> {
+        let this = self.get_mut();
+        try!(this.encoder_mut());
+        if let Some(ref mut linger) = this.linger {
+            if !item.is_empty() { linger.wrote(); }
+        }
+        this.start_job(move |encoder| {
+            encoder.write_input(&item);
+            encoder.flush();
+        });
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        AsyncWrite::poll_flush(self, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        AsyncWrite::poll_close(self, cx)
+    }
+}
+
+#[test]
+fn async_round_trip() {
+    use std::io::Write;
+    use test_helpers::*;
+    use write;
+
+    let input = mixed_data(100_000);
+
+    let mut expected = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    expected.write_all(&input).unwrap();
+    let expected = expected.finish().unwrap();
+
+    let dest = Stuttering::new(vec!(), 1000);
+    let mut encoder = SnappyFramedEncoder::new(dest);
+    for piece in input.chunks(7_000) {
+        let mut piece = piece;
+        while !piece.is_empty() {
+            let n = poll_until_ready(|cx| {
+                Pin::new(&mut encoder).poll_write(cx, piece)
+            }).unwrap();
+            piece = &piece[n..];
+        }
+    }
+    poll_until_ready(|cx| AsyncWrite::poll_close(Pin::new(&mut encoder), cx))
+        .unwrap();
+    let compressed = encoder.into_inner().inner;
+    assert_eq!(expected, compressed);
+
+    let source = Stuttering::new(&compressed as &[u8], 3);
+    let mut decoder = SnappyFramedDecoder::new(source, CrcMode::Verify);
+    let mut output = vec!();
+    let mut buf = [0; 5000];
+    loop {
+        let n = poll_until_ready(|cx| {
+            Pin::new(&mut decoder).poll_read(cx, &mut buf)
+        }).unwrap();
+        if n == 0 { break; }
+        output.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(input, output);
+}
+
+#[test]
+fn async_decoder_detects_truncation() {
+    use error::SnappyFramedError;
+    use std::io::Write;
+    use test_helpers::*;
+    use write;
+
+    let mut encoder = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(b"Hello, world!").unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let truncated = &compressed[..compressed.len() - 1];
+    let mut decoder = SnappyFramedDecoder::new(truncated, CrcMode::Verify);
+    let mut buf = [0; 100];
+    let err = poll_until_ready(|cx| {
+        Pin::new(&mut decoder).poll_read(cx, &mut buf)
+    }).unwrap_err();
+    assert_eq!(SnappyFramedError::Truncated.to_string(), err.to_string());
+}
+
+#[test]
+fn offloaded_round_trip() {
+    use std::io::Write;
+    use std::thread;
+    use test_helpers::*;
+    use write;
+
+    struct Threads;
+
+    impl Offload for Threads {
+        fn spawn(&self, job: Box<dyn FnOnce() + Send>) {
+            thread::spawn(job);
+        }
+    }
+
+    let input = mixed_data(300_000);
+
+    let mut expected = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    expected.write_all(&input[..1000]).unwrap();
+    expected.flush().unwrap();
+    expected.write_all(&input[1000..]).unwrap();
+    let expected = expected.finish().unwrap();
+
+    let dest = Stuttering::new(vec!(), 1000);
+    let mut encoder = SnappyFramedEncoder::new(dest);
+    encoder.set_offload(Threads);
+    for piece in &[&input[..1000], &input[1000..]] {
+        let mut piece = *piece;
+        while !piece.is_empty() {
+            let n = poll_until_ready(|cx| {
+                Pin::new(&mut encoder).poll_write(cx, piece)
+            }).unwrap();
+            piece = &piece[n..];
+        }
+        poll_until_ready(|cx| {
+            AsyncWrite::poll_flush(Pin::new(&mut encoder), cx)
+        }).unwrap();
+    }
+    poll_until_ready(|cx| AsyncWrite::poll_close(Pin::new(&mut encoder), cx))
+        .unwrap();
+    let compressed = encoder.into_inner().inner;
+    assert_eq!(expected, compressed);
+
+    let source = Stuttering::new(&compressed as &[u8], 5000);
+    let mut decoder = SnappyFramedDecoder::new(source, CrcMode::Verify);
+    decoder.set_offload(Threads);
+    let mut output = vec!();
+    let mut buf = [0; 5000];
+    loop {
+        let n = poll_until_ready(|cx| {
+            Pin::new(&mut decoder).poll_read(cx, &mut buf)
+        }).unwrap();
+        if n == 0 { break; }
+        output.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(input, output);
+}
+
+#[cfg(feature = "sink")]
+#[test]
+fn sink_sends_payloads_as_frames() {
+    use test_helpers::*;
+
+    let payloads = vec!(b"Hello!".to_vec(), vec!(), random_data(100_000),
+                        repeat_data(b"compressible ", 1000));
+    let dest = Stuttering::new(vec!(), 1000);
+    let mut encoder = SnappyFramedEncoder::new(dest);
+    poll_until_ready(|cx| {
+        Pin::new(&mut encoder).poll_write(cx, b"written")
+    }).unwrap();
+    for payload in &payloads {
+        poll_until_ready(|cx| Pin::new(&mut encoder).poll_ready(cx)).unwrap();
+        Pin::new(&mut encoder).start_send(Bytes::from(payload.clone()))
+            .unwrap();
+    }
+    poll_until_ready(|cx| Sink::poll_close(Pin::new(&mut encoder), cx))
+        .unwrap();
+    let compressed = encoder.into_inner().inner;
+
+    let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+    decoder.push_input(&compressed);
+    let mut frames = vec!();
+    while let Some(event) = decoder.next_event().unwrap() {
+        if let DecoderEvent::Data(data) = event { frames.push(data); }
+    }
+    decoder.finish().unwrap();
+    let big = &payloads[2];
+    assert_eq!(vec!(b"written".to_vec(), payloads[0].clone(),
+                    big[..65_536].to_vec(), big[65_536..].to_vec(),
+                    payloads[3].clone()),
+               frames);
+}
+
+#[test]
+fn flush_lingering_data() {
+    use chunk::STREAM_IDENTIFIER;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use test_helpers::*;
+
+    /// Sleeps which all go off when `expired` is set.
+    #[derive(Clone, Default)]
+    struct FakeTimer {
+        expired: Arc<AtomicBool>,
+        sleeps: Arc<AtomicUsize>
+    }
+
+    struct FakeSleep(Arc<AtomicBool>);
+
+    impl Future for FakeSleep {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+            if self.0.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    impl Timer for FakeTimer {
+        fn sleep(&self, duration: Duration) -> Sleep {
+            assert_eq!(Duration::from_millis(10), duration);
+            self.sleeps.fetch_add(1, Ordering::SeqCst);
+            Box::pin(FakeSleep(self.expired.clone()))
+        }
+    }
+
+    let timer = FakeTimer::default();
+    let dest = Stuttering::new(vec!(), 1000);
+    let mut encoder = SnappyFramedEncoder::new(dest);
+    encoder.set_max_linger(timer.clone(), Duration::from_millis(10));
+
+    // Nothing to flush, so there's no need for a timer.
+    assert!(poll_once(|cx| encoder.poll_linger(cx)).is_pending());
+    assert_eq!(0, timer.sleeps.load(Ordering::SeqCst));
+
+    // Each write restarts the timer.
+    for piece in &[b"Hello, " as &[u8], b"world!"] {
+        poll_until_ready(|cx| {
+            Pin::new(&mut encoder).poll_write(cx, piece)
+        }).unwrap();
+        assert!(poll_once(|cx| encoder.poll_linger(cx)).is_pending());
+    }
+    assert_eq!(2, timer.sleeps.load(Ordering::SeqCst));
+    // Our data is still buffered behind the stream identifier.
+    assert_eq!(&STREAM_IDENTIFIER as &[u8], &encoder.get_ref().inner as &[u8]);
+
+    timer.expired.store(true, Ordering::SeqCst);
+    poll_until_ready(|cx| encoder.poll_linger(cx)).unwrap();
+    let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+    decoder.push_input(&encoder.get_ref().inner);
+    let mut output = vec!();
+    while let Some(event) = decoder.next_event().unwrap() {
+        if let DecoderEvent::Data(data) = event { output.extend(data); }
+    }
+    assert_eq!(b"Hello, world!" as &[u8], &output as &[u8]);
+
+    // Now that we've flushed, there's nothing to wait for.
+    assert!(poll_once(|cx| encoder.poll_linger(cx)).is_pending());
+    assert_eq!(2, timer.sleeps.load(Ordering::SeqCst));
+}
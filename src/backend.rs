@@ -0,0 +1,129 @@
+//! Report which implementations this library is using at runtime, so that
+//! you can confirm that a deployment is using the fast paths you expect.
+//!
+//! ```
+//! use snappy_framed::backend::backend_report;
+//!
+//! println!("snappy_framed backends: {}", backend_report());
+//! ```
+
+use std::fmt;
+
+use masked_crc::crc_backend;
+
+/// The implementation used to compute CRC-32C checksums.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CrcBackend {
+    /// Portable slicing-by-8 software implementation.
+    Software,
+    /// The SSE 4.2 `crc32` instruction on x86-64 CPUs.
+    Sse42,
+    /// The ARMv8 CRC32 extension on AArch64 CPUs.
+    Armv8,
+    /// The `crc32c` crate, selected by the `crc32c` feature.
+    Crc32cCrate
+}
+
+/// The implementation used to compress and decompress Snappy data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CodecBackend {
+    /// Google's C++ `libsnappy`, via the `snappy` crate.
+    CSnappy,
+    /// The pure-Rust `snap` crate, selected by the `snap` feature.
+    Snap
+}
+
+/// The implementations in use by this process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct BackendReport {
+    /// How we compute checksums.
+    pub crc: CrcBackend,
+    /// How we compress and decompress data.
+    pub codec: CodecBackend,
+    /// Do any of our coders use background threads?  The coders in
+    /// `parallel` do, and they're always built, so this is `true`.
+    pub threads: bool
+}
+
+/// Report which implementations are active.
+pub fn backend_report() -> BackendReport {
+    BackendReport{
+        crc: crc_backend(),
+        codec: codec_backend(),
+        threads: true
+    }
+}
+
+#[cfg(not(feature = "snap"))]
+fn codec_backend() -> CodecBackend { CodecBackend::CSnappy }
+
+#[cfg(feature = "snap")]
+fn codec_backend() -> CodecBackend { CodecBackend::Snap }
+
+/// Identify the Snappy implementation we're linked against, for use in
+/// logs and bug reports.
+///
+/// The C `libsnappy` library has no API for querying its version, so for
+/// that backend we can only report `"libsnappy"`, and you'll need to log
+/// the version of your system's `libsnappy` package separately.  The
+/// `snap` backend is reported as `"snap"`, and its version is recorded
+/// in your `Cargo.lock`.
+pub fn snappy_version() -> &'static str {
+    match backend_report().codec {
+        CodecBackend::CSnappy => "libsnappy",
+        CodecBackend::Snap => "snap",
+    }
+}
+
+impl fmt::Display for CrcBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CrcBackend::Software => write!(f, "software"),
+            CrcBackend::Sse42 => write!(f, "sse4.2"),
+            CrcBackend::Armv8 => write!(f, "armv8-crc"),
+            CrcBackend::Crc32cCrate => write!(f, "crc32c crate"),
+        }
+    }
+}
+
+impl fmt::Display for CodecBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecBackend::CSnappy => write!(f, "libsnappy"),
+            CodecBackend::Snap => write!(f, "snap crate"),
+        }
+    }
+}
+
+impl fmt::Display for BackendReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "crc: {}, codec: {}, threads: {}",
+               self.crc, self.codec,
+               if self.threads { "enabled" } else { "disabled" })
+    }
+}
+
+#[test]
+#[cfg(not(feature = "snap"))]
+fn report_backends() {
+    let report = backend_report();
+    assert_eq!(CodecBackend::CSnappy, report.codec);
+    assert_eq!(format!("crc: {}, codec: libsnappy, threads: enabled",
+                       report.crc),
+               format!("{}", report));
+    assert_eq!("libsnappy", snappy_version());
+}
+
+#[test]
+#[cfg(feature = "snap")]
+fn report_snap_backend() {
+    let report = backend_report();
+    assert_eq!(CodecBackend::Snap, report.codec);
+    assert_eq!(format!("crc: {}, codec: snap crate, threads: enabled",
+                       report.crc),
+               format!("{}", report));
+    assert_eq!("snap", snappy_version());
+}
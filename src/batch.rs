@@ -0,0 +1,176 @@
+//! A writer for record batches, which keeps an index of where each batch
+//! starts in both the compressed and uncompressed streams.
+//!
+//! Each batch is stored in its own chunks, so a reader can use the index
+//! to seek to the start of any batch and decompress just that batch.  The
+//! index can be returned to the caller, or appended to the stream as a
+//! trailer of skippable chunks, which other decoders will ignore.
+//!
+//! ```
+//! use snappy_framed::batch::BatchWriter;
+//!
+//! let mut writer = BatchWriter::new(vec!()).unwrap();
+//! writer.write_batch(b"first batch").unwrap();
+//! writer.write_batch(b"second batch").unwrap();
+//! let (compressed, index) = writer.finish().unwrap();
+//! assert_eq!(2, index.len());
+//! assert_eq!(11, index[1].uncompressed_offset);
+//! # drop(compressed);
+//! ```
+
+use std::io::{self, Write};
+
+use chunk::{push_u64, read_u64};
+use consts::*;
+use error::SnappyFramedError;
+use write::SnappyFramedEncoder;
+
+/// The chunk type used for index trailers written by
+/// `BatchWriter::finish_with_trailer`.
+pub const BATCH_INDEX_CHUNK_TYPE: u8 = 0xB0;
+
+/// The size of each encoded index entry.
+const ENTRY_SIZE: usize = 24;
+
+/// Where a batch can be found in a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BatchIndexEntry {
+    /// The offset of the batch's first chunk in the compressed stream.
+    pub compressed_offset: u64,
+    /// The offset of the batch's first byte in the uncompressed data.
+    pub uncompressed_offset: u64,
+    /// The length of the batch's uncompressed data.
+    pub uncompressed_len: u64
+}
+
+/// Writes each record batch as a separate run of chunks, recording its
+/// position in an index.
+pub struct BatchWriter<W: Write> {
+    encoder: SnappyFramedEncoder<W>,
+    index: Vec<BatchIndexEntry>
+}
+
+impl<W: Write> BatchWriter<W> {
+    /// Create a new batch writer wrapping `dest`.
+    pub fn new(dest: W) -> io::Result<Self> {
+        Ok(BatchWriter{
+            encoder: try!(SnappyFramedEncoder::new(dest)),
+            index: vec!()
+        })
+    }
+
+    /// Write `batch` to the stream, starting a new chunk, and return its
+    /// position in the index.
+    pub fn write_batch(&mut self, batch: &[u8]) -> io::Result<usize> {
+        let status = self.encoder.status();
+        try!(self.encoder.write_all(batch));
+        try!(self.encoder.flush_buffer());
+        self.index.push(BatchIndexEntry{
+            compressed_offset: status.bytes_written,
+            uncompressed_offset: status.bytes_consumed,
+            uncompressed_len: batch.len() as u64
+        });
+        Ok(self.index.len() - 1)
+    }
+
+    /// The index of all batches written so far.
+    pub fn index(&self) -> &[BatchIndexEntry] {
+        &self.index
+    }
+
+    /// Flush our destination, and return it along with our index.
+    pub fn finish(self) -> io::Result<(W, Vec<BatchIndexEntry>)> {
+        let dest = try!(self.encoder.finish());
+        Ok((dest, self.index))
+    }
+
+    /// Append our index to the stream as one or more skippable chunks of
+    /// type `BATCH_INDEX_CHUNK_TYPE`, flush our destination, and return
+    /// it.  The contents of these chunks can be decoded using
+    /// `parse_index`.
+    pub fn finish_with_trailer(mut self) -> io::Result<W> {
+        let encoded = encode_index(&self.index);
+        let per_chunk = MAX_CHUNK_LEN / ENTRY_SIZE * ENTRY_SIZE;
+        for data in encoded.chunks(per_chunk) {
+            try!(self.encoder.write_skippable_chunk(BATCH_INDEX_CHUNK_TYPE,
+                                                    data));
+        }
+        let (dest, _) = try!(self.finish());
+        Ok(dest)
+    }
+}
+
+fn encode_index(index: &[BatchIndexEntry]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(index.len() * ENTRY_SIZE);
+    for entry in index {
+        push_u64(&mut encoded, entry.compressed_offset);
+        push_u64(&mut encoded, entry.uncompressed_offset);
+        push_u64(&mut encoded, entry.uncompressed_len);
+    }
+    encoded
+}
+
+/// Decode the contents of a `BATCH_INDEX_CHUNK_TYPE` chunk.
+pub fn parse_index(data: &[u8]) -> io::Result<Vec<BatchIndexEntry>> {
+    if data.len() % ENTRY_SIZE != 0 {
+        return Err(SnappyFramedError::Corrupt("Malformed Snappy batch index")
+                   .into());
+    }
+    Ok(data.chunks(ENTRY_SIZE).map(|entry| {
+        BatchIndexEntry{
+            compressed_offset: read_u64(&entry[0..8]),
+            uncompressed_offset: read_u64(&entry[8..16]),
+            uncompressed_len: read_u64(&entry[16..24])
+        }
+    }).collect())
+}
+
+#[test]
+fn batches_can_be_decoded_independently() {
+    use std::io::{Cursor, Read};
+
+    use chunk::STREAM_IDENTIFIER;
+    use read::{CrcMode, SnappyFramedDecoder};
+    use test_helpers::*;
+
+    let batches = vec![random_data(100), random_data(100_000), vec!(),
+                       random_data(10)];
+    let mut writer = BatchWriter::new(vec!()).unwrap();
+    for batch in &batches {
+        writer.write_batch(batch).unwrap();
+    }
+    let (compressed, index) = writer.finish().unwrap();
+
+    for (i, entry) in index.iter().enumerate() {
+        let end = index.get(i + 1)
+            .map(|next| next.compressed_offset as usize)
+            .unwrap_or(compressed.len());
+        let mut stream = STREAM_IDENTIFIER.to_vec();
+        stream.extend(compressed[entry.compressed_offset as usize..end]
+                      .iter().cloned());
+        let mut cursor = Cursor::new(&stream as &[u8]);
+        let mut decoder = SnappyFramedDecoder::new(&mut cursor,
+                                                   CrcMode::Verify);
+        let mut output = vec!();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(batches[i], output);
+        assert_eq!(batches[i].len() as u64, entry.uncompressed_len);
+    }
+}
+
+#[test]
+fn write_index_trailer() {
+    use chunk::parse_header;
+
+    let mut writer = BatchWriter::new(vec!()).unwrap();
+    writer.write_batch(b"first").unwrap();
+    writer.write_batch(b"second").unwrap();
+    let index = writer.index().to_vec();
+    let compressed = writer.finish_with_trailer().unwrap();
+
+    let trailer_len = HEADER_SIZE + 2 * ENTRY_SIZE;
+    let trailer = &compressed[compressed.len() - trailer_len..];
+    assert_eq!((BATCH_INDEX_CHUNK_TYPE, 2 * ENTRY_SIZE),
+               parse_header(&trailer[..HEADER_SIZE]));
+    assert_eq!(index, parse_index(&trailer[HEADER_SIZE..]).unwrap());
+}
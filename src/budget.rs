@@ -0,0 +1,122 @@
+//! Memory budgets shared between many coders.
+//!
+//! Services which decode many streams at once can give each decoder a
+//! clone of the same `MemoryBudget`.  If growing a decoder's buffers would
+//! exceed the budget, the read fails with a `MemoryBudgetExceeded` error
+//! instead of allocating more memory.
+//!
+//! ```
+//! use std::io::Read;
+//! use snappy_framed::budget::MemoryBudget;
+//! use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+//!
+//! let budget = MemoryBudget::new(64 * 1024 * 1024);
+//! let mut decoder = SnappyFramedDecoder::new(&[] as &[u8], CrcMode::Verify);
+//! decoder.set_memory_budget(budget.clone()).unwrap();
+//! assert!(budget.used() > 0);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A limit on the total buffer memory used by a group of coders.  Clones
+/// share the same limit.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    limit: usize,
+    used: AtomicUsize
+}
+
+impl MemoryBudget {
+    /// Create a new budget allowing up to `limit` bytes of buffers.
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget{
+            inner: Arc::new(BudgetInner{
+                limit: limit,
+                used: AtomicUsize::new(0)
+            })
+        }
+    }
+
+    /// The maximum number of bytes which may be allocated.
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// The number of bytes currently allocated against this budget, which
+    /// is the sum of `memory_usage()` for every coder sharing it.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::SeqCst)
+    }
+
+    /// Reserve `bytes` bytes, or fail without reserving anything.
+    pub fn try_reserve(&self, bytes: usize) -> io::Result<()> {
+        let mut used = self.used();
+        loop {
+            let available = self.inner.limit.saturating_sub(used);
+            if bytes > available {
+                let err = MemoryBudgetExceeded{
+                    requested: bytes,
+                    available: available
+                };
+                return Err(io::Error::new(io::ErrorKind::Other, err));
+            }
+            match self.inner.used.compare_exchange(used, used + bytes,
+                                                   Ordering::SeqCst,
+                                                   Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(actual) => used = actual
+            }
+        }
+    }
+
+    /// Return `bytes` bytes to the budget.
+    pub fn release(&self, bytes: usize) {
+        self.inner.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+/// The error returned when an allocation would exceed a `MemoryBudget`.
+/// This is wrapped in an `io::Error`, and can be recovered using
+/// `io::Error::get_ref` and `downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryBudgetExceeded {
+    /// The number of bytes we tried to allocate.
+    pub requested: usize,
+    /// The number of bytes left in the budget.
+    pub available: usize
+}
+
+impl fmt::Display for MemoryBudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Snappy memory budget exceeded (requested {} bytes, {} available)",
+               self.requested, self.available)
+    }
+}
+
+impl Error for MemoryBudgetExceeded {}
+
+#[test]
+fn reserve_and_release() {
+    let budget = MemoryBudget::new(100);
+    let shared = budget.clone();
+    budget.try_reserve(60).unwrap();
+    assert_eq!(60, shared.used());
+
+    let err = shared.try_reserve(50).unwrap_err();
+    let exceeded = err.get_ref().unwrap()
+        .downcast_ref::<MemoryBudgetExceeded>().unwrap();
+    assert_eq!(MemoryBudgetExceeded{requested: 50, available: 40}, *exceeded);
+    assert_eq!(60, budget.used());
+
+    budget.release(60);
+    shared.try_reserve(100).unwrap();
+}
@@ -1,6 +1,10 @@
+use std::cmp::{max, min};
+use std::io;
 use std::iter::repeat;
 use std::ptr::{copy, copy_nonoverlapping};
 
+use budget::MemoryBudget;
+
 /// An I/O buffer with various convenience functions.  This is an internal
 /// class, and the API is subject to change.
 pub struct Buffer {
@@ -9,13 +13,24 @@ pub struct Buffer {
     /// The start of the unread data in the buffer.
     begin: usize,
     /// The end of the unread data in the buffer.
-    end: usize
+    end: usize,
+    /// The budget our allocations are charged against, if any.
+    budget: Option<MemoryBudget>
 }
 
 /// Regular Buffer interface.
 impl Buffer {
     pub fn new(sz: usize) -> Buffer {
-        Buffer{buffer: vec![0; sz], begin: 0, end: 0}
+        Buffer{buffer: vec![0; sz], begin: 0, end: 0, budget: None}
+    }
+
+    /// Charge our future allocations against `budget`.  The caller must
+    /// already have reserved our current capacity.
+    pub fn set_budget(&mut self, budget: MemoryBudget) {
+        if let Some(old) = self.budget.take() {
+            old.release(self.capacity());
+        }
+        self.budget = Some(budget);
     }
     
     pub fn capacity(&self) -> usize { self.buffer.len() }
@@ -54,12 +69,22 @@ impl Buffer {
         self.end += bytes;
     }
 
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.begin..self.end]
+    }
+
     pub fn consume(&mut self, bytes: usize) -> &[u8] {
         let result = &self.buffer[self.begin..self.begin+bytes];
         self.begin += bytes;
         result
     }
 
+    /// Make the last `bytes` consumed bytes available for reading again.
+    pub fn unconsume(&mut self, bytes: usize) {
+        assert!(bytes <= self.begin);
+        self.begin -= bytes;
+    }
+
     pub fn copy_out_and_consume(&mut self, bytes: usize, dest: &mut [u8]) {
         assert!(bytes <= dest.len());
         unsafe {
@@ -71,7 +96,49 @@ impl Buffer {
         self.begin += bytes;
     }
 
-    pub fn add_capacity(&mut self, bytes: usize) {
+    pub fn add_capacity(&mut self, bytes: usize) -> io::Result<()> {
+        if let Some(ref budget) = self.budget {
+            try!(budget.try_reserve(bytes));
+        }
         self.buffer.extend(repeat(0).take(bytes));
+        Ok(())
     }
+
+    /// Grow or shrink the buffer to hold `capacity` bytes.  We never
+    /// discard buffered data, so the actual capacity may be larger than
+    /// requested.
+    pub fn set_capacity(&mut self, capacity: usize) -> io::Result<()> {
+        self.move_data_to_start();
+        let capacity = max(capacity, self.end);
+        let current = self.capacity();
+        if capacity > current {
+            try!(self.add_capacity(capacity - current));
+        } else if capacity < current {
+            self.buffer.truncate(capacity);
+            self.buffer.shrink_to_fit();
+            if let Some(ref budget) = self.budget {
+                budget.release(current - capacity);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.budget {
+            budget.release(self.capacity());
+        }
+    }
+}
+
+/// Copy as much of `output[*pos..]` as will fit into `buf`, advancing
+/// `pos` past it.  This is how our readers return decoded or encoded data
+/// which they hold in a plain `Vec`.
+pub fn copy_output(output: &[u8], pos: &mut usize, buf: &mut [u8]) -> usize {
+    let available = &output[*pos..];
+    let to_copy = min(available.len(), buf.len());
+    buf[..to_copy].copy_from_slice(&available[..to_copy]);
+    *pos += to_copy;
+    to_copy
 }
@@ -0,0 +1,280 @@
+//! Implementations of `Read` which take their input from a `BufRead`,
+//! in the style of `flate2::bufread`.
+//!
+//! These coders work directly from the caller's buffer whenever it holds
+//! a complete chunk, instead of copying input into a buffer of their own.
+//! This saves a copy per chunk when reading from a `BufReader` or an
+//! in-memory slice.
+//!
+//! ```
+//! use std::io::Read;
+//! use snappy_framed::bufread::{SnappyFramedDecoder, SnappyFramedEncoder};
+//! use snappy_framed::read::CrcMode;
+//!
+//! let mut compressed = vec!();
+//! SnappyFramedEncoder::new(b"Hello!" as &[u8])
+//!     .read_to_end(&mut compressed).unwrap();
+//!
+//! let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+//!                                            CrcMode::Verify);
+//! let mut output = vec!();
+//! decoder.read_to_end(&mut output).unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+//! ```
+
+use std::cmp::min;
+use std::io::{self, BufRead, Read, Write};
+
+use buffer::copy_output;
+use chunk::*;
+use compression::Compression;
+use consts::*;
+use read::CrcMode;
+use sans_io::{ChunkContents, ChunkDecoder, DecoderEvent};
+use write;
+
+/// Copy `bytes` from `available` into `out`, and return how many we
+/// copied.
+fn copy_into(out: &mut Vec<u8>, available: &[u8], bytes: usize) -> usize {
+    let to_copy = min(bytes, available.len());
+    out.extend_from_slice(&available[..to_copy]);
+    to_copy
+}
+
+/// Decode as much of the next chunk as we can from `available`, and
+/// return how many bytes we used.  If `available` holds a complete chunk,
+/// we decode it in place, and add any data it contains to `output`.
+/// Otherwise, we copy what we can into `state`.
+pub(crate) fn take_chunk(state: &mut ChunkDecoder, output: &mut Vec<u8>,
+              available: &[u8]) -> io::Result<usize> {
+    if state.buffered_len() == 0 && available.len() >= HEADER_SIZE {
+        let (chunk_type, chunk_len) = parse_header(&available[..HEADER_SIZE]);
+        try!(state.check_header(chunk_type, chunk_len));
+        let end = HEADER_SIZE + chunk_len;
+        if available.len() >= end {
+            let chunk = Chunk{
+                chunk_type: chunk_type,
+                data: &available[HEADER_SIZE..end]
+            };
+            match try!(state.decode_chunk(&chunk)) {
+                ChunkContents::Decompressed(data) => *output = data,
+                ChunkContents::Stored(data) => output.extend_from_slice(data),
+                _ => {}
+            }
+            return Ok(end);
+        }
+    }
+    let wanted = min(state.wanted(), available.len());
+    state.push_input(&available[..wanted]);
+    Ok(wanted)
+}
+
+/// Decode a Snappy framed stream read from a `BufRead`.
+pub struct SnappyFramedDecoder<R: BufRead> {
+    source: R,
+    /// Interprets each chunk, and holds any chunk which was split across
+    /// calls to `fill_buf`.
+    state: ChunkDecoder,
+    /// Decompressed data.
+    output: Vec<u8>,
+    /// How much of `output` has been returned.
+    pos: usize
+}
+
+impl<R: BufRead> SnappyFramedDecoder<R> {
+    /// Create a new decoder wrapping the specified `source`, and using the
+    /// CRC verification options indicated by `mode`.
+    pub fn new(source: R, mode: CrcMode) -> Self {
+        SnappyFramedDecoder{
+            source: source,
+            state: ChunkDecoder::new(mode),
+            output: vec!(),
+            pos: 0
+        }
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// Get a mutable reference to our source.  Reading from it directly
+    /// will corrupt our stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// The state machine which interprets our input.  Use this to set any
+    /// of the decoding options it supports.
+    pub fn chunk_decoder_mut(&mut self) -> &mut ChunkDecoder {
+        &mut self.state
+    }
+
+    /// Unwrap this decoder, returning our source.  Any data which we've
+    /// decompressed but not yet returned is lost.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Decode chunks until we find one containing data.  Returns `false`
+    /// at the end of the stream.
+    fn fill_output(&mut self) -> io::Result<bool> {
+        self.output.clear();
+        self.pos = 0;
+        while self.output.is_empty() {
+            // Decode any chunk we've finished copying.
+            if let Some(event) = try!(self.state.next_event()) {
+                if let DecoderEvent::Data(data) = event { self.output = data; }
+                continue;
+            }
+            let used = {
+                let available = try!(self.source.fill_buf());
+                if available.is_empty() {
+                    try!(self.state.finish());
+                    return Ok(false);
+                }
+                try!(take_chunk(&mut self.state, &mut self.output, available))
+            };
+            self.source.consume(used);
+        }
+        Ok(true)
+    }
+}
+
+impl<R: BufRead> Read for SnappyFramedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.output.len() && !try!(self.fill_output()) {
+            return Ok(0);
+        }
+        Ok(copy_output(&self.output, &mut self.pos, buf))
+    }
+}
+
+/// Compress data read from a `BufRead`, so that reading from the encoder
+/// returns a Snappy framed stream.
+pub struct SnappyFramedEncoder<R: BufRead> {
+    source: R,
+    /// Encodes each chunk of input into a buffer, from which we copy it
+    /// out.
+    encoder: write::SnappyFramedEncoder<Vec<u8>>,
+    chunk_size: usize,
+    /// A chunk of input which was split across calls to `fill_buf`.
+    input: Vec<u8>,
+    /// How much of the encoder's buffer has been returned.
+    pos: usize
+}
+
+impl<R: BufRead> SnappyFramedEncoder<R> {
+    /// Create a new encoder reading from `source`.
+    pub fn new(source: R) -> Self {
+        SnappyFramedEncoder::with_compression(source, Compression::default())
+    }
+
+    /// Create a new encoder reading from `source`, and using the options
+    /// in `compression`.
+    pub fn with_compression(source: R, compression: Compression) -> Self {
+        let encoder =
+            write::SnappyFramedEncoder::with_compression(vec!(), compression)
+            .expect("writing to a Vec should never fail");
+        SnappyFramedEncoder{
+            source: source,
+            encoder: encoder,
+            chunk_size: compression.chunk_size(),
+            input: vec!(),
+            pos: 0
+        }
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// Get a mutable reference to our source.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// Unwrap this encoder, returning our source.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Encode the next chunk of input.  Returns `false` at the end of our
+    /// input.
+    fn encode_chunk(&mut self) -> io::Result<bool> {
+        self.encoder.get_mut().clear();
+        self.pos = 0;
+        loop {
+            let used = {
+                let available = try!(self.source.fill_buf());
+                if available.is_empty() {
+                    if self.input.is_empty() { return Ok(false); }
+                    try!(self.encoder.write_all(&self.input));
+                    try!(self.encoder.flush_buffer());
+                    self.input.clear();
+                    return Ok(true);
+                }
+                if self.input.is_empty() && available.len() >= self.chunk_size {
+                    try!(self.encoder.write_all(&available[..self.chunk_size]));
+                    try!(self.encoder.flush_buffer());
+                    self.chunk_size
+                } else {
+                    let needed = self.chunk_size - self.input.len();
+                    copy_into(&mut self.input, available, needed)
+                }
+            };
+            self.source.consume(used);
+            if self.input.len() == self.chunk_size {
+                try!(self.encoder.write_all(&self.input));
+                try!(self.encoder.flush_buffer());
+                self.input.clear();
+            }
+            if !self.encoder.get_ref().is_empty() { return Ok(true); }
+        }
+    }
+}
+
+impl<R: BufRead> Read for SnappyFramedEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.encoder.get_ref().len() &&
+            !try!(self.encode_chunk())
+        {
+            return Ok(0);
+        }
+        Ok(copy_output(self.encoder.get_ref(), &mut self.pos, buf))
+    }
+}
+
+#[test]
+fn round_trip_through_small_buffers() {
+    use std::io::BufReader;
+    use test_helpers::*;
+
+    let input = mixed_data(100_000);
+
+    // Use buffers much smaller than a chunk, so every chunk is split.
+    let compression = Compression::default().with_chunk_size(10_000);
+    let mut encoder = SnappyFramedEncoder::with_compression(
+        BufReader::with_capacity(1000, &input as &[u8]), compression);
+    let mut compressed = vec!();
+    encoder.read_to_end(&mut compressed).unwrap();
+
+    let mut decoder = SnappyFramedDecoder::new(
+        BufReader::with_capacity(1000, &compressed as &[u8]),
+        CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+
+    // Slices hold everything, so chunks are always read in place.
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+
+    let mut decoder = SnappyFramedDecoder::new(
+        &compressed[..compressed.len() - 1], CrcMode::Verify);
+    assert!(decoder.read_to_end(&mut vec!()).is_err());
+}
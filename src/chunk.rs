@@ -0,0 +1,202 @@
+//! Helpers for parsing and building individual chunks.  These are shared
+//! by the various readers and writers.
+
+use std::io;
+use std::iter::repeat;
+
+use consts::*;
+use error::SnappyFramedError;
+use masked_crc::*;
+use raw;
+use read::CrcMode;
+
+/// Appears at the front of all Snappy framed streams.
+pub const STREAM_IDENTIFIER: [u8; 10] =
+    [0xFF, 0x06, 0x00, 0x00, 0x73, 0x4E, 0x61, 0x50, 0x70, 0x59];
+
+/// A framed chunk in a Snappy stream.
+#[derive(Debug)]
+pub struct Chunk<'a> {
+    pub chunk_type: u8,
+    pub data: &'a [u8]
+}
+
+impl<'a> Chunk<'a> {
+    /// Get the CRC stored at the front of a data chunk.
+    pub fn crc(&self) -> io::Result<u32> {
+        if self.data.len() < CRC_SIZE {
+            Err(SnappyFramedError::Corrupt("Snappy CRC truncated").into())
+        } else {
+            Ok((self.data[0] as u32) |
+               (self.data[1] as u32) << 8 |
+               (self.data[2] as u32) << 16 |
+               (self.data[3] as u32) << 24)
+        }
+    }
+}
+
+/// Parse a chunk header, returning the chunk type and length.
+pub fn parse_header(header: &[u8]) -> (u8, usize) {
+    (header[0],
+     ((header[3] as usize) << 16 |
+      (header[2] as usize) << 8 |
+      (header[1] as usize)))
+}
+
+/// Build a chunk header.
+pub fn header(chunk_type: u8, chunk_len: usize) -> [u8; HEADER_SIZE] {
+    assert!(chunk_len <= MAX_CHUNK_LEN);
+    [chunk_type,
+     ((chunk_len & 0x0000FF)      ) as u8,
+     ((chunk_len & 0x00FF00) >>  8) as u8,
+     ((chunk_len & 0xFF0000) >> 16) as u8]
+}
+
+/// Build the header and CRC for a data chunk with `payload_len` bytes of
+/// payload following the CRC.
+pub fn data_header(chunk_type: u8, payload_len: usize, crc: u32) ->
+    [u8; HEADER_SIZE+CRC_SIZE]
+{
+    let header = header(chunk_type, CRC_SIZE + payload_len);
+    [header[0], header[1], header[2], header[3],
+     ((crc & 0x000000FF)      ) as u8,
+     ((crc & 0x0000FF00) >>  8) as u8,
+     ((crc & 0x00FF0000) >> 16) as u8,
+     ((crc & 0xFF000000) >> 24) as u8]
+}
+
+/// The largest compressed size Snappy can produce for `len` bytes of
+/// input, as computed by `snappy::MaxCompressedLength`.
+pub fn max_compressed_len(len: usize) -> usize {
+    32 + len + len / 6
+}
+
+/// The largest data chunk (including its header) that we'll generate.
+pub fn max_data_chunk() -> usize {
+    max_frame_size_for_payload(MAX_UNCOMPRESSED_CHUNK)
+}
+
+/// The number of bytes of header and CRC in every data frame.
+pub fn frame_overhead() -> usize {
+    HEADER_SIZE + CRC_SIZE
+}
+
+/// The largest number of bytes our encoder may need to frame `len` bytes
+/// of data, not counting the stream identifier at the start of the
+/// stream.  If `len` is larger than `MAX_UNCOMPRESSED_CHUNK`, the data
+/// will be split across several frames, and we return their total size.
+///
+/// ```
+/// use snappy_framed::{frame_overhead, max_frame_size_for_payload};
+///
+/// assert!(max_frame_size_for_payload(1000) >= frame_overhead() + 1000);
+/// ```
+pub fn max_frame_size_for_payload(len: usize) -> usize {
+    let full_frames = len / MAX_UNCOMPRESSED_CHUNK;
+    let remainder = len % MAX_UNCOMPRESSED_CHUNK;
+    let mut total = full_frames *
+        (frame_overhead() + max_compressed_len(MAX_UNCOMPRESSED_CHUNK));
+    if remainder > 0 || full_frames == 0 {
+        total += frame_overhead() + max_compressed_len(remainder);
+    }
+    total
+}
+
+/// Read the uncompressed length from the start of Snappy-compressed
+/// data, without decompressing it.  Returns `None` if the length is
+/// malformed.
+pub fn uncompressed_len(compressed: &[u8]) -> Option<usize> {
+    let mut len: u64 = 0;
+    for (i, &byte) in compressed.iter().take(5).enumerate() {
+        len |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return if len <= 0xFFFF_FFFF { Some(len as usize) } else { None };
+        }
+    }
+    None
+}
+
+/// Compress `data` and append it to `out` as a data chunk.  If
+/// compression doesn't make it any smaller, we store it uncompressed.
+pub fn append_data_chunk(data: &[u8], out: &mut Vec<u8>) {
+    let compressed = raw::compress(data);
+    let crc = masked_crc(data);
+    if compressed.len() < data.len() {
+        out.extend(data_header(0x00, compressed.len(), crc).iter().cloned());
+        out.extend(compressed.into_iter());
+    } else {
+        out.extend(data_header(0x01, data.len(), crc).iter().cloned());
+        out.extend_from_slice(data);
+    }
+}
+
+/// Append a padding chunk of exactly `len` bytes, including the header.
+pub fn append_padding_chunk(len: usize, out: &mut Vec<u8>) {
+    assert!(len >= HEADER_SIZE);
+    out.extend(header(0xFE, len - HEADER_SIZE).iter().cloned());
+    out.extend(repeat(0).take(len - HEADER_SIZE));
+}
+
+/// Verify that `data` has the masked CRC `expected`.
+pub fn check_crc(expected: u32, data: &[u8]) -> io::Result<()> {
+    let actual = masked_crc(data);
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(SnappyFramedError::BadCrc{expected: expected, actual: actual}
+            .into())
+    }
+}
+
+/// Check `crc`, as stored in a chunk containing `data`, according to
+/// `mode`.  If `mode` is `Auto`, we replace it with the mode we detect.
+pub fn verify_crc(mode: &mut CrcMode, crc: u32, data: &[u8]) ->
+    io::Result<()>
+{
+    *mode = mode.detect(crc, data);
+    let crc = mode.stored_to_native(crc);
+    if mode.should_verify(crc) {
+        check_crc(crc, data)
+    } else {
+        Ok(())
+    }
+}
+
+/// Append `value` to `out` as a little-endian `u64`.
+pub fn push_u64(out: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        out.push((value >> (8 * i)) as u8);
+    }
+}
+
+/// Read a little-endian `u64` from the first 8 bytes of `bytes`.
+pub fn read_u64(bytes: &[u8]) -> u64 {
+    bytes[..8].iter().enumerate().fold(0, |acc, (i, &b)| {
+        acc | (b as u64) << (8 * i)
+    })
+}
+
+#[test]
+fn frame_sizes() {
+    assert_eq!(8, frame_overhead());
+    assert_eq!(8 + 32, max_frame_size_for_payload(0));
+    assert_eq!(max_data_chunk(),
+               max_frame_size_for_payload(MAX_UNCOMPRESSED_CHUNK));
+    assert_eq!(max_data_chunk() + 8 + 32 + 1,
+               max_frame_size_for_payload(MAX_UNCOMPRESSED_CHUNK + 1));
+}
+
+#[test]
+fn read_uncompressed_len() {
+    assert_eq!(Some(0), uncompressed_len(&raw::compress(b"")));
+    assert_eq!(Some(100_000),
+               uncompressed_len(&raw::compress(&[0; 100_000])));
+    assert_eq!(None, uncompressed_len(&[0x80, 0x80]));
+    assert_eq!(None, uncompressed_len(&[0xFF, 0xFF, 0xFF, 0xFF, 0x7F]));
+}
+
+#[test]
+fn header_round_trip() {
+    assert_eq!((0x01, 0x0A0B0C), parse_header(&header(0x01, 0x0A0B0C)));
+    assert_eq!(STREAM_IDENTIFIER[..HEADER_SIZE], header(0xFF, 6));
+}
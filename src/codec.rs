@@ -0,0 +1,92 @@
+//! A trait for choosing a compression format at runtime.
+//!
+//! Applications which support several formats can store a
+//! `Box<dyn FramedCodec>` in their configuration, and implement this trait
+//! for other formats' readers and writers.
+//!
+//! ```
+//! use std::io::{Cursor, Read, Write};
+//! use snappy_framed::codec::{FramedCodec, SnappyFramed};
+//!
+//! let codec: Box<dyn FramedCodec> = Box::new(SnappyFramed::default());
+//!
+//! let mut compressed = vec!();
+//! {
+//!     let mut encoder = codec.wrap_writer(Box::new(&mut compressed)).unwrap();
+//!     encoder.write_all(b"Hello!").unwrap();
+//!     encoder.flush().unwrap();
+//! }
+//!
+//! let mut decoder = codec.wrap_reader(Box::new(Cursor::new(compressed)));
+//! let mut output = vec!();
+//! decoder.read_to_end(&mut output).unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+//! ```
+
+use std::io::{self, Read, Write};
+
+use compression::Compression;
+use read::{CrcMode, SnappyFramedDecoder};
+use write::SnappyFramedEncoder;
+
+/// A streaming compression format.
+pub trait FramedCodec {
+    /// Wrap `source` in a reader which decompresses it.
+    fn wrap_reader<'a>(&self, source: Box<dyn Read + 'a>) ->
+        Box<dyn Read + 'a>;
+
+    /// Wrap `dest` in a writer which compresses data written to it.
+    fn wrap_writer<'a>(&self, dest: Box<dyn Write + 'a>) ->
+        io::Result<Box<dyn Write + 'a>>;
+}
+
+/// The Snappy framed format, with the specified options.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct SnappyFramed {
+    /// How to handle CRCs when reading.
+    pub crc_mode: CrcMode,
+    /// How to compress data when writing.
+    pub compression: Compression
+}
+
+impl FramedCodec for SnappyFramed {
+    fn wrap_reader<'a>(&self, source: Box<dyn Read + 'a>) ->
+        Box<dyn Read + 'a>
+    {
+        Box::new(SnappyFramedDecoder::new(source, self.crc_mode))
+    }
+
+    fn wrap_writer<'a>(&self, dest: Box<dyn Write + 'a>) ->
+        io::Result<Box<dyn Write + 'a>>
+    {
+        let encoder = try!(SnappyFramedEncoder::with_compression(
+            dest, self.compression));
+        Ok(Box::new(encoder))
+    }
+}
+
+#[test]
+fn round_trip_through_trait_object() {
+    use std::io::Cursor;
+
+    use test_helpers::*;
+
+    let input = read_file("data/arbres.txt").unwrap();
+    let codec: Box<dyn FramedCodec> = Box::new(SnappyFramed{
+        crc_mode: CrcMode::Ignore,
+        compression: Compression::default().with_chunk_size(100)
+    });
+
+    let mut compressed = vec!();
+    {
+        let mut encoder = codec.wrap_writer(Box::new(&mut compressed)).unwrap();
+        encoder.write_all(&input).unwrap();
+        encoder.flush().unwrap();
+    }
+
+    let mut decoder = codec.wrap_reader(Box::new(Cursor::new(compressed)));
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
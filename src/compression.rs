@@ -0,0 +1,236 @@
+//! Compression options, in the style of `flate2::Compression`.
+
+use std::cmp::{max, min};
+use std::time::{Duration, Instant};
+
+use chunk::frame_overhead;
+use consts::*;
+
+/// The smallest chunk size used in adaptive mode.
+const MIN_ADAPTIVE_CHUNK: usize = 4 * 1024;
+
+/// How often we re-estimate the write rate in adaptive mode, in
+/// milliseconds.
+const ADAPTIVE_WINDOW_MS: u64 = 100;
+
+/// In adaptive mode, we try to emit a chunk at least this often, in
+/// milliseconds, so that readers see data promptly.
+const ADAPTIVE_LATENCY_MS: u64 = 10;
+
+/// Options controlling how data is compressed.  Snappy has no compression
+/// levels, so unlike `flate2::Compression`, this describes how we split
+/// data into chunks.
+///
+/// ```
+/// use std::io::Write;
+/// use snappy_framed::Compression;
+/// use snappy_framed::write::SnappyFramedEncoder;
+///
+/// let compression = Compression::default().with_chunk_size(16 * 1024);
+/// let mut encoder =
+///     SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+/// encoder.write_all(b"Hello!").unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Compression {
+    chunk_size: usize,
+    adaptive: bool,
+    max_frame_size: Option<usize>,
+    min_savings_percent: Option<u8>
+}
+
+impl Compression {
+    /// Optimize for speed.  Provided for compatibility with `flate2`; this
+    /// is the same as `Compression::default()`.
+    pub fn fast() -> Compression {
+        Compression::default()
+    }
+
+    /// Optimize for size.  Provided for compatibility with `flate2`; this
+    /// is the same as `Compression::default()`, which already uses the
+    /// largest chunks allowed by the spec.
+    pub fn best() -> Compression {
+        Compression::default()
+    }
+
+    /// Split input into chunks of at most `chunk_size` bytes.  Smaller
+    /// chunks compress less well, but limit the damage done by
+    /// corruption.  Panics unless `0 < chunk_size <=
+    /// MAX_UNCOMPRESSED_CHUNK`.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Compression {
+        assert!(0 < chunk_size && chunk_size <= MAX_UNCOMPRESSED_CHUNK,
+                "invalid Snappy chunk size {}", chunk_size);
+        Compression{chunk_size: chunk_size, ..self}
+    }
+
+    /// Split input into chunks of up to `chunk_size` bytes, which may be
+    /// as large as `MAX_OVERSIZED_CHUNK`.  This can improve compression
+    /// for large, repetitive data sets, but **chunks larger than
+    /// `MAX_UNCOMPRESSED_CHUNK` are not allowed by the spec**, and the
+    /// output can only be read by this library, using a decoder with
+    /// `set_allow_oversized_chunks(true)`.  Panics unless `0 < chunk_size
+    /// <= MAX_OVERSIZED_CHUNK`.
+    pub fn with_oversized_chunk_size(self, chunk_size: usize) -> Compression {
+        assert!(0 < chunk_size && chunk_size <= MAX_OVERSIZED_CHUNK,
+                "invalid oversized Snappy chunk size {}", chunk_size);
+        Compression{chunk_size: chunk_size, ..self}
+    }
+
+    /// Adjust the chunk size based on how quickly data is being written:
+    /// use small chunks when data arrives slowly, so that readers see it
+    /// with low latency, and grow towards `chunk_size` under sustained
+    /// throughput, for better compression.
+    pub fn with_adaptive_chunk_size(self, adaptive: bool) -> Compression {
+        Compression{adaptive: adaptive, ..self}
+    }
+
+    /// Never emit a data chunk larger than `max_frame_size` bytes,
+    /// including its header and CRC, so that each chunk can be sent as a
+    /// single datagram or stored in a fixed-size record.  We split the
+    /// input into smaller chunks whenever the compressed data would be
+    /// too large, and store it uncompressed if it doesn't compress.
+    /// Panics unless `max_frame_size > frame_overhead()`.
+    pub fn with_max_frame_size(self, max_frame_size: usize) -> Compression {
+        assert!(max_frame_size > frame_overhead(),
+                "invalid Snappy frame size {}", max_frame_size);
+        Compression{max_frame_size: Some(max_frame_size), ..self}
+    }
+
+    /// Store a chunk uncompressed unless compressing it saves at least
+    /// `percent` percent of its size, so that barely-compressible data
+    /// costs less to decode.  By default, we store a chunk uncompressed
+    /// only if compressing it wouldn't make it any smaller.  Panics unless
+    /// `percent <= 100`.
+    pub fn with_min_savings_percent(self, percent: u8) -> Compression {
+        assert!(percent <= 100, "invalid Snappy savings percent {}", percent);
+        Compression{min_savings_percent: Some(percent), ..self}
+    }
+
+    /// The maximum number of uncompressed bytes in each chunk.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Do we adjust the chunk size based on the write rate?
+    pub fn adaptive_chunk_size(&self) -> bool {
+        self.adaptive
+    }
+
+    /// The largest data chunk we'll emit, if limited.
+    pub fn max_frame_size(&self) -> Option<usize> {
+        self.max_frame_size
+    }
+
+    /// The smallest saving, as a percentage, for which we'll store a
+    /// chunk compressed, if limited.
+    pub fn min_savings_percent(&self) -> Option<u8> {
+        self.min_savings_percent
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression{
+            chunk_size: MAX_UNCOMPRESSED_CHUNK,
+            adaptive: false,
+            max_frame_size: None,
+            min_savings_percent: None
+        }
+    }
+}
+
+/// Chooses an encoder's chunk size, adapting it to the write rate if
+/// requested.
+#[derive(Debug)]
+pub struct ChunkSizer {
+    max_chunk_size: usize,
+    current: usize,
+    adaptive: bool,
+    /// Tells us the time.  Tests replace this to control the write rate.
+    clock: fn() -> Instant,
+    window_start: Instant,
+    window_bytes: u64
+}
+
+impl ChunkSizer {
+    pub fn new(compression: &Compression) -> ChunkSizer {
+        let max_chunk_size = compression.chunk_size();
+        ChunkSizer{
+            max_chunk_size: max_chunk_size,
+            current: if compression.adaptive_chunk_size() {
+                min(MIN_ADAPTIVE_CHUNK, max_chunk_size)
+            } else {
+                max_chunk_size
+            },
+            adaptive: compression.adaptive_chunk_size(),
+            clock: Instant::now,
+            window_start: Instant::now(),
+            window_bytes: 0
+        }
+    }
+
+    /// Use `clock` to measure the write rate, starting a new window now.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: fn() -> Instant) {
+        self.clock = clock;
+        self.window_start = clock();
+        self.window_bytes = 0;
+    }
+
+    /// The chunk size to use for the next write.
+    pub fn chunk_size(&self) -> usize {
+        self.current
+    }
+
+    /// Record that `bytes` bytes were written, and adjust our chunk size
+    /// if we've seen enough data to estimate the write rate.
+    pub fn observe(&mut self, bytes: usize) {
+        if !self.adaptive { return; }
+        self.window_bytes += bytes as u64;
+        let now = (self.clock)();
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= Duration::from_millis(ADAPTIVE_WINDOW_MS) {
+            self.current = adaptive_chunk_size(self.window_bytes, elapsed,
+                                               self.max_chunk_size);
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+}
+
+/// Pick a chunk size which holds about `ADAPTIVE_LATENCY_MS` worth of
+/// data, given that `bytes` were written in `elapsed`.
+fn adaptive_chunk_size(bytes: u64, elapsed: Duration, max_chunk_size: usize)
+                       -> usize
+{
+    let elapsed_ms = max(1, elapsed.as_secs() * 1000 +
+                         (elapsed.subsec_nanos() / 1_000_000) as u64);
+    let wanted = (bytes * ADAPTIVE_LATENCY_MS / elapsed_ms) as usize;
+    let lower = min(MIN_ADAPTIVE_CHUNK, max_chunk_size);
+    min(max(wanted.next_power_of_two(), lower), max_chunk_size)
+}
+
+#[test]
+fn adapt_chunk_size_to_write_rate() {
+    let second = Duration::from_secs(1);
+    // A trickle of data gets the smallest chunks.
+    assert_eq!(MIN_ADAPTIVE_CHUNK, adaptive_chunk_size(100, second, 65_536));
+    // 1 MB/s is about 10 KB per 10 ms.
+    assert_eq!(16_384, adaptive_chunk_size(1_000_000, second, 65_536));
+    // Bulk transfers get the largest chunks we allow.
+    assert_eq!(65_536, adaptive_chunk_size(1 << 30, second, 65_536));
+    assert_eq!(1_000, adaptive_chunk_size(100, second, 1_000));
+}
+
+#[test]
+fn chunk_size_defaults_to_maximum() {
+    assert_eq!(MAX_UNCOMPRESSED_CHUNK, Compression::default().chunk_size());
+    assert_eq!(1024, Compression::fast().with_chunk_size(1024).chunk_size());
+}
+
+#[test]
+#[should_panic]
+fn reject_oversized_chunks() {
+    Compression::default().with_chunk_size(MAX_UNCOMPRESSED_CHUNK + 1);
+}
@@ -0,0 +1,90 @@
+//! Presets for common workloads.
+
+use compression::Compression;
+
+/// Buffer and chunk settings for both ends of a stream, with presets for
+/// common workloads.
+///
+/// ```
+/// use std::io::{Cursor, Read, Write};
+/// use snappy_framed::Config;
+/// use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+/// use snappy_framed::write::SnappyFramedEncoder;
+///
+/// let config = Config::for_low_memory();
+/// let mut encoder = SnappyFramedEncoder::with_config(vec!(), &config).unwrap();
+/// encoder.write_all(b"Hello!").unwrap();
+/// encoder.flush().unwrap();
+/// let (compressed, _) = encoder.into_inner();
+///
+/// let mut decoder = SnappyFramedDecoder::with_config(
+///     Cursor::new(compressed), CrcMode::Verify, &config);
+/// let mut output = vec!();
+/// decoder.read_to_end(&mut output).unwrap();
+/// assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Config {
+    /// How the encoder splits data into chunks.
+    pub compression: Compression,
+    /// Should the decoder use a single buffer and avoid reading ahead?
+    pub low_memory: bool
+}
+
+impl Config {
+    /// For interactive network protocols: adapt chunk sizes to the write
+    /// rate, so that small messages reach the reader quickly, while bulk
+    /// transfers still use full-size chunks.
+    pub fn for_network_streaming() -> Config {
+        Config{
+            compression: Compression::default()
+                .with_adaptive_chunk_size(true),
+            low_memory: false
+        }
+    }
+
+    /// For large files: use the largest chunks the spec allows, and read
+    /// ahead aggressively.
+    pub fn for_bulk_files() -> Config {
+        Config{
+            compression: Compression::default(),
+            low_memory: false
+        }
+    }
+
+    /// For embedded systems and servers handling many streams at once:
+    /// use small chunks, and decode using a single small buffer.
+    pub fn for_low_memory() -> Config {
+        Config{
+            compression: Compression::default().with_chunk_size(16 * 1024),
+            low_memory: true
+        }
+    }
+}
+
+#[test]
+fn presets_round_trip() {
+    use std::io::{Cursor, Read, Write};
+
+    use read::{CrcMode, SnappyFramedDecoder};
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    let input = random_data(100_000);
+    for config in [Config::default(), Config::for_network_streaming(),
+                   Config::for_bulk_files(), Config::for_low_memory()].iter()
+    {
+        let mut encoder =
+            SnappyFramedEncoder::with_config(vec!(), config).unwrap();
+        encoder.write_all(&input).unwrap();
+        encoder.flush().unwrap();
+        let (compressed, _) = encoder.into_inner();
+
+        let mut decoder = SnappyFramedDecoder::with_config(
+            Cursor::new(compressed), CrcMode::Verify, config);
+        let mut output = vec!();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(input, output);
+    }
+}
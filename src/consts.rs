@@ -4,5 +4,24 @@ pub const HEADER_SIZE: usize = 4;
 /// The size of a chunk CRC.
 pub const CRC_SIZE: usize = 4;
 
+/// The maximum length of a chunk's contents, which is stored in 24 bits.
+pub const MAX_CHUNK_LEN: usize = 0xFF_FFFF;
+
 /// The maximum size of the uncompressed data stored in a chunk.
 pub const MAX_UNCOMPRESSED_CHUNK: usize = 65_536;
+
+/// The maximum size of the uncompressed data stored in a chunk when
+/// oversized chunks are enabled.  This is not allowed by the spec.
+pub const MAX_OVERSIZED_CHUNK: usize = 4 * 1024 * 1024;
+
+/// The skippable chunk type used to carry frame sequence numbers.  See
+/// `SnappyFramedEncoder::set_sequence_numbers`.
+pub const SEQUENCE_CHUNK_TYPE: u8 = 0xB1;
+
+/// The skippable chunk type used to declare a stream's uncompressed
+/// length.  See `SnappyFramedEncoder::write_content_size_hint`.
+pub const CONTENT_SIZE_CHUNK_TYPE: u8 = 0xB3;
+
+/// The skippable chunk type used to carry a checksum of the next data
+/// chunk's contents.  See `SnappyFramedEncoder::set_payload_checksums`.
+pub const PAYLOAD_CRC_CHUNK_TYPE: u8 = 0xB4;
@@ -0,0 +1,139 @@
+//! Self-contained datagrams for unreliable transports like UDP or QUIC.
+//!
+//! Each datagram holds exactly one data chunk, optionally preceded by a
+//! stream identifier.  Since no state is carried from one datagram to the
+//! next, datagrams may be lost, duplicated or reordered in transit, and
+//! each one can still be decoded on its own.
+//!
+//! ```
+//! use snappy_framed::datagram::{decode_datagram, encode_datagram};
+//!
+//! let datagram = encode_datagram(b"Hello!", true).unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &decode_datagram(&datagram).unwrap() as &[u8]);
+//! ```
+
+use std::io;
+
+use chunk::*;
+use consts::*;
+use error::SnappyFramedError;
+use raw;
+
+fn malformed(msg: &'static str) -> io::Error {
+    SnappyFramedError::Corrupt(msg).into()
+}
+
+/// Encode `data` as a single datagram, including a stream identifier if
+/// `with_identifier` is true.  `data` may not be longer than
+/// `MAX_UNCOMPRESSED_CHUNK` bytes, because it must fit in one chunk.
+pub fn encode_datagram(data: &[u8], with_identifier: bool) ->
+    io::Result<Vec<u8>>
+{
+    if data.len() > MAX_UNCOMPRESSED_CHUNK {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "Snappy datagram payload too large"));
+    }
+    let mut datagram = vec!();
+    if with_identifier {
+        datagram.extend(STREAM_IDENTIFIER.iter().cloned());
+    }
+    append_data_chunk(data, &mut datagram);
+    Ok(datagram)
+}
+
+/// Decode a datagram created by `encode_datagram`, verifying its CRC.
+/// Padding and skippable chunks are ignored, but the datagram must
+/// contain exactly one data chunk.
+pub fn decode_datagram(datagram: &[u8]) -> io::Result<Vec<u8>> {
+    let mut rest = datagram;
+    let mut result = None;
+    while rest.len() > 0 {
+        if rest.len() < HEADER_SIZE {
+            return Err(SnappyFramedError::Truncated.into());
+        }
+        let (chunk_type, chunk_len) = parse_header(&rest[..HEADER_SIZE]);
+        if rest.len() - HEADER_SIZE < chunk_len {
+            return Err(SnappyFramedError::Truncated.into());
+        }
+        let chunk = Chunk{
+            chunk_type: chunk_type,
+            data: &rest[HEADER_SIZE..HEADER_SIZE+chunk_len]
+        };
+        rest = &rest[HEADER_SIZE+chunk_len..];
+
+        match chunk.chunk_type {
+            // Compressed or uncompressed data.
+            0x00 | 0x01 => {
+                if result.is_some() {
+                    return Err(malformed("Snappy datagram contains more than one data chunk"));
+                }
+                let crc = try!(chunk.crc());
+                let payload = &chunk.data[CRC_SIZE..];
+                let data = if chunk.chunk_type == 0x00 {
+                    try!(raw::uncompress(payload).ok_or_else(|| {
+                        malformed("Corrupt Snappy datagram")
+                    }))
+                } else {
+                    payload.to_vec()
+                };
+                try!(check_crc(crc, &data));
+                result = Some(data);
+            }
+
+            // Reserved unskippable chunks.
+            0x02...0x7F => {
+                return Err(malformed("Reserved unskippable chunk in Snappy datagram"));
+            }
+
+            // Stream identifier.
+            0xFF => {
+                if chunk.data != &STREAM_IDENTIFIER[HEADER_SIZE..] {
+                    return Err(SnappyFramedError::BadIdentifier.into());
+                }
+            }
+
+            // Reserved skippable chunks and padding.
+            _ => {}
+        }
+    }
+    result.ok_or_else(|| malformed("Snappy datagram contains no data"))
+}
+
+#[test]
+fn datagram_round_trip() {
+    for &with_identifier in [true, false].iter() {
+        let datagram = encode_datagram(b"Hello!", with_identifier).unwrap();
+        assert_eq!(b"Hello!" as &[u8],
+                   &decode_datagram(&datagram).unwrap() as &[u8]);
+    }
+    let datagram = encode_datagram(b"", false).unwrap();
+    assert_eq!(0, decode_datagram(&datagram).unwrap().len());
+}
+
+#[test]
+fn datagrams_are_independent() {
+    let first = encode_datagram(b"first", true).unwrap();
+    let second = encode_datagram(b"second", false).unwrap();
+    assert_eq!(b"second" as &[u8], &decode_datagram(&second).unwrap() as &[u8]);
+    assert_eq!(b"first" as &[u8], &decode_datagram(&first).unwrap() as &[u8]);
+}
+
+#[test]
+fn reject_malformed_datagrams() {
+    let datagram = encode_datagram(b"Hello!", true).unwrap();
+    for len in 0..datagram.len() {
+        assert!(decode_datagram(&datagram[..len]).is_err());
+    }
+
+    let mut corrupt = datagram.clone();
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0x01;
+    assert!(decode_datagram(&corrupt).is_err());
+
+    let mut doubled = datagram.clone();
+    doubled.extend(encode_datagram(b"again", false).unwrap().into_iter());
+    assert!(decode_datagram(&doubled).is_err());
+
+    let too_big = vec![0; MAX_UNCOMPRESSED_CHUNK + 1];
+    assert!(encode_datagram(&too_big, true).is_err());
+}
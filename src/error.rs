@@ -0,0 +1,168 @@
+//! The errors reported when a stream can't be decoded.
+//!
+//! Our coders implement `Read` and `Write`, so they return `io::Error`.
+//! When a stream is invalid, the `io::Error` wraps a `SnappyFramedError`,
+//! which can be recovered to find out what went wrong:
+//!
+//! ```
+//! use std::io::Read;
+//! use snappy_framed::SnappyFramedError;
+//! use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+//!
+//! let truncated = b"\xff\x06\x00\x00sNaPpY\x00\x10\x00";
+//! let mut decoder = SnappyFramedDecoder::new(&truncated[..],
+//!                                            CrcMode::Verify);
+//! let err = decoder.read_to_end(&mut vec!()).unwrap_err();
+//! match SnappyFramedError::from(err) {
+//!     SnappyFramedError::Truncated => {}
+//!     other => panic!("unexpected error: {}", other)
+//! }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Why a stream couldn't be decoded.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SnappyFramedError {
+    /// The stream identifier was invalid.
+    BadIdentifier,
+    /// A chunk's CRC didn't match its data.
+    BadCrc {
+        /// The CRC stored in the stream.
+        expected: u32,
+        /// The CRC of the data we decoded.
+        actual: u32
+    },
+    /// The stream contained invalid data.  The message describes the
+    /// problem.
+    Corrupt(&'static str),
+    /// The stream ended in the middle of a chunk.  This is reported as
+    /// `io::ErrorKind::UnexpectedEof`, so that short files can be told
+    /// apart from corrupt ones.
+    Truncated,
+    /// A chunk was larger than we're willing to decode.
+    ChunkTooLarge,
+    /// One of the decoder's configured limits was exceeded.  The message
+    /// describes the limit.
+    LimitExceeded(&'static str),
+    /// Sequence numbers showed that frames were missing from the stream.
+    MissingFrames {
+        /// The sequence number we expected.
+        expected: u64,
+        /// The sequence number we found.
+        found: u64
+    },
+    /// The stream ended with bytes which didn't form a complete chunk, or
+    /// with chunks we didn't recognize after the last data chunk.  See
+    /// `SnappyFramedDecoder::set_detect_trailing_junk`.
+    TrailingJunk {
+        /// Where the junk starts in the compressed stream.
+        offset: u64,
+        /// The number of bytes of junk.
+        len: u64
+    },
+    /// An encoder was asked to do something invalid.  The message
+    /// describes the problem.
+    InvalidInput(&'static str),
+    /// An I/O error occurred.
+    Io(io::Error)
+}
+
+impl SnappyFramedError {
+    /// The `io::ErrorKind` used when this error is converted to an
+    /// `io::Error`.  Invalid streams are reported as `InvalidData`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            SnappyFramedError::Io(ref err) => err.kind(),
+            SnappyFramedError::Truncated => io::ErrorKind::UnexpectedEof,
+            SnappyFramedError::InvalidInput(_) =>
+                io::ErrorKind::InvalidInput,
+            SnappyFramedError::BadIdentifier |
+            SnappyFramedError::BadCrc{..} |
+            SnappyFramedError::Corrupt(_) |
+            SnappyFramedError::ChunkTooLarge |
+            SnappyFramedError::TrailingJunk{..} => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other
+        }
+    }
+}
+
+impl fmt::Display for SnappyFramedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnappyFramedError::BadIdentifier =>
+                write!(f, "Invalid Snappy stream identifier"),
+            SnappyFramedError::BadCrc{expected, actual} =>
+                write!(f, "Invalid Snappy CRC (expected {:x}, got {:x})",
+                       expected, actual),
+            SnappyFramedError::Corrupt(msg) => write!(f, "{}", msg),
+            SnappyFramedError::Truncated =>
+                write!(f, "Incomplete Snappy chunk"),
+            SnappyFramedError::ChunkTooLarge =>
+                write!(f, "Snappy chunk too large"),
+            SnappyFramedError::LimitExceeded(msg) => write!(f, "{}", msg),
+            SnappyFramedError::InvalidInput(msg) => write!(f, "{}", msg),
+            SnappyFramedError::MissingFrames{expected, found} =>
+                write!(f, "Snappy stream skipped from frame {} to {}",
+                       expected, found),
+            SnappyFramedError::TrailingJunk{offset, len} =>
+                write!(f, "{} bytes of junk after Snappy stream at offset {}",
+                       len, offset),
+            SnappyFramedError::Io(ref err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl Error for SnappyFramedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SnappyFramedError::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<SnappyFramedError> for io::Error {
+    fn from(err: SnappyFramedError) -> io::Error {
+        match err {
+            SnappyFramedError::Io(err) => err,
+            err => io::Error::new(err.kind(), err)
+        }
+    }
+}
+
+impl From<io::Error> for SnappyFramedError {
+    /// Recover the `SnappyFramedError` wrapped by `err`, or wrap `err` if
+    /// it's an ordinary I/O error.
+    fn from(err: io::Error) -> SnappyFramedError {
+        let wraps_ours = err.get_ref()
+            .map(|inner| inner.is::<SnappyFramedError>())
+            .unwrap_or(false);
+        if wraps_ours {
+            let inner = err.into_inner().unwrap();
+            *inner.downcast::<SnappyFramedError>().unwrap()
+        } else {
+            SnappyFramedError::Io(err)
+        }
+    }
+}
+
+#[test]
+fn convert_to_and_from_io_errors() {
+    let err: io::Error = SnappyFramedError::BadCrc{expected: 1, actual: 2}
+        .into();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    assert_eq!("Invalid Snappy CRC (expected 1, got 2)", err.to_string());
+    match SnappyFramedError::from(err) {
+        SnappyFramedError::BadCrc{expected: 1, actual: 2} => {}
+        other => panic!("unexpected error: {:?}", other)
+    }
+
+    let io_err = io::Error::new(io::ErrorKind::TimedOut, "slow");
+    let err = SnappyFramedError::from(io_err);
+    assert!(err.source().is_some());
+    assert_eq!(io::ErrorKind::TimedOut, io::Error::from(err).kind());
+}
@@ -4,8 +4,7 @@
 //! implementations for framed snappy data.
 //!
 //! The API to this library is designed to be similar to that of
-//! [`flate2`][flate2], though we have not yet implemented
-//! `read::SnappyFramedEncoder` or `write::SnappyFramedDecoder`.
+//! [`flate2`][flate2].
 //!
 //! ### A note about checksums
 //!
@@ -16,40 +15,202 @@
 //! SmallTalk implementation use one byte order, and the Node.js and Python
 //! implementations use another.
 //!
-//! For now, we have chosen to generate checksums in the format used by the
-//! Java and `snzip` libraries. When reading, we can either verify
-//! Java-format checksums, or we can ignore the checksums entirely.
+//! By default, we generate checksums in the format used by the Java and
+//! `snzip` libraries, but encoders can use the other format instead (see
+//! `SnappyFramedEncoder::set_swapped_crcs`).  When reading, we can verify
+//! checksums in either format (see `CrcMode::VerifySwapped`), or we can
+//! ignore the checksums entirely.
+//!
+//! If you build with the `crc32c` feature, checksums are computed by the
+//! [`crc32c`][crc32c] crate instead of our own implementation.  The
+//! checksums themselves are identical either way.
+//!
+//! ### Snappy implementation
+//!
+//! By default, we compress and decompress data using Google's C++
+//! `libsnappy`, via the [`snappy`][snappy-crate] crate.  If you build
+//! with the `snap` feature, we use the pure-Rust [`snap`][snap] crate
+//! instead, which makes it possible to build without a C toolchain or
+//! `libsnappy`.  To drop the C dependency entirely, also disable our
+//! default features.  You can also supply your own implementation using
+//! `raw::RawCodec`.
+//!
+//! ### Threads
+//!
+//! All of our encoders and decoders are `Send` and `Sync` whenever the
+//! readers and writers they wrap are, so they can be moved between
+//! threads freely.  This is checked at compile time.
+//!
+//! The `parallel` module has coders which compress or decompress chunks
+//! on several threads at once.  If you build with the `rayon` feature, it
+//! also has helpers for compressing and decompressing whole buffers using
+//! rayon's thread pool.
+//!
+//! ### Async I/O
+//!
+//! If you build with the `futures-io` feature, the `async_io` module has
+//! an encoder and decoder implementing the `AsyncWrite` and `AsyncRead`
+//! traits from `futures-io`.  These work with any async runtime.  With
+//! the `sink` feature, the encoder is also a `Sink<Bytes>`.  The
+//! `async_bufread` module has coders which read from an `AsyncBufRead`,
+//! like those in `bufread`.
+//!
+//! If you build with the `tokio-codec` feature, the `tokio_codec` module
+//! implements `tokio_util::codec::{Encoder, Decoder}`, so that a `Framed`
+//! connection sends and receives individually compressed payloads.
+//!
+//! If you build with the `stream` feature, the `stream` module turns an
+//! `AsyncRead` or a `Stream` of compressed `Bytes` into a `Stream` of
+//! decompressed frames.
+//!
+//! ### Timing
+//!
+//! If you build with the `timing` feature, encoders and decoders have a
+//! `timings()` method reporting how long they've spent compressing,
+//! checksumming and doing I/O.
 //!
 //! ### Limitations
 //!
 //! This library is still a work in progress:
 //!
-//! - Invalid streams will probably result in a panic.
-//! - Decompression performance has been tuned a fair bit, except for CRCs,
-//!   but there's probably an extra 25% or so to be gained by further
-//!   tweaking.
-//! - We currently assume that you will `write` data in large blocks when
-//!   compressing, and we will generate poorly-compressed data if you make
-//!   lots of small writes.  This could be fixed by using an internal write
-//!   buffer.
+//! - Decompression performance has been tuned a fair bit, but there's
+//!   probably an extra 25% or so to be gained by further tweaking.  CRCs
+//!   use the SSE 4.2 or ARMv8 CRC32 instructions when the CPU supports
+//!   them.
 //!
 //! [snappy]: http://code.google.com/p/snappy/
 //! [framed]: http://code.google.com/p/snappy/source/browse/trunk/framing_format.txt
 //! [snzip]: https://github.com/kubo/snzip
+//! [crc32c]: https://crates.io/crates/crc32c
+//! [snappy-crate]: https://crates.io/crates/snappy
+//! [snap]: https://crates.io/crates/snap
 //! [flate2]: http://alexcrichton.com/flate2-rs/flate2/index.html
 
 #![cfg_attr(feature = "unstable", feature(test))]
 #![warn(missing_docs)]
 
-extern crate crc;
+#[cfg(not(any(feature = "snap", feature = "snappy")))]
+compile_error!("snappy_framed needs either the `snappy` or `snap` feature");
+
+#[cfg(feature = "bytes")] extern crate bytes;
+#[cfg(feature = "crc32c")] extern crate crc32c;
 #[cfg(test)] extern crate dribble;
+#[cfg(feature = "futures-core")] extern crate futures_core;
+#[cfg(feature = "futures-io")] extern crate futures_io;
+#[cfg(feature = "sink")] extern crate futures_sink;
 #[macro_use] extern crate log;
-extern crate snappy;
+#[cfg(feature = "rayon")] extern crate rayon;
+#[cfg(feature = "snap")] extern crate snap;
+#[cfg(not(feature = "snap"))] extern crate snappy;
 #[cfg(all(test, feature = "unstable"))] extern crate test;
+#[cfg(feature = "tokio-codec")] extern crate tokio_util;
 
+#[macro_use] mod timing;
 mod consts;
 #[cfg(test)] mod test_helpers;
 mod buffer;
+mod chunk;
+mod compression;
+mod config;
 mod masked_crc;
+#[cfg(feature = "futures-io")] pub mod async_bufread;
+#[cfg(feature = "futures-io")] pub mod async_io;
+pub mod backend;
+pub mod batch;
+pub mod budget;
+pub mod bufread;
+pub mod codec;
+pub mod datagram;
+pub mod error;
+pub mod manifest;
+pub mod multipart;
+pub mod parallel;
+pub mod prelude;
+pub mod raw;
 pub mod read;
+pub mod sans_io;
+pub mod seek;
+pub mod snap_compat;
+pub mod spill;
+#[cfg(feature = "stream")] pub mod stream;
+#[cfg(feature = "tokio-codec")] pub mod tokio_codec;
 pub mod write;
+
+pub use chunk::{frame_overhead, max_frame_size_for_payload};
+pub use compression::Compression;
+pub use config::Config;
+pub use consts::{CONTENT_SIZE_CHUNK_TYPE, MAX_OVERSIZED_CHUNK,
+                 MAX_UNCOMPRESSED_CHUNK, PAYLOAD_CRC_CHUNK_TYPE,
+                 SEQUENCE_CHUNK_TYPE};
+pub use error::SnappyFramedError;
+pub use masked_crc::{MaskedCrcHasher, masked_crc};
+#[cfg(feature = "timing")] pub use timing::Timings;
+
+/// Fail to compile if any of our coders stop being `Send` or `Sync` when
+/// the types they wrap are.  The coders in `parallel` receive from their
+/// worker threads over channels, so they're only `Send`.
+#[allow(dead_code)]
+fn assert_coders_are_send_and_sync<R, B, S, P, W, F>()
+    where R: std::io::Read + Send + Sync,
+          B: std::io::BufRead + Send + Sync,
+          S: std::io::Read + std::io::Seek + Send + Sync,
+          P: seek::ReadAt + Send + Sync,
+          W: std::io::Write + Send + Sync + 'static,
+          F: FnMut(Vec<u8>) -> std::io::Result<()> + Send + Sync
+{
+    fn is_send<T: Send>() {}
+    fn is_send_and_sync<T: Send + Sync>() {}
+    is_send_and_sync::<read::SnappyFramedDecoder<R>>();
+    is_send_and_sync::<read::SnappyFramedEncoder<R>>();
+    is_send_and_sync::<write::SnappyFramedEncoder<W>>();
+    is_send_and_sync::<write::SnappyFramedDecoder<W>>();
+    is_send_and_sync::<bufread::SnappyFramedDecoder<B>>();
+    is_send_and_sync::<bufread::SnappyFramedEncoder<B>>();
+    is_send::<parallel::SnappyFramedEncoder<W>>();
+    is_send::<parallel::SnappyFramedDecoder<R>>();
+    is_send::<parallel::PipelinedEncoder<W>>();
+    is_send::<parallel::PipelinedDecoder>();
+    is_send_and_sync::<seek::SeekableDecoder<S>>();
+    is_send_and_sync::<seek::PositionalDecoder<P>>();
+    is_send_and_sync::<sans_io::ChunkDecoder>();
+    is_send_and_sync::<sans_io::ChunkEncoder>();
+    is_send_and_sync::<batch::BatchWriter<W>>();
+    is_send_and_sync::<multipart::MultipartEncoder<F>>();
+    is_send_and_sync::<codec::SnappyFramed>();
+    is_send_and_sync::<Compression>();
+}
+
+/// The same check for our asynchronous coders.
+#[cfg(feature = "futures-io")]
+#[allow(dead_code)]
+fn assert_async_coders_are_send_and_sync<R, W, B>()
+    where R: futures_io::AsyncRead + Unpin + Send + Sync,
+          W: futures_io::AsyncWrite + Unpin + Send + Sync,
+          B: futures_io::AsyncBufRead + Unpin + Send + Sync
+{
+    fn is_send_and_sync<T: Send + Sync>() {}
+    is_send_and_sync::<async_io::SnappyFramedDecoder<R>>();
+    is_send_and_sync::<async_io::SnappyFramedEncoder<W>>();
+    is_send_and_sync::<async_bufread::SnappyFramedDecoder<B>>();
+    is_send_and_sync::<async_bufread::SnappyFramedEncoder<B>>();
+}
+
+/// The same check for our streams of frames.
+#[cfg(feature = "stream")]
+#[allow(dead_code)]
+fn assert_streams_are_send_and_sync<R, S>()
+    where R: futures_io::AsyncRead + Unpin + Send + Sync,
+          S: futures_core::Stream<Item = bytes::Bytes> + Unpin + Send + Sync
+{
+    fn is_send_and_sync<T: Send + Sync>() {}
+    is_send_and_sync::<stream::ReadFrames<R>>();
+    is_send_and_sync::<stream::StreamFrames<S>>();
+}
+
+/// The same check for our Tokio codec.
+#[cfg(feature = "tokio-codec")]
+#[allow(dead_code)]
+fn assert_tokio_codec_is_send_and_sync() {
+    fn is_send_and_sync<T: Send + Sync>() {}
+    is_send_and_sync::<tokio_codec::SnappyFramedCodec>();
+}
@@ -0,0 +1,141 @@
+//! Per-frame digests, which allow individual regions of a stream to be
+//! verified without decompressing the whole thing.
+//!
+//! This library doesn't depend on any cryptographic hash functions, so you
+//! need to supply one.  For example, using the `sha2` crate:
+//!
+//! ```ignore
+//! use sha2::{Digest, Sha256};
+//!
+//! encoder.set_frame_hasher(|data: &[u8]| Sha256::digest(data).to_vec());
+//! encoder.write_all(&input)?;
+//! encoder.write_manifest()?;
+//! ```
+
+use std::io;
+
+use chunk::{push_u64, read_u64};
+use consts::*;
+use error::SnappyFramedError;
+
+/// The chunk type used for manifests written by
+/// `SnappyFramedEncoder::write_manifest`.
+pub const MANIFEST_CHUNK_TYPE: u8 = 0xB2;
+
+/// Computes a digest of each frame's uncompressed data.
+///
+/// This is implemented for closures of type `FnMut(&[u8]) -> Vec<u8>`.
+pub trait FrameHasher {
+    /// Return the digest of `data`.
+    fn digest(&mut self, data: &[u8]) -> Vec<u8>;
+}
+
+impl<F> FrameHasher for F where F: FnMut(&[u8]) -> Vec<u8> {
+    fn digest(&mut self, data: &[u8]) -> Vec<u8> {
+        self(data)
+    }
+}
+
+/// The digest of a single frame.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ManifestEntry {
+    /// The offset of the frame's data in the uncompressed stream.
+    pub uncompressed_offset: u64,
+    /// The length of the frame's uncompressed data.
+    pub uncompressed_len: u64,
+    /// The digest of the frame's uncompressed data.
+    pub digest: Vec<u8>
+}
+
+/// A list of digests for each frame in a stream.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DigestManifest {
+    /// One entry for each data frame, in order.
+    pub entries: Vec<ManifestEntry>
+}
+
+impl DigestManifest {
+    /// Create an empty manifest.
+    pub fn new() -> DigestManifest {
+        DigestManifest::default()
+    }
+
+    /// Encode this manifest as a sequence of chunk payloads, each of which
+    /// fits in a single `MANIFEST_CHUNK_TYPE` chunk.  Entries are never
+    /// split across payloads.
+    pub fn to_chunk_payloads(&self) -> Vec<Vec<u8>> {
+        let mut payloads = vec!();
+        let mut current = vec!();
+        for entry in &self.entries {
+            let mut encoded = Vec::with_capacity(20 + entry.digest.len());
+            push_u64(&mut encoded, entry.uncompressed_offset);
+            push_u64(&mut encoded, entry.uncompressed_len);
+            push_u64(&mut encoded, entry.digest.len() as u64);
+            encoded.extend(entry.digest.iter().cloned());
+            if current.len() + encoded.len() > MAX_CHUNK_LEN {
+                payloads.push(current);
+                current = vec!();
+            }
+            current.extend(encoded);
+        }
+        if !current.is_empty() || payloads.is_empty() {
+            payloads.push(current);
+        }
+        payloads
+    }
+
+    /// Decode the contents of a `MANIFEST_CHUNK_TYPE` chunk, appending its
+    /// entries to this manifest.
+    pub fn extend_from_chunk_payload(&mut self, mut data: &[u8]) ->
+        io::Result<()>
+    {
+        while !data.is_empty() {
+            if data.len() < 24 { return Err(malformed_manifest()); }
+            let offset = read_u64(&data[0..8]);
+            let len = read_u64(&data[8..16]);
+            let digest_len = read_u64(&data[16..24]);
+            data = &data[24..];
+            if digest_len > data.len() as u64 {
+                return Err(malformed_manifest());
+            }
+            let (digest, rest) = data.split_at(digest_len as usize);
+            self.entries.push(ManifestEntry{
+                uncompressed_offset: offset,
+                uncompressed_len: len,
+                digest: digest.to_vec()
+            });
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Check `data`, which should be the uncompressed contents of the
+    /// frame described by `entry`, using `hasher`.
+    pub fn verify<H: FrameHasher>(entry: &ManifestEntry, data: &[u8],
+                                  hasher: &mut H) -> bool {
+        data.len() as u64 == entry.uncompressed_len &&
+            hasher.digest(data) == entry.digest
+    }
+}
+
+fn malformed_manifest() -> io::Error {
+    SnappyFramedError::Corrupt("Malformed Snappy digest manifest").into()
+}
+
+#[test]
+fn manifest_round_trip() {
+    let mut manifest = DigestManifest::new();
+    manifest.entries.push(ManifestEntry{
+        uncompressed_offset: 0, uncompressed_len: 10, digest: vec![1, 2, 3]
+    });
+    manifest.entries.push(ManifestEntry{
+        uncompressed_offset: 10, uncompressed_len: 5, digest: vec!()
+    });
+    let payloads = manifest.to_chunk_payloads();
+    assert_eq!(1, payloads.len());
+
+    let mut decoded = DigestManifest::new();
+    decoded.extend_from_chunk_payload(&payloads[0]).unwrap();
+    assert_eq!(manifest, decoded);
+    assert!(decoded.extend_from_chunk_payload(&payloads[0][..30]).is_err());
+}
@@ -1,7 +1,165 @@
-use crc::crc32::checksum_castagnoli;
+use backend::CrcBackend;
+
+/// Portable CRC-32C using the "slicing-by-8" algorithm, which processes 8
+/// bytes per step using 8 lookup tables.
+#[cfg(any(test, not(feature = "crc32c")))]
+mod software {
+    /// The Castagnoli polynomial, in reversed bit order.
+    const CASTAGNOLI: u32 = 0x82F63B78;
+
+    /// `TABLES[0]` is the usual byte-at-a-time table, and `TABLES[n]`
+    /// advances a byte's CRC past `n` more zero bytes.
+    static TABLES: [[u32; 256]; 8] = make_tables();
+
+    const fn make_tables() -> [[u32; 256]; 8] {
+        let mut tables = [[0; 256]; 8];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ CASTAGNOLI
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            tables[0][i] = crc;
+            i += 1;
+        }
+        let mut i = 0;
+        while i < 256 {
+            let mut t = 1;
+            while t < 8 {
+                let prev = tables[t - 1][i];
+                tables[t][i] = (prev >> 8) ^ tables[0][(prev & 0xFF) as usize];
+                t += 1;
+            }
+            i += 1;
+        }
+        tables
+    }
+
+    /// Update an unmasked CRC with `bytes`.
+    pub fn update(crc: u32, bytes: &[u8]) -> u32 {
+        let t = &TABLES;
+        let mut crc = !crc;
+        let mut words = bytes.chunks_exact(8);
+        for w in &mut words {
+            let lo = crc ^ u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+            crc = t[7][(lo & 0xFF) as usize] ^
+                t[6][((lo >> 8) & 0xFF) as usize] ^
+                t[5][((lo >> 16) & 0xFF) as usize] ^
+                t[4][(lo >> 24) as usize] ^
+                t[3][w[4] as usize] ^
+                t[2][w[5] as usize] ^
+                t[1][w[6] as usize] ^
+                t[0][w[7] as usize];
+        }
+        for &byte in words.remainder() {
+            crc = t[0][((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        !crc
+    }
+}
+
+/// Hardware CRC-32C using the SSE 4.2 `crc32` instruction.
+#[cfg(all(target_arch = "x86_64", not(feature = "crc32c")))]
+mod sse42 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    /// Update an unmasked CRC with `bytes`.  Callers must check that the
+    /// CPU supports SSE 4.2.
+    #[target_feature(enable = "sse4.2")]
+    pub unsafe fn update(crc: u32, bytes: &[u8]) -> u32 {
+        let mut crc = !crc as u64;
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(word);
+            crc = _mm_crc32_u64(crc, u64::from_le_bytes(buf));
+        }
+        let mut crc = crc as u32;
+        for &byte in words.remainder() {
+            crc = _mm_crc32_u8(crc, byte);
+        }
+        !crc
+    }
+}
+
+/// Hardware CRC-32C using the ARMv8 CRC32 extension.
+#[cfg(all(target_arch = "aarch64", not(feature = "crc32c")))]
+mod armv8 {
+    use std::arch::aarch64::{__crc32cb, __crc32cd};
+
+    /// Update an unmasked CRC with `bytes`.  Callers must check that the
+    /// CPU supports the CRC32 extension.
+    #[target_feature(enable = "crc")]
+    pub unsafe fn update(crc: u32, bytes: &[u8]) -> u32 {
+        let mut crc = !crc;
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(word);
+            crc = __crc32cd(crc, u64::from_le_bytes(buf));
+        }
+        for &byte in words.remainder() {
+            crc = __crc32cb(crc, byte);
+        }
+        !crc
+    }
+}
+
+/// The CRC implementation we'll use on this CPU.
+#[cfg(feature = "crc32c")]
+pub fn crc_backend() -> CrcBackend {
+    CrcBackend::Crc32cCrate
+}
+
+/// The CRC implementation we'll use on this CPU.
+#[cfg(not(feature = "crc32c"))]
+pub fn crc_backend() -> CrcBackend {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") { return CrcBackend::Sse42; }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if is_aarch64_feature_detected!("crc") { return CrcBackend::Armv8; }
+    }
+    CrcBackend::Software
+}
+
+/// Update an unmasked CRC-32C with `bytes`, using the `crc32c` crate.
+#[cfg(feature = "crc32c")]
+fn update_crc(crc: u32, bytes: &[u8]) -> u32 {
+    crc32c::crc32c_append(crc, bytes)
+}
+
+/// Update an unmasked CRC-32C with `bytes`, using the fastest
+/// implementation this CPU supports.
+#[cfg(not(feature = "crc32c"))]
+fn update_crc(crc: u32, bytes: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.2") {
+            return unsafe { sse42::update(crc, bytes) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if is_aarch64_feature_detected!("crc") {
+            return unsafe { armv8::update(crc, bytes) };
+        }
+    }
+    software::update(crc, bytes)
+}
 
 #[test]
 fn unmasked_checksum() {
+    let checksum_castagnoli = |bytes: &[u8]| software::update(0, bytes);
+
     // Test values from: https://www.ietf.org/rfc/rfc3720.txt , CRC
     // examples interpreted as little endian bytes.
     assert_eq!(checksum_castagnoli(b"123456789"), 0xe3069283);
@@ -23,9 +181,40 @@ fn mask(crc: u32) -> u32 {
 /// "Checksums are not stored directly, but masked, as checksumming data
 /// and then its own checksum can be problematic."
 pub fn masked_crc(bytes: &[u8]) -> u32 {
-    // Also consider porting:
-    // https://github.com/Voxer/sse4_crc32/blob/master/src/sse4_crc32.cpp
-    mask(checksum_castagnoli(bytes))
+    mask(update_crc(0, bytes))
+}
+
+/// Computes a Snappy-format masked CRC incrementally, for data which
+/// arrives in several pieces.
+///
+/// ```
+/// use snappy_framed::{MaskedCrcHasher, masked_crc};
+///
+/// let mut hasher = MaskedCrcHasher::new();
+/// hasher.update(b"Hello, ");
+/// hasher.update(b"world!");
+/// assert_eq!(masked_crc(b"Hello, world!"), hasher.finalize());
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MaskedCrcHasher {
+    crc: u32
+}
+
+impl MaskedCrcHasher {
+    /// Create a new hasher.
+    pub fn new() -> MaskedCrcHasher {
+        MaskedCrcHasher{crc: 0}
+    }
+
+    /// Add `bytes` to the data being checksummed.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.crc = update_crc(self.crc, bytes);
+    }
+
+    /// Return the masked CRC of all the data passed to `update`.
+    pub fn finalize(&self) -> u32 {
+        mask(self.crc)
+    }
 }
 
 #[test]
@@ -45,6 +234,49 @@ fn masked_checksum() {
     //assert_eq!(0xB214298A, masked_crc(&[1; 50]));
 }
 
+#[test]
+fn incremental_masked_checksum() {
+    let data = b"aaaaaaaaaaaabbbbbbbaaaaaa";
+    for split in 0..data.len() + 1 {
+        let mut hasher = MaskedCrcHasher::new();
+        hasher.update(&data[..split]);
+        hasher.update(&data[split..]);
+        assert_eq!(0x9274CDA8, hasher.finalize());
+    }
+    assert_eq!(masked_crc(b""), MaskedCrcHasher::new().finalize());
+}
+
+/// A bit-at-a-time CRC-32C, which is slow but easy to check against the
+/// spec.
+#[cfg(test)]
+fn reference_crc(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82F63B78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn hardware_crc_matches_software() {
+    use test_helpers::random_data;
+
+    let data = random_data(100);
+    for start in 0..8 {
+        for end in start..data.len() {
+            let bytes = &data[start..end];
+            assert_eq!(reference_crc(0, bytes), update_crc(0, bytes));
+            assert_eq!(reference_crc(12345, bytes),
+                       update_crc(12345, bytes));
+            assert_eq!(reference_crc(12345, bytes),
+                       software::update(12345, bytes));
+        }
+    }
+}
+
 #[cfg(all(test, feature = "unstable"))]
 mod benches {
     use test::Bencher;
@@ -0,0 +1,256 @@
+//! Split compressed output into independently-decodable parts, for use
+//! with APIs like S3 multipart uploads.
+//!
+//! Every part is a complete Snappy framed stream with its own stream
+//! identifier, so parts can later be fetched and decoded in parallel.
+//! Every part except the last is padded to exactly `part_size` bytes.
+//!
+//! ```
+//! use std::io::{Cursor, Read, Write};
+//! use snappy_framed::multipart::MultipartEncoder;
+//! use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+//!
+//! let mut parts = vec!();
+//! {
+//!     let mut encoder = MultipartEncoder::new(128 * 1024, |part: Vec<u8>| {
+//!         parts.push(part);
+//!         Ok(())
+//!     }).unwrap();
+//!     encoder.write_all(b"Hello!").unwrap();
+//!     let _on_part = encoder.finish().unwrap();
+//! }
+//!
+//! let mut cursor = Cursor::new(&parts[0] as &[u8]);
+//! let mut decoder = SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify);
+//! let mut output = vec!();
+//! decoder.read_to_end(&mut output).unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+//! ```
+
+use std::io::{self, Write};
+use std::mem;
+
+use chunk::*;
+use compression::Compression;
+use consts::*;
+use sans_io::ChunkEncoder;
+use write::Checkpoint;
+
+/// The smallest part size we support: a stream identifier, one maximal
+/// data chunk, and room for a padding chunk.
+pub fn min_part_size() -> usize {
+    STREAM_IDENTIFIER.len() + max_data_chunk() + HEADER_SIZE
+}
+
+/// Compresses data into a series of independent streams of exactly
+/// `part_size` bytes each (except the last), passing each completed part
+/// to `on_part`.  Input is buffered until it fills a chunk, so call
+/// `finish` to compress the rest.
+pub struct MultipartEncoder<F>
+    where F: FnMut(Vec<u8>) -> io::Result<()>
+{
+    part_size: usize,
+    part: Vec<u8>,
+    /// Builds the data chunks, which we then place in parts.
+    encoder: ChunkEncoder,
+    on_part: F
+}
+
+impl<F> MultipartEncoder<F>
+    where F: FnMut(Vec<u8>) -> io::Result<()>
+{
+    /// Create a new encoder which passes parts of `part_size` bytes to
+    /// `on_part`.  `part_size` must be at least `min_part_size()`.
+    pub fn new(part_size: usize, on_part: F) -> io::Result<Self> {
+        MultipartEncoder::with_compression(part_size, Compression::default(),
+                                           on_part)
+    }
+
+    /// Create a new encoder which passes parts of `part_size` bytes to
+    /// `on_part`, using the options in `compression`.  Oversized chunks
+    /// can't be used, because they might not fit in a part.
+    pub fn with_compression(part_size: usize, compression: Compression,
+                            on_part: F) -> io::Result<Self> {
+        if part_size < min_part_size() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Snappy part size is too small"));
+        }
+        if compression.chunk_size() > MAX_UNCOMPRESSED_CHUNK {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Snappy part chunk size is too large"));
+        }
+        let mut encoder = MultipartEncoder{
+            part_size: part_size,
+            part: Vec::with_capacity(part_size),
+            encoder: ChunkEncoder::resume(Checkpoint::default(), compression),
+            on_part: on_part
+        };
+        encoder.start_part();
+        Ok(encoder)
+    }
+
+    fn start_part(&mut self) {
+        self.part.clear();
+        self.part.extend(STREAM_IDENTIFIER.iter().cloned());
+    }
+
+    /// Does the current part contain any data chunks?
+    fn part_has_data(&self) -> bool {
+        self.part.len() > STREAM_IDENTIFIER.len()
+    }
+
+    /// Pad out the current part, hand it to `on_part`, and start a new one.
+    fn emit_part(&mut self) -> io::Result<()> {
+        let gap = self.part_size - self.part.len();
+        if gap > 0 {
+            append_padding_chunk(gap, &mut self.part);
+        }
+        let part =
+            mem::replace(&mut self.part, Vec::with_capacity(self.part_size));
+        try!((self.on_part)(part));
+        self.start_part();
+        Ok(())
+    }
+
+    /// Place each chunk in the encoder's output in the current part,
+    /// starting a new part whenever the next chunk won't fit.
+    fn place_chunks(&mut self) -> io::Result<()> {
+        let output = self.encoder.take_output();
+        let mut rest = &output as &[u8];
+        while !rest.is_empty() {
+            let (_, chunk_len) = parse_header(rest);
+            let chunk_end = HEADER_SIZE + chunk_len;
+            if !self.fits(chunk_end) {
+                try!(self.emit_part());
+            }
+            self.part.extend_from_slice(&rest[..chunk_end]);
+            rest = &rest[chunk_end..];
+        }
+        Ok(())
+    }
+
+    /// Would a chunk of `len` bytes fit in the current part?  We need to
+    /// either fill the part exactly, or leave enough room for padding.
+    fn fits(&self, len: usize) -> bool {
+        let used = self.part.len() + len;
+        used == self.part_size || used + HEADER_SIZE <= self.part_size
+    }
+
+    /// The number of bytes currently allocated for our buffers.
+    pub fn memory_usage(&self) -> usize {
+        self.part.capacity() + self.encoder.memory_usage()
+    }
+
+    /// Compress any buffered input, emit the final, unpadded part (if it
+    /// contains any data), and return our callback.
+    pub fn finish(mut self) -> io::Result<F> {
+        try!(self.flush());
+        if self.part_has_data() {
+            let part = mem::replace(&mut self.part, vec!());
+            try!((self.on_part)(part));
+        }
+        Ok(self.on_part)
+    }
+}
+
+impl<F> Write for MultipartEncoder<F>
+    where F: FnMut(Vec<u8>) -> io::Result<()>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let taken = self.encoder.take_input(rest);
+            rest = &rest[taken..];
+            try!(self.place_chunks());
+        }
+        self.encoder.observe_write(buf.len());
+        Ok(buf.len())
+    }
+
+    /// Compress any buffered input into the current part.  This doesn't
+    /// emit the part, so the data isn't passed to `on_part` until the
+    /// part fills up or we finish.
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush();
+        self.place_chunks()
+    }
+}
+
+#[test]
+fn parts_are_independent_streams_of_fixed_size() {
+    use std::io::{Cursor, Read};
+
+    use read::{CrcMode, SnappyFramedDecoder};
+    use test_helpers::*;
+
+    // Use poorly-compressible data so that we get several parts.
+    let input = random_data(300_000);
+    let part_size = min_part_size();
+
+    let mut parts = vec!();
+    {
+        let mut encoder = MultipartEncoder::new(part_size, |part: Vec<u8>| {
+            parts.push(part);
+            Ok(())
+        }).unwrap();
+        encoder.write_all(&input).unwrap();
+        let _on_part = encoder.finish().unwrap();
+    }
+    assert!(parts.len() > 2);
+
+    let mut decompressed = vec!();
+    for (i, part) in parts.iter().enumerate() {
+        if i + 1 < parts.len() {
+            assert_eq!(part_size, part.len());
+        }
+        let mut cursor = Cursor::new(part as &[u8]);
+        let mut decoder = SnappyFramedDecoder::new(&mut cursor,
+                                                   CrcMode::Verify);
+        decoder.read_to_end(&mut decompressed).unwrap();
+    }
+    assert_eq!(input, decompressed);
+}
+
+#[test]
+fn reject_tiny_part_size() {
+    assert!(MultipartEncoder::new(1024, |_| Ok(())).is_err());
+}
+
+#[test]
+fn buffer_small_writes_into_full_chunks() {
+    use test_helpers::*;
+
+    let input = mixed_data(300_000);
+    let encode = |piece_size: usize| {
+        let mut parts = vec!();
+        {
+            let compression = Compression::default().with_chunk_size(10_000);
+            let mut encoder = MultipartEncoder::with_compression(
+                min_part_size(), compression, |part: Vec<u8>| {
+                    parts.push(part);
+                    Ok(())
+                }).unwrap();
+            for piece in input.chunks(piece_size) {
+                encoder.write_all(piece).unwrap();
+            }
+            let _on_part = encoder.finish().unwrap();
+        }
+        parts
+    };
+
+    // Tiny writes produce the same parts as one big one, and the chunk
+    // size is honoured.
+    let parts = encode(input.len());
+    assert_eq!(parts, encode(100));
+    let mut compressed = vec!();
+    for part in &parts { compressed.extend_from_slice(part); }
+    let mut pos = STREAM_IDENTIFIER.len();
+    let mut data_chunks = 0;
+    while pos < compressed.len() {
+        let (chunk_type, chunk_len) =
+            parse_header(&compressed[pos..pos + HEADER_SIZE]);
+        if chunk_type <= 0x01 { data_chunks += 1; }
+        pos += HEADER_SIZE + chunk_len;
+    }
+    assert_eq!((input.len() + 9_999) / 10_000, data_chunks);
+}
@@ -0,0 +1,924 @@
+//! Coders which spread the work of compression and checksumming across
+//! several threads.
+//!
+//! Every chunk in a Snappy framed stream is self-contained, so chunks can
+//! be processed in parallel, as long as the results are returned in
+//! order.  The output is identical to that of a single-threaded coder.
+//!
+//! ```
+//! use std::io::{Read, Write};
+//! use snappy_framed::parallel::{SnappyFramedDecoder, SnappyFramedEncoder};
+//! use snappy_framed::read::CrcMode;
+//!
+//! let mut encoder = SnappyFramedEncoder::new(vec!(), 4).unwrap();
+//! encoder.write_all(b"Hello!").unwrap();
+//! let compressed = encoder.finish().unwrap();
+//!
+//! let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+//!                                            CrcMode::Verify, 4);
+//! let mut output = vec!();
+//! decoder.read_to_end(&mut output).unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+//! ```
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "rayon")] use rayon::prelude::*;
+
+use buffer::copy_output;
+use chunk::*;
+use compression::Compression;
+#[cfg(feature = "rayon")] use consts::*;
+#[cfg(feature = "rayon")] use error::SnappyFramedError;
+use read::{self, CrcMode};
+use sans_io::{ChunkDecoder, ChunkEncoder};
+use write::{self, Checkpoint};
+
+/// How many chunks each worker may have queued or finished but unwritten.
+const CHUNKS_PER_THREAD: usize = 2;
+
+/// How many chunks a pipeline's background thread may get ahead of us.
+const PIPELINE_DEPTH: usize = 2;
+
+fn worker_failed() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Snappy worker thread failed")
+}
+
+/// A pool of threads which each apply `work` to the jobs they receive,
+/// and send back the results labelled with the job's sequence number.
+/// If `work` panics, the result is `None`.
+struct Workers<T: Send + 'static, U: Send + 'static> {
+    jobs: Option<Sender<(u64, T)>>,
+    results: Receiver<(u64, Option<U>)>,
+    threads: Vec<JoinHandle<()>>
+}
+
+impl<T: Send + 'static, U: Send + 'static> Workers<T, U> {
+    fn new<F>(threads: usize, work: F) -> Self
+        where F: Fn(T) -> U + Send + Sync + 'static
+    {
+        assert!(threads > 0);
+        let (jobs, job_receiver) = channel::<(u64, T)>();
+        let (result_sender, results) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let work = Arc::new(work);
+        let threads = (0..threads).map(|_| {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            let work = work.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = match job_receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return
+                    };
+                    let (seq, input) = match job {
+                        Ok(job) => job,
+                        Err(_) => return
+                    };
+                    // Report panics, or `recv` would wait forever.
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        work(input)
+                    }));
+                    if result_sender.send((seq, result.ok())).is_err() {
+                        return;
+                    }
+                }
+            })
+        }).collect();
+        Workers{jobs: Some(jobs), results: results, threads: threads}
+    }
+
+    fn send(&self, seq: u64, job: T) -> io::Result<()> {
+        let jobs = self.jobs.as_ref().expect("workers already stopped");
+        jobs.send((seq, job)).map_err(|_| worker_failed())
+    }
+
+    /// Wait for the next result, in whatever order they finish.
+    fn recv(&self) -> io::Result<(u64, U)> {
+        match self.results.recv() {
+            Ok((seq, Some(result))) => Ok((seq, result)),
+            _ => Err(worker_failed())
+        }
+    }
+
+    /// Let our threads finish their current jobs and exit.
+    fn stop(&mut self) {
+        self.jobs = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<T: Send + 'static, U: Send + 'static> Drop for Workers<T, U> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Compress data written to it into a Snappy framed stream, using several
+/// threads.  Adaptive chunk sizes aren't supported, but the other
+/// `Compression` options are, and the output is byte-for-byte identical to
+/// that of a `write::SnappyFramedEncoder` given the same options and the
+/// same calls to `flush`, however many threads we use and however the
+/// input is split between calls to `write`.
+pub struct SnappyFramedEncoder<W: Write> {
+    /// Our destination.  This is only `None` after `finish` has taken it.
+    dest: Option<W>,
+    workers: Workers<Vec<u8>, Vec<u8>>,
+    chunk_size: usize,
+    max_in_flight: usize,
+    /// Input which doesn't yet fill a chunk.
+    pending: Vec<u8>,
+    /// The sequence number of the next chunk we'll send to a worker.
+    next_job: u64,
+    /// The sequence number of the next chunk we'll write.
+    next_write: u64,
+    /// Encoded chunks waiting for earlier chunks to finish.
+    finished: BTreeMap<u64, Vec<u8>>,
+    /// Set if a write to `dest` failed, leaving our output incomplete.
+    poisoned: bool
+}
+
+impl<W: Write> SnappyFramedEncoder<W> {
+    /// Create a new encoder wrapping `dest`, using `threads` worker
+    /// threads.
+    pub fn new(dest: W, threads: usize) -> io::Result<Self> {
+        SnappyFramedEncoder::with_compression(dest, threads,
+                                              Compression::default())
+    }
+
+    /// Create a new encoder wrapping `dest`, using `threads` worker
+    /// threads and the options in `compression`, except for adaptive
+    /// chunk sizing, which is turned off.
+    pub fn with_compression(mut dest: W, threads: usize,
+                            compression: Compression) -> io::Result<Self> {
+        try!(dest.write_all(&STREAM_IDENTIFIER));
+        let compression = compression.with_adaptive_chunk_size(false);
+        Ok(SnappyFramedEncoder{
+            dest: Some(dest),
+            // Each chunk is built just as the serial encoder would build it.
+            workers: Workers::new(threads, move |data: Vec<u8>| {
+                let mut encoder =
+                    ChunkEncoder::resume(Checkpoint::default(), compression);
+                encoder.write_input(&data);
+                encoder.flush();
+                encoder.take_output()
+            }),
+            chunk_size: compression.chunk_size(),
+            max_in_flight: threads * CHUNKS_PER_THREAD,
+            pending: Vec::with_capacity(compression.chunk_size()),
+            next_job: 0,
+            next_write: 0,
+            finished: BTreeMap::new(),
+            poisoned: false
+        })
+    }
+
+    /// Get a reference to our destination.
+    pub fn get_ref(&self) -> &W {
+        self.dest.as_ref().expect("encoder destination already taken")
+    }
+
+    fn dest_mut(&mut self) -> &mut W {
+        self.dest.as_mut().expect("encoder destination already taken")
+    }
+
+    /// Hand `data` to a worker, first waiting for earlier chunks if too
+    /// many are in flight.
+    fn send_chunk(&mut self, data: Vec<u8>) -> io::Result<()> {
+        while self.next_job - self.next_write >= self.max_in_flight as u64 {
+            try!(self.write_next_finished());
+        }
+        try!(self.workers.send(self.next_job, data));
+        self.next_job += 1;
+        Ok(())
+    }
+
+    /// Wait for at least one more chunk to finish, and write any finished
+    /// chunks which are next in line.
+    fn write_next_finished(&mut self) -> io::Result<()> {
+        let (seq, chunk) = try!(self.workers.recv());
+        self.finished.insert(seq, chunk);
+        while let Some(chunk) = self.finished.remove(&self.next_write) {
+            if let Err(err) = self.dest_mut().write_all(&chunk) {
+                self.poisoned = true;
+                return Err(err);
+            }
+            self.next_write += 1;
+        }
+        Ok(())
+    }
+
+    /// Compress any buffered input as a chunk, and wait until every chunk
+    /// has been written to our destination, without flushing it.
+    pub fn flush_buffer(&mut self) -> io::Result<()> {
+        try!(self.check_poisoned());
+        if !self.pending.is_empty() {
+            let capacity = self.chunk_size;
+            let data = mem::replace(&mut self.pending,
+                                    Vec::with_capacity(capacity));
+            try!(self.send_chunk(data));
+        }
+        while self.next_write < self.next_job {
+            try!(self.write_next_finished());
+        }
+        Ok(())
+    }
+
+    /// Write all remaining data, stop our worker threads, and return our
+    /// destination.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.flush());
+        self.workers.stop();
+        Ok(self.dest.take().expect("encoder destination already taken"))
+    }
+
+    /// Fail if an earlier write error left our output in an unknown state.
+    fn check_poisoned(&self) -> io::Result<()> {
+        if self.poisoned {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "Snappy encoder unusable after earlier write error"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<W: Write> Write for SnappyFramedEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.check_poisoned());
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let wanted = self.chunk_size - self.pending.len();
+            let (now, later) = rest.split_at(min(wanted, rest.len()));
+            self.pending.extend_from_slice(now);
+            rest = later;
+            if self.pending.len() == self.chunk_size {
+                let capacity = self.chunk_size;
+                let data = mem::replace(&mut self.pending,
+                                        Vec::with_capacity(capacity));
+                try!(self.send_chunk(data));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.flush_buffer());
+        self.dest_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for SnappyFramedEncoder<W> {
+    /// Write any buffered input, logging any errors, just like
+    /// `write::SnappyFramedEncoder`.
+    fn drop(&mut self) {
+        if self.dest.is_none() || self.poisoned { return; }
+        if let Err(err) = self.flush_buffer() {
+            error!("Snappy encoder could not write buffered data: {}", err);
+        }
+    }
+}
+
+/// Decode a Snappy framed stream, decompressing and verifying chunks on
+/// several threads.  We read ahead a few chunks per thread, and return
+/// their contents in order.  Any data decoded before an error is returned
+/// before the error itself.
+///
+/// In `CrcMode::Auto`, each chunk's byte order is detected separately.
+/// Data chunks are always decompressed using the default raw codec.
+pub struct SnappyFramedDecoder<R: Read> {
+    source: R,
+    /// Checks each chunk in order, and holds any we've partly read.
+    state: ChunkDecoder,
+    /// Scratch space for reading from `source`.
+    buf: Vec<u8>,
+    /// Decode data chunks using the decoders they're sent with.
+    workers: Workers<(ChunkDecoder, u8, Vec<u8>), io::Result<Vec<u8>>>,
+    max_in_flight: usize,
+    /// Have we reached the end of our source, or an error reading it?
+    done: bool,
+    /// The sequence number of the next chunk we'll send to a worker.
+    next_job: u64,
+    /// The sequence number of the next chunk we'll return.
+    next_read: u64,
+    /// Decoded chunks waiting for earlier chunks to be returned.
+    finished: BTreeMap<u64, io::Result<Vec<u8>>>,
+    /// Decompressed data.
+    output: Vec<u8>,
+    /// How much of `output` has been returned.
+    pos: usize
+}
+
+impl<R: Read> SnappyFramedDecoder<R> {
+    /// Create a new decoder wrapping `source`, using `threads` worker
+    /// threads and the CRC verification options indicated by `mode`.
+    pub fn new(source: R, mode: CrcMode, threads: usize) -> Self {
+        type Job = (ChunkDecoder, u8, Vec<u8>);
+        SnappyFramedDecoder{
+            source: source,
+            state: ChunkDecoder::new(mode),
+            buf: vec!(),
+            workers: Workers::new(threads, |job: Job| {
+                let (mut decoder, chunk_type, data) = job;
+                let data = try!(decoder.decode_data(&Chunk{
+                    chunk_type: chunk_type,
+                    data: &data
+                }));
+                Ok(data.into_owned())
+            }),
+            max_in_flight: threads * CHUNKS_PER_THREAD,
+            done: false,
+            next_job: 0,
+            next_read: 0,
+            finished: BTreeMap::new(),
+            output: vec!(),
+            pos: 0
+        }
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// The state machine which checks each chunk.  Use this to set any of
+    /// the decoding options it supports.
+    pub fn chunk_decoder_mut(&mut self) -> &mut ChunkDecoder {
+        &mut self.state
+    }
+
+    /// Read until we find the next data chunk, and hand it to a worker.
+    /// Other chunks are checked and skipped.
+    fn read_chunk(&mut self) -> io::Result<()> {
+        loop {
+            if let Some((chunk_type, data)) = try!(self.state.next_data_chunk())
+            {
+                let job = (self.state.data_decoder(), chunk_type, data);
+                try!(self.workers.send(self.next_job, job));
+                self.next_job += 1;
+                return Ok(());
+            }
+            if !try!(self.state.read_from(&mut self.source, &mut self.buf)) {
+                self.done = true;
+                return self.state.finish().map_err(|err| err.into());
+            }
+        }
+    }
+
+    /// Keep our workers busy by reading ahead.  If reading fails, we
+    /// queue the error behind the chunks we've already read.
+    fn read_ahead(&mut self) {
+        while !self.done &&
+            self.next_job - self.next_read < self.max_in_flight as u64
+        {
+            if let Err(err) = self.read_chunk() {
+                self.finished.insert(self.next_job, Err(err));
+                self.next_job += 1;
+                self.done = true;
+            }
+        }
+    }
+
+    /// Wait for the next chunk in order.  Returns `false` at the end of
+    /// the stream.
+    fn fill_output(&mut self) -> io::Result<bool> {
+        self.read_ahead();
+        if self.next_read == self.next_job { return Ok(false); }
+        while !self.finished.contains_key(&self.next_read) {
+            let (seq, result) = try!(self.workers.recv());
+            self.finished.insert(seq, result);
+        }
+        let result = self.finished.remove(&self.next_read)
+            .expect("chunk should have finished");
+        self.next_read += 1;
+        self.output = try!(result);
+        self.pos = 0;
+        self.read_ahead();
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for SnappyFramedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.output.len() {
+            if !try!(self.fill_output()) { return Ok(0); }
+        }
+        Ok(copy_output(&self.output, &mut self.pos, buf))
+    }
+}
+
+/// Decode a Snappy framed stream on a background thread, so that reading
+/// and decompressing the next chunk overlaps with the caller's use of the
+/// current one.  This hides I/O latency without needing many cores.
+///
+/// ```
+/// use std::io::{Cursor, Read, Write};
+/// use snappy_framed::parallel::PipelinedDecoder;
+/// use snappy_framed::read::CrcMode;
+/// use snappy_framed::write::SnappyFramedEncoder;
+///
+/// let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+/// encoder.write_all(b"Hello!").unwrap();
+/// let compressed = encoder.finish().unwrap();
+///
+/// let mut decoder = PipelinedDecoder::new(Cursor::new(compressed),
+///                                         CrcMode::Verify);
+/// let mut output = vec!();
+/// decoder.read_to_end(&mut output).unwrap();
+/// assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+/// ```
+pub struct PipelinedDecoder {
+    /// Each message holds the contents of a data chunk, or an error.  An
+    /// empty chunk marks the end of the stream.
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    /// Have we reached the end of the stream, or an error?
+    done: bool,
+    /// Decompressed data.
+    output: Vec<u8>,
+    /// How much of `output` has been returned.
+    pos: usize
+}
+
+impl PipelinedDecoder {
+    /// Start decoding `source` on a background thread, using the CRC
+    /// verification options indicated by `mode`.  If we're dropped before
+    /// the end of the stream, the thread exits after its current chunk.
+    pub fn new<R>(source: R, mode: CrcMode) -> Self
+        where R: Read + Send + 'static
+    {
+        let (sender, chunks) = sync_channel(PIPELINE_DEPTH);
+        thread::spawn(move || {
+            let mut decoder = read::SnappyFramedDecoder::new(source, mode);
+            let mut frames = vec!();
+            loop {
+                let message = match decoder.decode_frames_into(&mut frames, 1) {
+                    Ok(0) => Ok(vec!()),
+                    Ok(_) => {
+                        let frame = frames.pop().expect("frame decoded");
+                        if frame.is_empty() { continue; }
+                        Ok(frame)
+                    }
+                    Err(err) => Err(err)
+                };
+                let last = match message {
+                    Ok(ref frame) => frame.is_empty(),
+                    Err(_) => true
+                };
+                if sender.send(message).is_err() || last { return; }
+            }
+        });
+        PipelinedDecoder{chunks: chunks, done: false, output: vec!(), pos: 0}
+    }
+}
+
+impl Read for PipelinedDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.output.len() {
+            if self.done { return Ok(0); }
+            match self.chunks.recv() {
+                Ok(Ok(frame)) => {
+                    self.done = frame.is_empty();
+                    self.output = frame;
+                    self.pos = 0;
+                }
+                Ok(Err(err)) => {
+                    self.done = true;
+                    return Err(err);
+                }
+                Err(_) => {
+                    self.done = true;
+                    return Err(worker_failed());
+                }
+            }
+        }
+        Ok(copy_output(&self.output, &mut self.pos, buf))
+    }
+}
+
+/// A message to a `PipelinedEncoder`'s background thread.
+enum PipelineMessage {
+    /// Compress this data.
+    Data(Vec<u8>),
+    /// Flush our destination, and report the result.
+    Flush(SyncSender<io::Result<()>>)
+}
+
+/// Compress data on a background thread, so that `write` returns as soon
+/// as the data has been queued.  If the queue is full, because the thread
+/// can't keep up, `write` blocks until there's room.
+///
+/// Errors writing to our destination are reported by the next call to
+/// `write`, `flush` or `finish`.
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use snappy_framed::parallel::PipelinedEncoder;
+/// use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+///
+/// let mut encoder = PipelinedEncoder::new(vec!()).unwrap();
+/// encoder.write_all(b"Hello!").unwrap();
+/// let compressed = encoder.finish().unwrap();
+///
+/// let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+///                                            CrcMode::Verify);
+/// let mut output = vec!();
+/// decoder.read_to_end(&mut output).unwrap();
+/// assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+/// ```
+pub struct PipelinedEncoder<W: Write + Send + 'static> {
+    /// Our queue.  This is only `None` once we've finished.
+    messages: Option<SyncSender<PipelineMessage>>,
+    /// Our thread, which returns our destination when it's done.
+    thread: Option<JoinHandle<io::Result<W>>>,
+    chunk_size: usize,
+    /// Input which doesn't yet fill a chunk.
+    pending: Vec<u8>
+}
+
+impl<W: Write + Send + 'static> PipelinedEncoder<W> {
+    /// Create a new encoder wrapping `dest`.
+    pub fn new(dest: W) -> io::Result<Self> {
+        PipelinedEncoder::with_compression(dest, Compression::default())
+    }
+
+    /// Create a new encoder wrapping `dest`, and using the options in
+    /// `compression`.
+    pub fn with_compression(dest: W, compression: Compression) ->
+        io::Result<Self>
+    {
+        let mut encoder = try!(
+            write::SnappyFramedEncoder::with_compression(dest, compression));
+        let (messages, receiver) = sync_channel(PIPELINE_DEPTH);
+        let thread = thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    PipelineMessage::Data(data) => {
+                        try!(encoder.write_all(&data));
+                        try!(encoder.flush_buffer());
+                    }
+                    PipelineMessage::Flush(done) => {
+                        let _ = done.send(encoder.flush());
+                    }
+                }
+            }
+            encoder.finish()
+        });
+        Ok(PipelinedEncoder{
+            messages: Some(messages),
+            thread: Some(thread),
+            chunk_size: compression.chunk_size(),
+            pending: Vec::with_capacity(compression.chunk_size())
+        })
+    }
+
+    /// Stop our thread, and return our destination or the error which
+    /// stopped it.
+    fn join(&mut self) -> io::Result<W> {
+        self.messages = None;
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Err(worker_failed())
+        };
+        match thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(worker_failed())
+        }
+    }
+
+    /// Queue `message`, waiting if the queue is full.
+    fn send(&mut self, message: PipelineMessage) -> io::Result<()> {
+        let sent = match self.messages {
+            Some(ref messages) => messages.send(message).is_ok(),
+            None => false
+        };
+        if sent { return Ok(()); }
+        // Our thread stopped early, so find out why.
+        match self.join() {
+            Ok(_) => Err(worker_failed()),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Queue any buffered input to be written as a chunk.
+    fn send_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() { return Ok(()); }
+        let capacity = self.chunk_size;
+        let data = mem::replace(&mut self.pending,
+                                Vec::with_capacity(capacity));
+        self.send(PipelineMessage::Data(data))
+    }
+
+    /// Write all remaining data, stop our thread, and return our
+    /// destination.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.send_pending());
+        self.join()
+    }
+}
+
+impl<W: Write + Send + 'static> Write for PipelinedEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let wanted = self.chunk_size - self.pending.len();
+            let (now, later) = rest.split_at(min(wanted, rest.len()));
+            self.pending.extend_from_slice(now);
+            rest = later;
+            if self.pending.len() == self.chunk_size {
+                try!(self.send_pending());
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Wait until everything written so far has been compressed and
+    /// written, and flush our destination.
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.send_pending());
+        let (done, result) = sync_channel(1);
+        try!(self.send(PipelineMessage::Flush(done)));
+        match result.recv() {
+            Ok(result) => result,
+            Err(_) => self.join().and(Err(worker_failed()))
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> Drop for PipelinedEncoder<W> {
+    /// Write any buffered input and wait for our thread to exit, logging
+    /// any errors.
+    fn drop(&mut self) {
+        if self.thread.is_none() { return; }
+        if let Err(err) = self.send_pending().and_then(|()| self.join()) {
+            error!("Snappy encoder could not write buffered data: {}", err);
+        }
+    }
+}
+
+/// Compress `buffers` into a single Snappy framed stream using rayon's
+/// thread pool.  Each buffer starts a new chunk, and is split into
+/// chunks of `MAX_UNCOMPRESSED_CHUNK` bytes.
+///
+/// ```
+/// use snappy_framed::parallel::{compress_chunks_parallel,
+///                               decompress_parallel};
+///
+/// let compressed = compress_chunks_parallel(&[b"Hello, ", b"world!"]);
+/// assert_eq!(b"Hello, world!" as &[u8],
+///            &decompress_parallel(&compressed).unwrap() as &[u8]);
+/// ```
+#[cfg(feature = "rayon")]
+pub fn compress_chunks_parallel(buffers: &[&[u8]]) -> Vec<u8> {
+    let pieces: Vec<&[u8]> = buffers.iter()
+        .flat_map(|buffer| buffer.chunks(MAX_UNCOMPRESSED_CHUNK))
+        .collect();
+    let chunks: Vec<Vec<u8>> = pieces.par_iter().map(|data| {
+        let mut chunk = vec!();
+        append_data_chunk(data, &mut chunk);
+        chunk
+    }).collect();
+    let mut compressed = STREAM_IDENTIFIER.to_vec();
+    for chunk in chunks { compressed.extend(chunk); }
+    compressed
+}
+
+/// Decompress the Snappy framed stream in `compressed` using rayon's
+/// thread pool, verifying CRCs.
+#[cfg(feature = "rayon")]
+pub fn decompress_parallel(compressed: &[u8]) -> io::Result<Vec<u8>> {
+    // Check every chunk in order, and keep the data chunks.
+    let mut state = ChunkDecoder::new(CrcMode::Verify);
+    let mut chunks = vec!();
+    let mut rest = compressed;
+    while !rest.is_empty() {
+        if rest.len() < HEADER_SIZE {
+            return Err(state.truncated(rest).into());
+        }
+        let (chunk_type, chunk_len) = parse_header(&rest[..HEADER_SIZE]);
+        try!(state.check_header(chunk_type, chunk_len));
+        if rest.len() - HEADER_SIZE < chunk_len {
+            return Err(state.truncated(rest).into());
+        }
+        let chunk = Chunk{
+            chunk_type: chunk_type,
+            data: &rest[HEADER_SIZE..HEADER_SIZE + chunk_len]
+        };
+        if try!(state.frame_chunk(&chunk)).is_none() { chunks.push(chunk); }
+        rest = &rest[HEADER_SIZE + chunk_len..];
+    }
+    try!(state.end_of_stream());
+
+    let decoded: Vec<Vec<u8>> = try!(chunks.par_iter().map(|chunk| {
+        let data = try!(state.data_decoder().decode_data(chunk));
+        Ok(data.into_owned())
+    }).collect::<Result<_, SnappyFramedError>>());
+    Ok(decoded.concat())
+}
+
+/// Write `input` to `encoder` a piece at a time, and flush it.
+#[cfg(test)]
+fn write_in_pieces<W: Write>(encoder: &mut W, input: &[u8]) {
+    for piece in input.chunks(10_000) {
+        encoder.write_all(piece).unwrap();
+    }
+    encoder.flush().unwrap();
+}
+
+/// Read everything from `decoder`, returning the data along with the
+/// result of one more read after the end.
+#[cfg(test)]
+fn read_all<R: Read>(mut decoder: R) -> (Vec<u8>, io::Result<usize>) {
+    let mut output = vec!();
+    let result = decoder.read_to_end(&mut output)
+        .and_then(|_| decoder.read(&mut [0; 10]));
+    (output, result)
+}
+
+#[test]
+fn coders_round_trip() {
+    use consts::*;
+    use std::io::Cursor;
+    use test_helpers::*;
+    use write;
+
+    type Encode = fn(&[u8]) -> Vec<u8>;
+    type Decode = fn(&[u8]) -> (Vec<u8>, io::Result<usize>);
+
+    let encoders: Vec<(&str, Encode)> = vec!(
+        ("parallel, 1 thread", |input: &[u8]| {
+            let mut encoder = SnappyFramedEncoder::new(vec!(), 1).unwrap();
+            write_in_pieces(&mut encoder, input);
+            encoder.finish().unwrap()
+        }),
+        ("parallel, 4 threads", |input: &[u8]| {
+            let mut encoder = SnappyFramedEncoder::new(vec!(), 4).unwrap();
+            write_in_pieces(&mut encoder, input);
+            encoder.finish().unwrap()
+        }),
+        ("pipelined", |input: &[u8]| {
+            let mut encoder = PipelinedEncoder::new(vec!()).unwrap();
+            write_in_pieces(&mut encoder, input);
+            encoder.finish().unwrap()
+        }),
+    );
+    let decoders: Vec<(&str, Decode)> = vec!(
+        ("parallel, 1 thread", |compressed: &[u8]| {
+            read_all(SnappyFramedDecoder::new(compressed, CrcMode::Verify, 1))
+        }),
+        ("parallel, 4 threads", |compressed: &[u8]| {
+            read_all(SnappyFramedDecoder::new(compressed, CrcMode::Verify, 4))
+        }),
+        ("pipelined", |compressed: &[u8]| {
+            let source = Cursor::new(compressed.to_vec());
+            read_all(PipelinedDecoder::new(source, CrcMode::Verify))
+        }),
+    );
+
+    let input = mixed_data(500_000);
+    let mut expected = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    expected.write_all(&input).unwrap();
+    let expected = expected.finish().unwrap();
+    for &(name, encode) in &encoders {
+        assert!(expected == encode(&input), "{} encoder differs", name);
+    }
+
+    // Skippable chunks are ignored, and any data before a corrupt or
+    // truncated chunk is returned before the error.
+    let mut encoder = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(&input).unwrap();
+    encoder.write_skippable_chunk(0x90, b"metadata").unwrap();
+    let compressed = encoder.finish().unwrap();
+    let mut corrupt = compressed.clone();
+    let last = corrupt.len() - 20;
+    corrupt[last] ^= 1;
+    let truncated = &compressed[..compressed.len() - 1];
+    for &(name, decode) in &decoders {
+        let (output, result) = decode(&compressed);
+        assert_eq!(0, result.unwrap());
+        assert!(input == output, "{} decoder differs", name);
+
+        for bad in &[&corrupt as &[u8], truncated] {
+            let (output, result) = decode(bad);
+            assert!(result.is_err(), "{} decoder accepted bad input", name);
+            assert!(&input[..output.len()] == &output as &[u8]);
+            assert!(output.len() >= input.len() - MAX_UNCOMPRESSED_CHUNK);
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn rayon_bulk_round_trip() {
+    use test_helpers::*;
+
+    let first = random_data(200_000);
+    let second = repeat_data(b"compressible ", 10_000);
+    let compressed = compress_chunks_parallel(&[&first, b"", &second]);
+    let mut expected = first.clone();
+    expected.extend(&second);
+    assert_eq!(expected, decompress_parallel(&compressed).unwrap());
+
+    let truncated = &compressed[..compressed.len() - 1];
+    assert!(decompress_parallel(truncated).is_err());
+}
+
+#[test]
+fn encoders_match_serial_encoder() {
+    use test_helpers::*;
+
+    let input = mixed_data(300_000);
+    let options = [
+        Compression::default(),
+        Compression::fast().with_chunk_size(1000),
+        Compression::default().with_min_savings_percent(50),
+        Compression::default().with_max_frame_size(2000),
+        Compression::best().with_adaptive_chunk_size(true)
+    ];
+    for &compression in &options {
+        let serial = compression.with_adaptive_chunk_size(false);
+        let mut expected =
+            write::SnappyFramedEncoder::with_compression(vec!(), serial)
+            .unwrap();
+        expected.write_all(&input[..100_000]).unwrap();
+        expected.flush().unwrap();
+        expected.write_all(&input[100_000..]).unwrap();
+        let expected = expected.finish().unwrap();
+
+        for &threads in &[1, 3, 8] {
+            for &piece_size in &[input.len(), 777] {
+                let mut encoder = SnappyFramedEncoder::with_compression(
+                    vec!(), threads, compression).unwrap();
+                for piece in input[..100_000].chunks(piece_size) {
+                    encoder.write_all(piece).unwrap();
+                }
+                encoder.flush().unwrap();
+                for piece in input[100_000..].chunks(piece_size) {
+                    encoder.write_all(piece).unwrap();
+                }
+                assert!(expected == encoder.finish().unwrap(),
+                        "{} threads with {:?} differ", threads, compression);
+            }
+        }
+    }
+}
+
+#[test]
+fn decoder_uses_chunk_decoder_options() {
+    use test_helpers::*;
+
+    // A reserved unskippable chunk after the data.
+    let input = mixed_data(100_000);
+    let mut compressed = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    compressed.write_all(&input).unwrap();
+    let mut compressed = compressed.finish().unwrap();
+    compressed.extend_from_slice(&header(0x02, 0));
+
+    let mut decoder =
+        SnappyFramedDecoder::new(&compressed as &[u8], CrcMode::Verify, 2);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+
+    let mut decoder =
+        SnappyFramedDecoder::new(&compressed as &[u8], CrcMode::Verify, 2);
+    decoder.chunk_decoder_mut().set_reject_reserved_chunks(true);
+    let mut output = vec!();
+    assert!(decoder.read_to_end(&mut output).is_err());
+    assert_eq!(input, output);
+}
+
+#[test]
+fn pipelined_encoder_reports_write_errors() {
+    use std::io::Cursor;
+    use test_helpers::*;
+
+    // A destination which fills up after 100,000 bytes.
+    let dest = Cursor::new(vec![0; 100_000].into_boxed_slice());
+    let mut encoder = PipelinedEncoder::new(dest).unwrap();
+    let input = random_data(1_000_000);
+    let written = encoder.write_all(&input).and_then(|()| encoder.flush());
+    assert!(written.is_err());
+}
+
+#[test]
+fn workers_report_panics() {
+    let workers = Workers::new(2, |job: u64| {
+        assert!(job != 1, "simulated worker panic");
+        job
+    });
+    for job in 0..3 {
+        workers.send(job, job).unwrap();
+    }
+    let succeeded = (0..3).filter(|_| workers.recv().is_ok()).count();
+    assert_eq!(2, succeeded);
+}
@@ -0,0 +1,14 @@
+//! Re-exports of the most commonly used types, so that you can write:
+//!
+//! ```
+//! use snappy_framed::prelude::*;
+//!
+//! let encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+//! let mut decoder = SnappyFramedDecoder::new(&[] as &[u8], CrcMode::Verify);
+//! # drop((encoder, &mut decoder));
+//! ```
+
+pub use compression::Compression;
+pub use config::Config;
+pub use read::{CrcMode, SnappyFramedDecoder};
+pub use write::{FlushMode, SnappyFramedEncoder};
@@ -0,0 +1,86 @@
+//! The raw Snappy implementation we're built with.  By default, this is
+//! Google's C++ `libsnappy`, via the `snappy` crate.  The `snap` feature
+//! selects the pure-Rust `snap` crate instead, which doesn't need a C
+//! toolchain or `libsnappy` to build.
+//!
+//! To use some other implementation, such as a vendored SIMD build or a
+//! system library, implement `RawCodec` and pass it to
+//! `SnappyFramedEncoder::set_raw_codec` or
+//! `SnappyFramedDecoder::set_raw_codec`.  We still handle the framing,
+//! CRCs and chunk size limits ourselves.
+
+#[cfg(feature = "snap")] use snap;
+#[cfg(not(feature = "snap"))] use snappy;
+
+/// Compresses and decompresses individual blocks of raw Snappy data.
+pub trait RawCodec {
+    /// Compress `data` as a single block of raw Snappy data.  `data` is
+    /// never longer than `MAX_UNCOMPRESSED_CHUNK`.
+    fn compress(&mut self, data: &[u8]) -> Vec<u8>;
+
+    /// Decompress a block of raw Snappy data, or return `None` if it's
+    /// corrupt.
+    fn uncompress(&mut self, compressed: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// The implementation we're built with, as selected by the `snap`
+/// feature.  This is what we use unless told otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultCodec;
+
+impl RawCodec for DefaultCodec {
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        compress(data)
+    }
+
+    fn uncompress(&mut self, compressed: &[u8]) -> Option<Vec<u8>> {
+        uncompress(compressed)
+    }
+}
+
+/// Compress `data` as a single block of raw Snappy data.
+#[cfg(not(feature = "snap"))]
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    snappy::compress(data)
+}
+
+/// Compress `data` as a single block of raw Snappy data.
+#[cfg(feature = "snap")]
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // This only fails for inputs of 4GB or more, which are far larger
+    // than any chunk.
+    snap::raw::Encoder::new().compress_vec(data)
+        .expect("Snappy input too large")
+}
+
+/// Decompress a block of raw Snappy data, or return `None` if it's
+/// corrupt.
+#[cfg(not(feature = "snap"))]
+pub fn uncompress(compressed: &[u8]) -> Option<Vec<u8>> {
+    snappy::uncompress(compressed)
+}
+
+/// Decompress a block of raw Snappy data, or return `None` if it's
+/// corrupt.
+#[cfg(feature = "snap")]
+pub fn uncompress(compressed: &[u8]) -> Option<Vec<u8>> {
+    snap::raw::Decoder::new().decompress_vec(compressed).ok()
+}
+
+#[test]
+fn raw_round_trip() {
+    let data = b"Hello, Hello, Hello, Hello!";
+    let compressed = compress(data);
+    assert!(compressed.len() < data.len());
+    assert_eq!(Some(data.to_vec()), uncompress(&compressed));
+    assert_eq!(None, uncompress(b"\xff\xff\xff\xff\xff"));
+}
+
+#[test]
+fn default_codec_round_trip() {
+    let data = b"Hello, Hello, Hello, Hello!";
+    let mut codec = DefaultCodec;
+    let compressed = codec.compress(data);
+    assert_eq!(compress(data), compressed);
+    assert_eq!(Some(data.to_vec()), codec.uncompress(&compressed));
+}
@@ -1,50 +1,219 @@
 //! Implementations of `Read` using Snappy compression.
 
-use snappy;
-use std::cmp::min;
-use std::io::{self, Read};
+use std::cmp::{max, min};
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::path::Path;
 
-use buffer::Buffer;
+use budget::MemoryBudget;
+use buffer::{copy_output, Buffer};
+use chunk::*;
+use compression::Compression;
+use config::Config;
 use consts::*;
-use masked_crc::*;
+use error::SnappyFramedError;
+use masked_crc::masked_crc;
+use raw::RawCodec;
+use sans_io::{ChunkContents, ChunkDecoder};
+use seek::{ChunkIndex, IndexEntry};
+use write;
+#[cfg(feature = "timing")] use timing::Timings;
 
-/// Should we verify or ignore the CRC when reading?
-#[derive(Debug, PartialEq, Eq)]
+/// Should we verify or ignore the CRC when reading?  Defaults to
+/// `Verify`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum CrcMode {
     /// Verify that the CRC values in the stream are correct.
     Verify,
     /// Ignore the CRC values.
-    Ignore
+    Ignore,
+    /// Verify CRC values, except that a CRC of zero means the chunk has
+    /// no checksum.  Some non-standard encoders write streams like this.
+    VerifyNonZero,
+    /// Verify CRC values stored in the byte order used by the Node.js and
+    /// Python implementations, which is the reverse of the order used by
+    /// Java, `snzip` and our own encoder.
+    VerifySwapped,
+    /// Detect the byte order from the first data chunk, and verify the
+    /// rest of the stream using that order.  The first chunk's CRC must
+    /// match in one order or the other.
+    Auto
 }
 
-/// A framed chunk in a Snappy stream.
+impl CrcMode {
+    /// Should we check a chunk whose stored CRC is `crc`?
+    pub(crate) fn should_verify(self, crc: u32) -> bool {
+        match self {
+            CrcMode::Verify => true,
+            CrcMode::Ignore => false,
+            CrcMode::VerifyNonZero => crc != 0,
+            CrcMode::VerifySwapped | CrcMode::Auto => true
+        }
+    }
+
+    /// Convert a CRC as stored in the stream to the byte order returned by
+    /// `masked_crc`.  `Auto` must be resolved using `detect` first.
+    pub fn stored_to_native(self, crc: u32) -> u32 {
+        match self {
+            CrcMode::VerifySwapped => crc.swap_bytes(),
+            _ => crc
+        }
+    }
+
+    /// If we're in `Auto` mode, decide which byte order was used to store
+    /// `crc`, the CRC of `data`.  If neither order matches, we use the
+    /// standard order, so that verification fails.
+    pub fn detect(self, crc: u32, data: &[u8]) -> CrcMode {
+        if self != CrcMode::Auto { return self; }
+        let actual = masked_crc(data);
+        if actual != crc && actual == crc.swap_bytes() {
+            CrcMode::VerifySwapped
+        } else {
+            CrcMode::Verify
+        }
+    }
+}
+
+impl Default for CrcMode {
+    fn default() -> CrcMode {
+        CrcMode::Verify
+    }
+}
+
+/// How large may the data in a single chunk be?  Defaults to `Strict`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ChunkSizePolicy {
+    /// Reject chunks containing more than `MAX_UNCOMPRESSED_CHUNK` bytes
+    /// of data, as required by the spec.
+    Strict,
+    /// Accept chunks containing up to the specified number of bytes of
+    /// data, for reading streams from encoders which ignore the spec.
+    /// Our input buffer may grow to hold a couple of chunks this large.
+    /// Limits larger than a chunk's 24-bit length field can describe are
+    /// reduced to fit, so `Lenient(usize::MAX)` accepts any chunk.
+    Lenient(usize)
+}
+
+impl Default for ChunkSizePolicy {
+    fn default() -> ChunkSizePolicy {
+        ChunkSizePolicy::Strict
+    }
+}
+
+/// Receives the skippable chunks found by a decoder, which may carry
+/// application metadata.
+///
+/// This is implemented for closures, so you can write:
+///
+/// ```
+/// use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+///
+/// let mut decoder = SnappyFramedDecoder::new(&[] as &[u8], CrcMode::Verify);
+/// decoder.set_skippable_chunk_handler(|chunk_type: u8, data: &[u8]| {
+///     println!("chunk {:#x}: {} bytes", chunk_type, data.len());
+///     Ok(())
+/// });
+/// ```
+pub trait SkippableChunkHandler {
+    /// Called with the type and contents of each skippable chunk, in the
+    /// order they appear in the stream.  Returning an error stops
+    /// decoding, and the error is returned to the reader.
+    fn handle(&mut self, chunk_type: u8, data: &[u8]) -> io::Result<()>;
+}
+
+impl<F> SkippableChunkHandler for F
+    where F: FnMut(u8, &[u8]) -> io::Result<()>
+{
+    fn handle(&mut self, chunk_type: u8, data: &[u8]) -> io::Result<()> {
+        self(chunk_type, data)
+    }
+}
+
+/// The smallest input buffer we'll use.  Streams made up of small chunks
+/// never need more than this.
+const MIN_INPUT_BUFFER: usize = 16 * 1024;
+
+/// The largest input buffer we'll grow to in the normal course of events.
+/// Individual chunks larger than this will still be buffered.
+const MAX_INPUT_BUFFER: usize = 1024 * 1024;
+
+/// How many chunks we try to keep in the input buffer at once.
+const CHUNKS_PER_BUFFER: usize = 8;
+
+/// How many smaller chunks we need to see before we forget about an
+/// unusually large one and allow the input buffer to shrink.
+const CHUNKS_BEFORE_SHRINK: usize = 32;
+
+/// Chooses an input buffer size based on the chunk sizes we've seen
+/// recently.  We start small, grow towards `MAX_INPUT_BUFFER` for streams
+/// with large chunks, and shrink again once an outlier has passed.
 #[derive(Debug)]
-struct Chunk<'a> {
-    chunk_type: u8,
-    data: &'a [u8]
+struct InputSizer {
+    /// The smallest buffer we'll use.  This is `MIN_INPUT_BUFFER` unless
+    /// configured otherwise.
+    min_capacity: usize,
+    /// The largest buffer we'll normally use.  This is `MAX_INPUT_BUFFER`
+    /// unless configured otherwise or we're allowing oversized chunks.
+    max_capacity: usize,
+    /// The largest chunk (including its header) we've seen recently.
+    largest_recent_chunk: usize,
+    /// The largest chunk we've seen since `largest_recent_chunk`.
+    largest_since_peak: usize,
+    /// The number of chunks we've seen since `largest_recent_chunk`.
+    chunks_since_peak: usize
 }
 
-impl<'a> Chunk<'a> {
-    fn crc(&self) -> io::Result<u32> {
-        if self.data.len() < CRC_SIZE {
-            Err(io::Error::new(io::ErrorKind::Other, "Snappy CRC truncated"))
+impl InputSizer {
+    fn new() -> InputSizer {
+        InputSizer{
+            min_capacity: MIN_INPUT_BUFFER,
+            max_capacity: MAX_INPUT_BUFFER,
+            largest_recent_chunk: 0,
+            largest_since_peak: 0,
+            chunks_since_peak: 0
+        }
+    }
+
+    /// Record the size of a chunk we've just read.
+    fn observe(&mut self, chunk_size: usize) {
+        if chunk_size >= self.largest_recent_chunk {
+            self.largest_recent_chunk = chunk_size;
+            self.largest_since_peak = 0;
+            self.chunks_since_peak = 0;
         } else {
-            Ok((self.data[0] as u32) |
-               (self.data[1] as u32) << 8 |
-               (self.data[2] as u32) << 16 |
-               (self.data[3] as u32) << 24)
+            self.largest_since_peak = max(self.largest_since_peak, chunk_size);
+            self.chunks_since_peak += 1;
+            if self.chunks_since_peak >= CHUNKS_BEFORE_SHRINK {
+                self.largest_recent_chunk = self.largest_since_peak;
+                self.largest_since_peak = 0;
+                self.chunks_since_peak = 0;
+            }
         }
     }
-}
 
-fn check_crc(expected: u32, data: &[u8]) -> io::Result<()> {
-    let actual = masked_crc(data);
-    if expected == actual {
-        Ok(())
-    } else {
-        Err(io::Error::new(io::ErrorKind::Other,
-                           format!("Invalid Snappy CRC (expected {:x}, got {:x})",
-                                   expected, actual)))
+    /// How large should our input buffer be?
+    fn target_capacity(&self) -> usize {
+        let wanted = (self.largest_recent_chunk * CHUNKS_PER_BUFFER)
+            .next_power_of_two();
+        min(max(wanted, self.min_capacity), self.max_capacity)
+    }
+
+    /// Resize `input` if it has drifted too far from our target.  We only
+    /// shrink when we're more than twice as large as we need to be, so
+    /// that we don't reallocate constantly, or when a single oversized
+    /// chunk forced us to grow beyond `max_capacity`.
+    fn adjust(&self, input: &mut Buffer) {
+        let target = self.target_capacity();
+        let capacity = input.capacity();
+        if target > capacity || capacity > 2 * target ||
+            capacity > self.max_capacity
+        {
+            // Growing is only an optimization, so if our memory budget
+            // won't allow it, just keep our current buffer.
+            let _ = input.set_capacity(target);
+        }
     }
 }
 
@@ -54,70 +223,73 @@ fn check_crc(expected: u32, data: &[u8]) -> io::Result<()> {
 // done.  But if we render `SnappyFramedDecoder` unavailable, we can't
 // write to our output buffer.  So it's better to keep this separate.
 impl Buffer {
-    /// Make sure we have at least the specified number of bytes buffered.
-    fn ensure_buffered<R: Read>(&mut self, bytes: usize, source: &mut R) ->
-        io::Result<Option<&[u8]>>
+    /// Make sure we have at least the specified number of bytes buffered,
+    /// without consuming them.  If `read_ahead` is false, we never read
+    /// more than we need.  Returns `false` if the stream ends first.  If
+    /// `source` fails, we keep everything we've read so far, so that a
+    /// non-blocking source which returns `WouldBlock` can be retried.
+    fn fill_to<R: Read>(&mut self, bytes: usize, read_ahead: bool,
+                        source: &mut R) -> io::Result<bool>
     {
-        // If we don't have enough data buffered, go get more.
-        if self.buffered() < bytes {
-            // If our input buffer is too small to hold a chunk, resize it.
-            // This should never fire for reasonable input files, so we're not
-            // concerned about speed.
-            let capacity = self.capacity();
-            if bytes > capacity {
-                warn!("Snappy chunk of {} bytes required growing buffer", bytes);
-                self.add_capacity(bytes - capacity);
-            }
+        // If we have enough data buffered, we're done.
+        if self.buffered() >= bytes { return Ok(true); }
 
-            // Move any partial data to the start of the buffer.
-            self.move_data_to_start();
-
-            // Try to fill up our buffer.
-            loop {
-                let bytes_read = {
-                    let space = self.space_to_fill();
-                    if space.len() == 0 { break; /* Full. */ }
-                    try!(source.read(space))
-                };
-                self.added(bytes_read);
-                if bytes_read == 0 { break; /* No more, at least for now. */ }
-            }
+        // If our input buffer is too small to hold a chunk, resize it.
+        // This should never fire for reasonable input files, so we're not
+        // concerned about speed.
+        let capacity = self.capacity();
+        if bytes > capacity {
+            warn!("Snappy chunk of {} bytes required growing buffer", bytes);
+            try!(self.add_capacity(bytes - capacity));
+        }
 
-            // Decide what to do if we still don't have enough data.
-            if self.buffered() == 0 {
-                // No data, so we're presumably at the end of the file.
-                // Return nothing.
-                return Ok(None);
-            } else if self.buffered() < bytes {
-                // Partial data, so fail with an error.
-                return Err(io::Error::new(io::ErrorKind::Other,
-                                          "Incomplete Snappy chunk"));
+        // Move any partial data to the start of the buffer.
+        self.move_data_to_start();
+
+        // Try to fill up our buffer.
+        loop {
+            let missing = bytes.saturating_sub(self.buffered());
+            if !read_ahead && missing == 0 { break; /* Enough. */ }
+            let result = {
+                let space = self.space_to_fill();
+                if space.len() == 0 { break; /* Full. */ }
+                let wanted = if read_ahead { space.len() } else { missing };
+                source.read(&mut space[..wanted])
+            };
+            match result {
+                Ok(0) => break, /* No more, at least for now. */
+                Ok(bytes_read) => self.added(bytes_read),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // We were only reading ahead, so we can stop here.
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock &&
+                    missing == 0 => break,
+                Err(err) => return Err(err)
             }
         }
-
-        // Actually return our bytes.
-        Ok(Some(self.consume(bytes)))
+        Ok(self.buffered() >= bytes)
     }
 
-    /// Read in the next input chunk.
-    fn next_chunk<R: Read>(&mut self, source: &mut R) ->
-        io::Result<Option<Chunk>>
+    /// Read in the next input chunk.  Chunks which `state` would reject
+    /// from their header alone are rejected before we buffer them.
+    /// Nothing is consumed until we have the whole chunk, so if reading
+    /// fails, we can try again later.
+    fn next_chunk<R: Read>(&mut self, read_ahead: bool, state: &ChunkDecoder,
+                           source: &mut R) -> io::Result<Option<Chunk>>
     {
-        let (chunk_type, chunk_len) = {
-            match try!(self.ensure_buffered(HEADER_SIZE, source)) {
-                None => return Ok(None),
-                Some(chunk_header) => {
-                    (chunk_header[0],
-                     ((chunk_header[3] as usize) << 16 |
-                      (chunk_header[2] as usize) << 8 |
-                      (chunk_header[1] as usize)))
-                }
-            }
-        };
-        let data = try!(self.ensure_buffered(chunk_len, source))
-            .expect("Snappy chunk header with missing data");
-        Ok(Some(Chunk{chunk_type: chunk_type, data: data}))
-    }    
+        if !try!(self.fill_to(HEADER_SIZE, read_ahead, source)) {
+            // If we have no data, we're presumably at the end of the file.
+            // But partial data means the stream was cut short.
+            if self.empty() { return Ok(None); }
+            return Err(SnappyFramedError::Truncated.into());
+        }
+        let (chunk_type, chunk_len) = parse_header(self.data());
+        try!(state.check_header(chunk_type, chunk_len));
+        if !try!(self.fill_to(HEADER_SIZE + chunk_len, read_ahead, source)) {
+            return Err(SnappyFramedError::Truncated.into());
+        }
+        self.consume(HEADER_SIZE);
+        Ok(Some(Chunk{chunk_type: chunk_type, data: self.consume(chunk_len)}))
+    }
 }
 
 /// Decode a stream containing Snappy-compressed frames.
@@ -141,149 +313,1975 @@ impl Buffer {
 pub struct SnappyFramedDecoder<R: Read> {
     source: R,
     input: Buffer,
+    input_sizer: InputSizer,
     output: Buffer,
-    mode: CrcMode
+    /// Interprets each chunk, and holds most of our options.
+    state: ChunkDecoder,
+    /// Time spent on I/O.  `state` records the rest.
+    #[cfg(feature = "timing")]
+    timings: Timings,
+    low_memory: bool,
+    split_members: bool,
+    /// Total bytes of data in all chunks we've read.
+    output_bytes: u64,
+    /// The data chunks we've seen since we were first asked to seek.
+    seen_chunks: Option<ChunkIndex>,
+    /// Have we stopped at a stream identifier, waiting for `next_member`?
+    at_member_boundary: bool,
+    skippable_handler: Option<Box<dyn SkippableChunkHandler + Send + Sync>>,
+    /// Is part of a chunk sitting in `input`, because our source wasn't
+    /// ready to give us the rest?
+    partial_input: bool
+}
+
+impl SnappyFramedDecoder<File> {
+    /// Open the file at `path` for decoding, verifying CRCs.  We already
+    /// buffer our input, so there's no need to wrap the file in a
+    /// `BufReader`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = try!(File::open(path));
+        Ok(SnappyFramedDecoder::new(file, CrcMode::Verify))
+    }
+
+    /// Configure a new decoder.  The builder can create decoders for any
+    /// kind of reader; it's only defined here so that the compiler doesn't
+    /// need to know the reader's type yet.
+    ///
+    /// ```
+    /// use std::io::Read;
+    /// use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+    ///
+    /// let compressed: &[u8] = &[0xff, 0x06, 0x00, 0x00, 0x73, 0x4e, 0x61,
+    ///                           0x50, 0x70, 0x59];
+    /// let mut decoder = SnappyFramedDecoder::builder()
+    ///     .with_crc_mode(CrcMode::Auto)
+    ///     .with_require_stream_identifier(true)
+    ///     .with_max_input_bytes(Some(1024 * 1024))
+    ///     .build(compressed);
+    /// let mut output = vec!();
+    /// decoder.read_to_end(&mut output).unwrap();
+    /// assert!(output.is_empty());
+    /// ```
+    pub fn builder() -> DecoderBuilder {
+        DecoderBuilder::default()
+    }
+}
+
+/// Options for creating a `SnappyFramedDecoder`.  See
+/// `SnappyFramedDecoder::builder`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DecoderBuilder {
+    mode: CrcMode,
+    low_memory: bool,
+    require_identifier: bool,
+    chunk_size_policy: ChunkSizePolicy,
+    min_input_buffer: usize,
+    max_input_buffer: Option<usize>,
+    max_input_bytes: Option<u64>,
+    max_skippable_chunks: Option<usize>,
+    max_skippable_bytes: Option<u64>
+}
+
+impl DecoderBuilder {
+    /// How to handle CRCs.  Defaults to `CrcMode::Verify`.
+    pub fn with_crc_mode(self, mode: CrcMode) -> Self {
+        DecoderBuilder{mode: mode, ..self}
+    }
+
+    /// Use a single buffer and never read ahead.  See
+    /// `SnappyFramedDecoder::new_low_memory`.  Defaults to `false`.
+    pub fn with_low_memory(self, low_memory: bool) -> Self {
+        DecoderBuilder{low_memory: low_memory, ..self}
+    }
+
+    /// Require the stream to begin with a stream identifier.  See
+    /// `SnappyFramedDecoder::set_require_stream_identifier`.  Defaults to
+    /// `false`.
+    pub fn with_require_stream_identifier(self, require: bool) -> Self {
+        DecoderBuilder{require_identifier: require, ..self}
+    }
+
+    /// Accept oversized chunks.  See
+    /// `SnappyFramedDecoder::set_allow_oversized_chunks`.  Defaults to
+    /// `false`.
+    pub fn with_allow_oversized_chunks(self, allow: bool) -> Self {
+        let policy = if allow {
+            ChunkSizePolicy::Lenient(MAX_OVERSIZED_CHUNK)
+        } else {
+            ChunkSizePolicy::Strict
+        };
+        self.with_chunk_size_policy(policy)
+    }
+
+    /// How large a chunk's data may be.  See
+    /// `SnappyFramedDecoder::set_chunk_size_policy`.  Defaults to
+    /// `ChunkSizePolicy::Strict`.
+    pub fn with_chunk_size_policy(self, policy: ChunkSizePolicy) -> Self {
+        DecoderBuilder{chunk_size_policy: policy, ..self}
+    }
+
+    /// Never shrink our input buffer below `bytes`.  Defaults to 16 KiB.
+    /// If this is larger than the maximum input buffer, the maximum is
+    /// used instead.
+    pub fn with_min_input_buffer(self, bytes: usize) -> Self {
+        DecoderBuilder{min_input_buffer: bytes, ..self}
+    }
+
+    /// Don't grow our input buffer beyond `bytes` to read ahead, although
+    /// we'll still grow it as needed to hold a single chunk.  Defaults to
+    /// 1 MiB, or more if oversized chunks are allowed.
+    pub fn with_max_input_buffer(self, bytes: usize) -> Self {
+        DecoderBuilder{max_input_buffer: Some(bytes), ..self}
+    }
+
+    /// Limit the total size of the compressed input.  See
+    /// `SnappyFramedDecoder::set_max_input_bytes`.
+    pub fn with_max_input_bytes(self, limit: Option<u64>) -> Self {
+        DecoderBuilder{max_input_bytes: limit, ..self}
+    }
+
+    /// Limit consecutive chunks without data.  See
+    /// `SnappyFramedDecoder::set_max_skippable_chunks`.
+    pub fn with_max_skippable_chunks(self, limit: Option<usize>) -> Self {
+        DecoderBuilder{max_skippable_chunks: limit, ..self}
+    }
+
+    /// Limit the total size of chunks without data.  See
+    /// `SnappyFramedDecoder::set_max_skippable_bytes`.
+    pub fn with_max_skippable_bytes(self, limit: Option<u64>) -> Self {
+        DecoderBuilder{max_skippable_bytes: limit, ..self}
+    }
+
+    /// Create a decoder reading from `source`.
+    pub fn build<R: Read>(self, source: R) -> SnappyFramedDecoder<R> {
+        let mut decoder = SnappyFramedDecoder::with_buffers(
+            source, self.mode, self.low_memory);
+        decoder.set_chunk_size_policy(self.chunk_size_policy);
+        if let Some(max_input_buffer) = self.max_input_buffer {
+            decoder.input_sizer.max_capacity = max_input_buffer;
+        }
+        let min_input_buffer =
+            min(self.min_input_buffer, decoder.input_sizer.max_capacity);
+        decoder.input_sizer.min_capacity = min_input_buffer;
+        if !self.low_memory {
+            decoder.input = Buffer::new(min_input_buffer);
+        }
+        decoder.set_require_stream_identifier(self.require_identifier);
+        decoder.set_max_input_bytes(self.max_input_bytes);
+        decoder.set_max_skippable_chunks(self.max_skippable_chunks);
+        decoder.set_max_skippable_bytes(self.max_skippable_bytes);
+        decoder
+    }
 }
 
+impl Default for DecoderBuilder {
+    fn default() -> DecoderBuilder {
+        DecoderBuilder{
+            mode: CrcMode::Verify,
+            low_memory: false,
+            require_identifier: false,
+            chunk_size_policy: ChunkSizePolicy::Strict,
+            min_input_buffer: MIN_INPUT_BUFFER,
+            max_input_buffer: None,
+            max_input_bytes: None,
+            max_skippable_chunks: None,
+            max_skippable_bytes: None
+        }
+    }
+}
+
+/// An alias for `SnappyFramedDecoder`, matching the naming used by the
+/// `snap` crate.
+pub type FrameDecoder<R> = SnappyFramedDecoder<R>;
+
 impl<R: Read> SnappyFramedDecoder<R> {
     /// Create a new decoder wrapping the specified `source`, and using the
     /// CRC verification options indicated by `mode`.
     pub fn new(source: R, mode: CrcMode) -> Self {
+        SnappyFramedDecoder::with_buffers(source, mode, false)
+    }
+
+    /// Create a new decoder which uses a single buffer for both input and
+    /// output, and which never reads ahead of the current chunk.  This
+    /// needs roughly one chunk's worth of memory per stream, but makes
+    /// many more small `read` calls on `source`, so you may want to wrap
+    /// it in a `BufReader` if it's backed by a file or socket.
+    pub fn new_low_memory(source: R, mode: CrcMode) -> Self {
+        SnappyFramedDecoder::with_buffers(source, mode, true)
+    }
+
+    /// Create a new decoder wrapping the specified `source`, and using the
+    /// decoder settings in `config`.
+    pub fn with_config(source: R, mode: CrcMode, config: &Config) -> Self {
+        SnappyFramedDecoder::with_buffers(source, mode, config.low_memory)
+    }
+
+    fn with_buffers(source: R, mode: CrcMode, low_memory: bool) -> Self {
+        let output_size = if low_memory { 0 } else { MAX_UNCOMPRESSED_CHUNK };
         SnappyFramedDecoder{
             source: source,
-            input: Buffer::new(1024*1024),
-            output: Buffer::new(MAX_UNCOMPRESSED_CHUNK),
-            mode: mode
+            input: Buffer::new(MIN_INPUT_BUFFER),
+            input_sizer: InputSizer::new(),
+            output: Buffer::new(output_size),
+            state: ChunkDecoder::new(mode),
+            #[cfg(feature = "timing")]
+            timings: Timings::default(),
+            low_memory: low_memory,
+            split_members: false,
+            output_bytes: 0,
+            seen_chunks: None,
+            at_member_boundary: false,
+            skippable_handler: None,
+            partial_input: false
         }
     }
-}
 
-impl<R: Read> Read for SnappyFramedDecoder<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.output.empty() {
-            loop {
-                match try!(self.input.next_chunk(&mut self.source)) {
-                    None => return Ok(0),
-                    Some(chunk) => {
-                        //println!("chunk: {:?}", chunk);
-                        match chunk.chunk_type {
-                            // Compressed data.
-                            0x00 => {
-                                // TODO: Output size check.
-                                // TODO: Malformed data check.
-                                let crc = try!(chunk.crc());
-                                let compressed = &chunk.data[CRC_SIZE..];
-                                let data = snappy::uncompress(compressed)
-                                    .expect("Snappy decompression failure");
-                                if self.mode == CrcMode::Verify {
-                                    try!(check_crc(crc, &data));
-                                }
-                                self.output.set_data(&data);
-                                break;
-                            }
-
-                            // Uncompressed data.
-                            0x01 => {
-                                // TODO: Output size check.
-                                // TODO: Malformed data check.
-                                let crc = try!(chunk.crc());
-                                let data = &chunk.data[CRC_SIZE..];
-                                if self.mode == CrcMode::Verify {
-                                    try!(check_crc(crc, &data));
-                                }
-                                self.output.set_data(&data);
-                                break;
-                            }
-
-                            // Reserved unskippable chunks.
-                            0x02...0x7F => {}
-                            // Reserved skippable chunks.
-                            0x80...0xFD => {}
-                            // Padding.
-                            0xFE => {}
-                            // Stream identifier.  
-                            0xFF => {}
-                            _ => unreachable!()
-                        }
-                    }
-                }
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// Get a mutable reference to our source.  Reading from it directly
+    /// will corrupt our stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// Unwrap this decoder, returning our source.  Any data which we've
+    /// read from the source but not yet returned is lost.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Accept chunks containing up to `MAX_OVERSIZED_CHUNK` bytes of
+    /// data, as written by an encoder using
+    /// `Compression::with_oversized_chunk_size`.  These are not allowed by
+    /// the spec, so by default, we reject chunks larger than
+    /// `MAX_UNCOMPRESSED_CHUNK`.
+    pub fn set_allow_oversized_chunks(&mut self, allow: bool) {
+        self.set_chunk_size_policy(if allow {
+            ChunkSizePolicy::Lenient(MAX_OVERSIZED_CHUNK)
+        } else {
+            ChunkSizePolicy::Strict
+        });
+    }
+
+    /// Choose how large a chunk's data may be.  Chunks which are too
+    /// large are rejected with `SnappyFramedError::ChunkTooLarge` before
+    /// we buffer them.
+    pub fn set_chunk_size_policy(&mut self, policy: ChunkSizePolicy) {
+        self.state.set_chunk_size_policy(policy);
+        self.input_sizer.max_capacity = match policy {
+            ChunkSizePolicy::Strict => MAX_INPUT_BUFFER,
+            ChunkSizePolicy::Lenient(_) => {
+                let max_chunk_size = self.state.max_chunk_size();
+                max(MAX_INPUT_BUFFER,
+                    2 * max_frame_size_for_payload(max_chunk_size))
             }
+        };
+    }
+
+    /// Fail if we see more than `limit` consecutive chunks which contain
+    /// no data (padding, skippable chunks, or stream identifiers).  This
+    /// prevents a malicious stream from keeping us busy indefinitely
+    /// without ever returning any data.  Defaults to `None`.
+    pub fn set_max_skippable_chunks(&mut self, limit: Option<usize>) {
+        self.state.set_max_skippable_chunks(limit);
+    }
+
+    /// Fail if the total size of all chunks without data (including their
+    /// headers) exceeds `limit` bytes over the life of the stream.
+    /// Defaults to `None`.
+    pub fn set_max_skippable_bytes(&mut self, limit: Option<u64>) {
+        self.state.set_max_skippable_bytes(limit);
+    }
+
+    /// Fail if the chunks in the stream, including their headers, add up
+    /// to more than `limit` bytes of compressed input.  Unlike limits on
+    /// output, this bounds the work done on streams which contain mostly
+    /// padding or skippable chunks.  Defaults to `None`.
+    pub fn set_max_input_bytes(&mut self, limit: Option<u64>) {
+        self.state.set_max_input_bytes(limit);
+    }
+
+    /// Check the checksums written by an encoder with
+    /// `set_payload_checksums` enabled, before decompressing each data
+    /// chunk.  Data chunks without a checksum are still accepted.
+    /// Defaults to `false`.
+    pub fn set_verify_payload_checksums(&mut self, verify: bool) {
+        self.state.set_verify_payload_checksums(verify);
+    }
+
+    /// Fail unless the stream begins with a valid stream identifier, and
+    /// check any later stream identifiers too.  By default, we're lenient
+    /// and accept streams without one, such as those produced by
+    /// concatenating chunks from different sources.
+    ///
+    /// The first chunk header is checked before we read anything else, so
+    /// data which isn't Snappy framed at all is rejected without buffering
+    /// any of it.
+    pub fn set_require_stream_identifier(&mut self, require: bool) {
+        self.state.set_require_stream_identifier(require);
+    }
+
+    /// Check the sequence numbers written by an encoder with
+    /// `set_sequence_numbers` enabled, and fail if any frames are
+    /// missing or out of order.  The first sequence number we see may be
+    /// non-zero, so that we can join a stream part way through.  Defaults
+    /// to `false`.
+    pub fn set_check_sequence_numbers(&mut self, check: bool) {
+        self.state.set_check_sequence_numbers(check);
+    }
+
+    /// Fail if we find a reserved unskippable chunk (types `0x02` through
+    /// `0x7F`), as the spec requires.  By default, we skip them.  Defaults
+    /// to `false`.
+    pub fn set_reject_reserved_chunks(&mut self, reject: bool) {
+        self.state.set_reject_reserved_chunks(reject);
+    }
+
+    /// Fail with `SnappyFramedError::TrailingJunk` if the stream ends with
+    /// bytes which don't form a complete chunk, or if any chunk after the
+    /// last data chunk is one we don't recognize.  We recognize padding,
+    /// stream identifiers and our own skippable chunk types.  Other chunks
+    /// are valid mid-stream, but at the end, they usually mean that junk
+    /// was appended to the stream, so streams which legitimately end with
+    /// them will be rejected.  The junk can be retrieved using
+    /// `trailing_junk`.  By default, partial chunks are reported as
+    /// `SnappyFramedError::Truncated`, and unrecognized chunks are skipped.
+    /// Defaults to `false`.
+    pub fn set_detect_trailing_junk(&mut self, detect: bool) {
+        self.state.set_detect_trailing_junk(detect);
+    }
+
+    /// The bytes reported as `SnappyFramedError::TrailingJunk`, so that
+    /// they can be logged or salvaged.  Before the end of the stream, this
+    /// holds any chunks since the last data chunk which might turn out to
+    /// be junk.  Always empty unless `set_detect_trailing_junk` is
+    /// enabled.
+    pub fn trailing_junk(&self) -> &[u8] {
+        self.state.trailing_junk()
+    }
+
+    /// Treat each stream identifier after the start of the stream as the
+    /// beginning of a new member, as found in appended files or the output
+    /// of restarted producers.  When we reach one, we report the end of
+    /// the stream, so the application can reset any per-member state, and
+    /// then call `next_member` to keep reading.  By default, we decode
+    /// every member as part of a single stream.
+    ///
+    /// ```
+    /// use std::io::{Read, Write};
+    /// use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+    /// use snappy_framed::write::SnappyFramedEncoder;
+    ///
+    /// let mut compressed = vec!();
+    /// for member in &[b"first", b"other"] {
+    ///     let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    ///     encoder.write_all(*member).unwrap();
+    ///     compressed.extend(encoder.finish().unwrap());
+    /// }
+    ///
+    /// let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+    ///                                            CrcMode::Verify);
+    /// decoder.set_split_members(true);
+    /// let mut members = vec!();
+    /// loop {
+    ///     let mut output = vec!();
+    ///     decoder.read_to_end(&mut output).unwrap();
+    ///     members.push(output);
+    ///     if !decoder.next_member() { break; }
+    /// }
+    /// assert_eq!(vec!(b"first".to_vec(), b"other".to_vec()), members);
+    /// ```
+    pub fn set_split_members(&mut self, split: bool) {
+        self.split_members = split;
+    }
+
+    /// If we stopped at the start of a new member (see
+    /// `set_split_members`), forget any sequence numbers and content size
+    /// from the previous member, continue reading, and return `true`.
+    /// Returns `false` if we're not at the start of a member.
+    pub fn next_member(&mut self) -> bool {
+        if !self.at_member_boundary { return false; }
+        self.at_member_boundary = false;
+        self.state.start_member();
+        true
+    }
+
+    /// Pass the type and contents of every skippable chunk (types `0x80`
+    /// through `0xFD`) to `handler`, including those we also interpret
+    /// ourselves, such as sequence numbers.  Padding and stream
+    /// identifiers aren't passed on.  By default, skippable chunks are
+    /// discarded.
+    pub fn set_skippable_chunk_handler<H>(&mut self, handler: H)
+        where H: SkippableChunkHandler + Send + Sync + 'static
+    {
+        self.skippable_handler = Some(Box::new(handler));
+    }
+
+    /// Decompress data chunks using `codec`, instead of the Snappy
+    /// implementation we were built with.  See `raw` for details.
+    pub fn set_raw_codec<C>(&mut self, codec: C)
+        where C: RawCodec + Send + Sync + 'static
+    {
+        self.state.set_raw_codec(codec);
+    }
+
+    /// Record that the chunk we just read, of `chunk_len` bytes, contained
+    /// `len` bytes of data.  Once we've been asked to seek, we remember
+    /// where each new data chunk starts.
+    fn found_data(&mut self, chunk_len: usize, len: usize) {
+        let chunk_bytes = (HEADER_SIZE + chunk_len) as u64;
+        let entry = IndexEntry{
+            compressed_offset: self.state.input_bytes() - chunk_bytes,
+            uncompressed_offset: self.output_bytes,
+            uncompressed_len: len as u64
+        };
+        self.output_bytes += len as u64;
+        if let Some(ref mut index) = self.seen_chunks {
+            let seen = entry.uncompressed_offset < index.uncompressed_len();
+            if len > 0 && !seen { index.entries.push(entry); }
         }
+    }
 
-        let to_copy = min(self.output.buffered(), buf.len());
-        self.output.copy_out_and_consume(to_copy, buf);
-        Ok(to_copy)
+    /// Release any buffer space beyond what we need for the data we've
+    /// already buffered, and forget about any large chunks we've seen.
+    /// Our buffers will grow again as needed.
+    pub fn shrink_to_fit(&mut self) {
+        self.input_sizer = InputSizer{
+            min_capacity: self.input_sizer.min_capacity,
+            max_capacity: self.input_sizer.max_capacity,
+            ..InputSizer::new()
+        };
+        // Shrinking never allocates, so it can't exceed our budget.
+        let _ = self.input.set_capacity(self.input_sizer.min_capacity);
     }
-}
 
-#[cfg(test)]
-fn large_compressed_data(repeats: usize) -> io::Result<Vec<u8>> {
-    use std::io::Write;
+    /// In low-memory mode, return our shared buffer to its normal size
+    /// once we've finished with an oversized chunk.
+    fn shrink_low_memory_buffer(&mut self) {
+        if self.input.capacity() > max_data_chunk() {
+            let _ = self.input.set_capacity(max_data_chunk());
+        }
+    }
 
-    use write::SnappyFramedEncoder;
-    use test_helpers::*;
+    /// The buffer containing our decompressed data.  In low-memory mode,
+    /// we never read past the end of the current chunk, so we can reuse
+    /// our input buffer.
+    fn output_buffer(&mut self) -> &mut Buffer {
+        if self.low_memory { &mut self.input } else { &mut self.output }
+    }
 
-    // Build a large vector containing repeated text data.
-    let hunk = try!(read_file("data/arbres.txt"));
-    let data = repeat_data(&hunk, repeats);
+    /// The number of decompressed bytes waiting to be read.  In low-memory
+    /// mode, our output buffer may instead hold part of a chunk which we
+    /// couldn't finish reading, and that doesn't count.
+    fn output_len(&mut self) -> usize {
+        if self.partial_input { 0 } else { self.output_buffer().buffered() }
+    }
 
-    // Compress it.
-    let mut compressed = vec!();
+    /// Time spent in each stage of decoding so far.
+    #[cfg(feature = "timing")]
+    pub fn timings(&self) -> Timings {
+        Timings{io: self.timings.io, ..self.state.timings()}
+    }
+
+    /// The number of bytes currently allocated for our buffers.
+    pub fn memory_usage(&self) -> usize {
+        self.input.capacity() + self.output.capacity()
+    }
+
+    /// Charge our buffers against `budget`, which may be shared with
+    /// other decoders.  Fails if the budget can't cover the buffers we've
+    /// already allocated.  Reads will fail if we need to grow our buffers
+    /// beyond the budget.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) ->
+        io::Result<()>
     {
-        let mut compressor = try!(SnappyFramedEncoder::new(&mut compressed));
-        let written = try!(compressor.write(&data));
-        assert_eq!(data.len(), written);
-        try!(compressor.flush());
+        // Reserve space for both buffers at once, so that if we fail,
+        // neither of them has been changed.
+        try!(budget.try_reserve(self.memory_usage()));
+        self.input.set_budget(budget.clone());
+        self.output.set_budget(budget);
+        Ok(())
     }
-    Ok(compressed)
-}
 
-#[test]
-fn decode_example_stream() {
-    use std::fs::File;
-    use dribble::DribbleReader;
+    /// Store `data` as our decompressed output.
+    fn set_output(&mut self, data: &[u8]) -> io::Result<()> {
+        let output = self.output_buffer();
+        if data.len() > output.capacity() {
+            try!(output.set_capacity(data.len()));
+        }
+        output.set_data(data);
+        Ok(())
+    }
 
-    use test_helpers::*;
+    /// Decode up to `max_frames` data chunks, appending the contents of
+    /// each one to `frames`, and return the number of frames decoded.
+    /// This returns fewer than `max_frames` frames only at the end of the
+    /// stream.  If earlier calls to `read` consumed part of a chunk, the
+    /// rest of that chunk is returned as the first frame.
+    pub fn decode_frames_into(&mut self, frames: &mut Vec<Vec<u8>>,
+                              max_frames: usize) -> io::Result<usize> {
+        let mut decoded = 0;
+        while decoded < max_frames {
+            if self.output_len() == 0 && !try!(self.fill_output()) {
+                break;
+            }
+            let output = self.output_buffer();
+            let len = output.buffered();
+            let mut frame = vec![0; len];
+            output.copy_out_and_consume(len, &mut frame);
+            frames.push(frame);
+            decoded += 1;
+        }
+        Ok(decoded)
+    }
 
-    let mut compressed = File::open("data/arbres.txt.sz").unwrap();
-    let mut dribble = DribbleReader::new(&mut compressed);
-    let mut decompressor = SnappyFramedDecoder::new(&mut dribble,
-                                                    CrcMode::Verify);
-    let mut decompressed = vec!();
-    decompressor.read_to_end(&mut decompressed).unwrap();
+    /// The uncompressed length of the stream, if the encoder declared it
+    /// using `write_content_size_hint`.  If we haven't seen it yet, this
+    /// reads ahead to the first chunk containing data, which will be
+    /// returned by the next call to `read`.  The hint comes from the
+    /// stream, so don't trust it further than you trust the stream.
+    pub fn content_size_hint(&mut self) -> io::Result<Option<u64>> {
+        if self.state.content_size().is_none() && self.output_len() == 0 {
+            try!(self.fill_output());
+        }
+        Ok(self.state.content_size())
+    }
 
-    let expected = read_file("data/arbres.txt").unwrap();
-    assert_eq!(expected, decompressed);
+    /// Iterate over the lines of decompressed text, without line endings.
+    /// This is like `BufRead::lines`, except that a line longer than
+    /// `max_line_len` bytes is reported as an error instead of being
+    /// read into memory, so that a malicious stream can't exhaust our
+    /// memory by never ending a line.  Iteration stops after any error.
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
+    /// use snappy_framed::write::SnappyFramedEncoder;
+    ///
+    /// let mut compressed = vec!();
+    /// {
+    ///     let mut encoder =
+    ///         SnappyFramedEncoder::new(&mut compressed).unwrap();
+    ///     encoder.write_all(b"one\ntwo\n").unwrap();
+    ///     encoder.flush().unwrap();
+    /// }
+    ///
+    /// let decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+    ///                                        CrcMode::Verify);
+    /// for line in decoder.limited_lines(1024) {
+    ///     println!("{}", line.unwrap());
+    /// }
+    /// ```
+    pub fn limited_lines(self, max_line_len: usize) -> LimitedLines<R> {
+        LimitedLines{
+            decoder: self,
+            max_line_len: max_line_len,
+            done: false
+        }
+    }
+
+    /// Read chunks until we find one containing data, and store its
+    /// contents in our output buffer.  Returns `false` at the end of the
+    /// stream.
+    fn fill_output(&mut self) -> io::Result<bool> {
+        if self.at_member_boundary { return Ok(false); }
+        let read_ahead = !self.low_memory;
+        if !read_ahead { self.shrink_low_memory_buffer(); }
+        loop {
+            if read_ahead { self.input_sizer.adjust(&mut self.input); }
+            // If this fails, we may leave part of a chunk in `input`.
+            self.partial_input = true;
+            let next = timed!(self.timings.io,
+                              self.input.next_chunk(read_ahead, &self.state,
+                                                    &mut self.source));
+            let next = match next {
+                Ok(next) => next,
+                Err(err) => return Err(self.chunk_error(err))
+            };
+            self.partial_input = false;
+            let chunk = match next {
+                Some(chunk) => chunk,
+                None => {
+                    try!(self.state.end_of_stream());
+                    return Ok(false);
+                }
+            };
+            let chunk_len = chunk.data.len();
+            self.input_sizer.observe(HEADER_SIZE + chunk_len);
+            if let Some(ref mut handler) = self.skippable_handler {
+                if chunk.chunk_type >= 0x80 && chunk.chunk_type <= 0xFD {
+                    try!(handler.handle(chunk.chunk_type, chunk.data));
+                }
+            }
+            match try!(self.state.decode_chunk(&chunk)) {
+                ChunkContents::Decompressed(data) => {
+                    try!(self.set_output(&data));
+                    self.found_data(chunk_len, data.len());
+                    return Ok(true);
+                }
+                ChunkContents::Stored(data) => {
+                    let len = data.len();
+                    if self.low_memory {
+                        // Our data is still sitting in the input buffer,
+                        // so just reuse it.
+                        self.input.unconsume(len);
+                    } else {
+                        if len > self.output.capacity() {
+                            try!(self.output.set_capacity(len));
+                        }
+                        self.output.set_data(data);
+                    }
+                    self.found_data(chunk_len, len);
+                    return Ok(true);
+                }
+                ChunkContents::StreamIdentifier => {
+                    // Identifiers after the first chunk start new members.
+                    let chunk_bytes = (HEADER_SIZE + chunk_len) as u64;
+                    if self.split_members &&
+                        self.state.input_bytes() > chunk_bytes
+                    {
+                        self.at_member_boundary = true;
+                        return Ok(false);
+                    }
+                }
+                ChunkContents::Skipped(_) => {}
+            }
+        }
+    }
+
+    /// If the stream ended part way through a chunk, let `state` decide
+    /// how to report it.  Other errors are returned unchanged.
+    fn chunk_error(&mut self, err: io::Error) -> io::Error {
+        let truncated = err.get_ref().map_or(false, |inner| {
+            match inner.downcast_ref::<SnappyFramedError>() {
+                Some(&SnappyFramedError::Truncated) => true,
+                _ => false
+            }
+        });
+        if !truncated { return err; }
+        self.state.truncated(self.input.data()).into()
+    }
 }
 
-#[test]
-fn encode_and_decode_large_data() {
-    use std::io::Cursor;
+impl<R: Read> BufRead for SnappyFramedDecoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.output_len() == 0 {
+            try!(self.fill_output());
+        }
+        Ok(self.output_buffer().data())
+    }
 
-    use test_helpers::*;
+    fn consume(&mut self, amt: usize) {
+        self.output_buffer().consume(amt);
+    }
+}
 
-    // Build a multi-MB vector containing text data.
-    let hunk = read_file("data/arbres.txt").unwrap();
-    let input = repeat_data(&hunk, 1000);
-    let compressed = large_compressed_data(1000).unwrap();
+/// An iterator over lines of decompressed text, returned by
+/// `SnappyFramedDecoder::limited_lines`.
+pub struct LimitedLines<R: Read> {
+    decoder: SnappyFramedDecoder<R>,
+    max_line_len: usize,
+    done: bool
+}
 
-    // Decode it.
-    let mut cursor = Cursor::new(&compressed as &[u8]);
-    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
-                                                    CrcMode::Verify);
-    let mut decompressed = vec!();
-    decompressor.read_to_end(&mut decompressed).unwrap();
+impl<R: Read> LimitedLines<R> {
+    /// Read the next line, including any trailing `'\r'`.
+    fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = vec!();
+        loop {
+            let (found_newline, used) = {
+                let available = try!(self.decoder.fill_buf());
+                if available.is_empty() {
+                    return Ok(if line.is_empty() { None } else { Some(line) });
+                }
+                match available.iter().position(|&b| b == b'\n') {
+                    Some(pos) => {
+                        line.extend_from_slice(&available[..pos]);
+                        (true, pos + 1)
+                    }
+                    None => {
+                        line.extend_from_slice(available);
+                        (false, available.len())
+                    }
+                }
+            };
+            self.decoder.consume(used);
+            if line.len() > self.max_line_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Line too long in Snappy stream"));
+            }
+            if found_newline { return Ok(Some(line)); }
+        }
+    }
+}
 
-    // Compare it.
-    assert_eq!(input, decompressed);
+impl<R: Read> Iterator for LimitedLines<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        if self.done { return None; }
+        let result = self.next_line().and_then(|line| {
+            match line {
+                None => Ok(None),
+                Some(mut line) => {
+                    if line.last() == Some(&b'\r') { line.pop(); }
+                    String::from_utf8(line).map(Some).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData,
+                                       "Invalid UTF-8 in Snappy stream")
+                    })
+                }
+            }
+        });
+        match result {
+            Ok(Some(line)) => Some(Ok(line)),
+            Ok(None) => { self.done = true; None }
+            Err(err) => { self.done = true; Some(Err(err)) }
+        }
+    }
 }
 
-// Test for invalid inputs:
-//   - No identifier chunk.
-//   - Incomplete chunks: All positions return errors.
-//   - Reserved chunk types.
-//   - Bad CRC.
-//   - Overlong chunks (both compressed--two variants--and uncompressed).
+impl<R: Read> Read for SnappyFramedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output_len() == 0 && !try!(self.fill_output()) {
+            return Ok(0);
+        }
+
+        let output = self.output_buffer();
+        let to_copy = min(output.buffered(), buf.len());
+        output.copy_out_and_consume(to_copy, buf);
+        Ok(to_copy)
+    }
+}
+
+impl<R: Read + Seek> SnappyFramedDecoder<R> {
+    /// Our position in the uncompressed stream.
+    fn position(&mut self) -> u64 {
+        self.output_bytes - self.output_len() as u64
+    }
+
+    /// Discard everything we've buffered, and continue decoding from the
+    /// chunk at `compressed_offset`, whose data starts at
+    /// `uncompressed_offset`.
+    fn restart_at(&mut self, compressed_offset: u64,
+                  uncompressed_offset: u64) -> io::Result<()> {
+        try!(self.source.seek(SeekFrom::Start(compressed_offset)));
+        self.input.set_data(&[]);
+        self.output.set_data(&[]);
+        self.state.restart_at(compressed_offset);
+        self.output_bytes = uncompressed_offset;
+        self.at_member_boundary = false;
+        self.partial_input = false;
+        Ok(())
+    }
+}
+
+/// Seek within the uncompressed stream, which must start at the beginning
+/// of our source.  We remember where data chunks start once we're first
+/// asked to seek, so we can jump straight back to them.  Otherwise, we
+/// rewind to the start of the stream if necessary, and decompress and
+/// discard data until we reach the target.  Seeking past the end leaves
+/// us at the end, and `SeekFrom::End` isn't supported, because we don't
+/// know where the end is without reading the whole stream.
+impl<R: Read + Seek> Seek for SnappyFramedDecoder<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let current = self.position();
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) if offset >= 0 => {
+                current.checked_add(offset as u64)
+            }
+            SeekFrom::Current(offset) => {
+                current.checked_sub(offset.wrapping_neg() as u64)
+            }
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Snappy decoders can't seek relative to the end"));
+            }
+        };
+        let target = try!(target.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "invalid seek to a negative or overflowing position")
+        }));
+
+        // Jump to a chunk we've seen if it gets us closer, or rewind.
+        let index = self.seen_chunks.get_or_insert_with(ChunkIndex::new);
+        let known = index.find(target).map(|i| index.entries[i]);
+        match known {
+            Some(entry) if target < current ||
+                entry.uncompressed_offset > current => {
+                try!(self.restart_at(entry.compressed_offset,
+                                     entry.uncompressed_offset));
+            }
+            _ if target < current => try!(self.restart_at(0, 0)),
+            _ => {}
+        }
+
+        // Skip forward to the target.
+        let mut remaining = target - self.position();
+        while remaining > 0 {
+            let available = try!(self.fill_buf()).len() as u64;
+            if available == 0 { break; }
+            let skipped = min(available, remaining);
+            self.consume(skipped as usize);
+            remaining -= skipped;
+        }
+        Ok(self.position())
+    }
+}
+
+/// Compress data read from `source`, so that reading from the encoder
+/// returns a Snappy framed stream.  This is useful for passing compressed
+/// data to APIs which want a `Read`.
+///
+/// ```
+/// use std::io::Read;
+/// use snappy_framed::read::{CrcMode, SnappyFramedDecoder,
+///                           SnappyFramedEncoder};
+///
+/// let mut compressed = vec!();
+/// SnappyFramedEncoder::new(b"Hello!" as &[u8])
+///     .read_to_end(&mut compressed).unwrap();
+///
+/// let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+///                                            CrcMode::Verify);
+/// let mut output = vec!();
+/// decoder.read_to_end(&mut output).unwrap();
+/// assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+/// ```
+pub struct SnappyFramedEncoder<R: Read> {
+    source: R,
+    /// Encodes each chunk of input into a buffer, from which we copy it
+    /// out.
+    encoder: write::SnappyFramedEncoder<Vec<u8>>,
+    /// The uncompressed data for the next chunk.
+    input: Vec<u8>,
+    /// How much of the encoder's buffer has been returned.
+    pos: usize,
+    eof: bool
+}
+
+impl<R: Read> SnappyFramedEncoder<R> {
+    /// Create a new encoder reading from `source`.
+    pub fn new(source: R) -> Self {
+        SnappyFramedEncoder::with_compression(source, Compression::default())
+    }
+
+    /// Create a new encoder reading from `source`, and using the options
+    /// in `compression`.
+    pub fn with_compression(source: R, compression: Compression) -> Self {
+        let encoder =
+            write::SnappyFramedEncoder::with_compression(vec!(), compression)
+            .expect("writing to a Vec should never fail");
+        SnappyFramedEncoder{
+            source: source,
+            encoder: encoder,
+            input: vec![0; compression.chunk_size()],
+            pos: 0,
+            eof: false
+        }
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// Get a mutable reference to our source.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// Unwrap this encoder, returning our source.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Read a full chunk of input, if we can, and encode it.  Returns
+    /// `false` at the end of our input.
+    fn encode_chunk(&mut self) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < self.input.len() {
+            match self.source.read(&mut self.input[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err)
+            }
+        }
+        if filled == 0 { return Ok(false); }
+        self.encoder.get_mut().clear();
+        self.pos = 0;
+        try!(io::Write::write_all(&mut self.encoder, &self.input[..filled]));
+        try!(self.encoder.flush_buffer());
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for SnappyFramedEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.encoder.get_ref().len() {
+            if self.eof || !try!(self.encode_chunk()) {
+                self.eof = true;
+                return Ok(0);
+            }
+        }
+        Ok(copy_output(self.encoder.get_ref(), &mut self.pos, buf))
+    }
+}
+
+#[cfg(test)]
+fn large_compressed_data(repeats: usize) -> io::Result<Vec<u8>> {
+    use std::io::Write;
+
+    use write::SnappyFramedEncoder;
+    use test_helpers::*;
+
+    // Build a large vector containing repeated text data.
+    let hunk = try!(read_file("data/arbres.txt"));
+    let data = repeat_data(&hunk, repeats);
+
+    // Compress it.
+    let mut compressed = vec!();
+    {
+        let mut compressor = try!(SnappyFramedEncoder::new(&mut compressed));
+        let written = try!(compressor.write(&data));
+        assert_eq!(data.len(), written);
+        try!(compressor.flush());
+    }
+    Ok(compressed)
+}
+
+#[test]
+fn decode_example_stream() {
+    use std::fs::File;
+    use dribble::DribbleReader;
+
+    use test_helpers::*;
+
+    let mut compressed = File::open("data/arbres.txt.sz").unwrap();
+    let mut dribble = DribbleReader::new(&mut compressed);
+    let mut decompressor = SnappyFramedDecoder::new(&mut dribble,
+                                                    CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+
+    let expected = read_file("data/arbres.txt").unwrap();
+    assert_eq!(expected, decompressed);
+}
+
+#[test]
+fn encode_and_decode_large_data() {
+    use std::io::Cursor;
+
+    use test_helpers::*;
+
+    // Build a multi-MB vector containing text data.
+    let hunk = read_file("data/arbres.txt").unwrap();
+    let input = repeat_data(&hunk, 1000);
+    let compressed = large_compressed_data(1000).unwrap();
+
+    // Decode it.
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+
+    // Compare it.
+    assert_eq!(input, decompressed);
+}
+
+#[test]
+fn low_memory_mode_decodes_with_one_small_buffer() {
+    use std::fs::File;
+    use std::io::Cursor;
+    use dribble::DribbleReader;
+
+    use test_helpers::*;
+
+    let mut compressed = File::open("data/arbres.txt.sz").unwrap();
+    let mut dribble = DribbleReader::new(&mut compressed);
+    let mut decompressor =
+        SnappyFramedDecoder::new_low_memory(&mut dribble, CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(read_file("data/arbres.txt").unwrap(), decompressed);
+
+    let hunk = read_file("data/arbres.txt").unwrap();
+    let input = repeat_data(&hunk, 100);
+    let compressed = large_compressed_data(100).unwrap();
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decompressor =
+        SnappyFramedDecoder::new_low_memory(&mut cursor, CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(input, decompressed);
+    assert_eq!(0, decompressor.output.capacity());
+    assert!(decompressor.memory_usage() <= 2 * MAX_UNCOMPRESSED_CHUNK);
+
+    // Uncompressed chunks are served straight out of the input buffer.
+    let stored = repeat_chunks(3, 1000);
+    let mut cursor = Cursor::new(&stored as &[u8]);
+    let mut decompressor =
+        SnappyFramedDecoder::new_low_memory(&mut cursor, CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(vec![b'x'; 3000], decompressed);
+}
+
+#[test]
+fn input_buffer_adapts_to_chunk_sizes() {
+    use std::io::Cursor;
+
+    // A stream of small chunks only needs a small buffer.
+    let small = repeat_chunks(100, 1);
+    let mut cursor = Cursor::new(&small as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(MIN_INPUT_BUFFER, decompressor.input.capacity());
+    assert_eq!(MIN_INPUT_BUFFER + MAX_UNCOMPRESSED_CHUNK,
+               decompressor.memory_usage());
+
+    // One huge chunk grows the buffer, which shrinks again afterwards.
+    let mut mixed = repeat_chunks(1, 60_000);
+    mixed.extend(repeat_chunks(2 * CHUNKS_BEFORE_SHRINK, 1).into_iter()
+                 .skip(10));
+    let mut cursor = Cursor::new(&mixed as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Ignore);
+    let mut buf = [0; 1];
+    decompressor.read_exact(&mut buf).unwrap();
+    assert!(decompressor.input.capacity() >= 60_000);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(MIN_INPUT_BUFFER, decompressor.input.capacity());
+}
+
+/// Build a stream containing `count` uncompressed chunks of `len` bytes.
+/// We ignore the spec's size limit so we can exercise large buffers.
+#[cfg(test)]
+fn repeat_chunks(count: usize, len: usize) -> Vec<u8> {
+    let mut result = vec![0xff, 0x06, 0x00, 0x00,
+                          0x73, 0x4e, 0x61, 0x50, 0x70, 0x59];
+    let data = vec![b'x'; len];
+    let crc = masked_crc(&data);
+    let chunk_len = CRC_SIZE + len;
+    for _ in 0..count {
+        result.extend([0x01,
+                       (chunk_len & 0xFF) as u8,
+                       ((chunk_len >> 8) & 0xFF) as u8,
+                       ((chunk_len >> 16) & 0xFF) as u8,
+                       (crc & 0xFF) as u8,
+                       ((crc >> 8) & 0xFF) as u8,
+                       ((crc >> 16) & 0xFF) as u8,
+                       ((crc >> 24) & 0xFF) as u8].iter().cloned());
+        result.extend(data.iter().cloned());
+    }
+    result
+}
+
+#[test]
+fn enforce_shared_memory_budget() {
+    use std::io::Cursor;
+
+    let budget = MemoryBudget::new(MIN_INPUT_BUFFER + MAX_UNCOMPRESSED_CHUNK);
+    {
+        let mut first = SnappyFramedDecoder::new(&[] as &[u8], CrcMode::Verify);
+        first.set_memory_budget(budget.clone()).unwrap();
+        let mut second = SnappyFramedDecoder::new(&[] as &[u8], CrcMode::Verify);
+        assert!(second.set_memory_budget(budget.clone()).is_err());
+    }
+    assert_eq!(0, budget.used());
+
+    // If the budget can't cover all our buffers, we don't charge any.
+    let small = MemoryBudget::new(MIN_INPUT_BUFFER);
+    let mut decoder = SnappyFramedDecoder::new(&[] as &[u8], CrcMode::Verify);
+    assert!(decoder.set_memory_budget(small.clone()).is_err());
+    assert_eq!(0, small.used());
+
+    // We can't grow our buffer to hold a large chunk.
+    let stream = repeat_chunks(1, MIN_INPUT_BUFFER);
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_memory_budget(budget.clone()).unwrap();
+    assert!(decompressor.read_to_end(&mut vec!()).is_err());
+}
+
+#[test]
+fn shrink_after_oversized_chunks() {
+    use std::io::Cursor;
+
+    use chunk::{STREAM_IDENTIFIER, header};
+
+    // A huge skippable chunk, followed by some data.
+    let huge = 2 * MAX_INPUT_BUFFER;
+    let mut stream = STREAM_IDENTIFIER.to_vec();
+    stream.extend(header(0x80, huge).iter().cloned());
+    stream.extend(vec![0; huge].into_iter());
+    stream.extend(repeat_chunks(2, 5).into_iter().skip(10));
+
+    for &low_memory in [false, true].iter() {
+        let mut cursor = Cursor::new(&stream as &[u8]);
+        let mut decompressor = if low_memory {
+            SnappyFramedDecoder::new_low_memory(&mut cursor, CrcMode::Verify)
+        } else {
+            SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify)
+        };
+        let mut buf = [0; 5];
+        decompressor.read_exact(&mut buf).unwrap();
+        decompressor.read_exact(&mut buf).unwrap();
+        assert!(decompressor.input.capacity() <= MAX_INPUT_BUFFER);
+    }
+
+    // We can also shrink explicitly.
+    let stream = repeat_chunks(2, 60_000);
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.read_to_end(&mut vec!()).unwrap();
+    assert!(decompressor.input.capacity() > MIN_INPUT_BUFFER);
+    decompressor.shrink_to_fit();
+    assert_eq!(MIN_INPUT_BUFFER, decompressor.input.capacity());
+}
+
+#[test]
+fn decode_batches_of_frames() {
+    use std::io::Cursor;
+
+    let stream = repeat_chunks(5, 10);
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    let mut buf = [0; 4];
+    decompressor.read_exact(&mut buf).unwrap();
+
+    let mut frames = vec!();
+    assert_eq!(3, decompressor.decode_frames_into(&mut frames, 3).unwrap());
+    assert_eq!(vec![vec![b'x'; 6], vec![b'x'; 10], vec![b'x'; 10]], frames);
+    assert_eq!(2, decompressor.decode_frames_into(&mut frames, 3).unwrap());
+    assert_eq!(5, frames.len());
+    assert_eq!(0, decompressor.decode_frames_into(&mut frames, 3).unwrap());
+}
+
+#[test]
+fn oversized_chunks_are_opt_in() {
+    use std::io::{Cursor, Write};
+
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    let input = random_data(3 * MAX_UNCOMPRESSED_CHUNK);
+    let compression = Compression::default()
+        .with_oversized_chunk_size(2 * MAX_UNCOMPRESSED_CHUNK);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.write_all(&input).unwrap();
+    encoder.flush().unwrap();
+    let (compressed, status) = encoder.into_inner();
+    assert_eq!(2, status.frames_written);
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    assert!(decompressor.read_to_end(&mut vec!()).is_err());
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_allow_oversized_chunks(true);
+    let mut output = vec!();
+    decompressor.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+
+    // Uncompressed chunks are checked, too.
+    let stored = repeat_chunks(1, MAX_UNCOMPRESSED_CHUNK + 1);
+    for &low_memory in [false, true].iter() {
+        let mut cursor = Cursor::new(&stored as &[u8]);
+        let mut decompressor = if low_memory {
+            SnappyFramedDecoder::new_low_memory(&mut cursor, CrcMode::Verify)
+        } else {
+            SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify)
+        };
+        assert!(decompressor.read_to_end(&mut vec!()).is_err());
+        let mut cursor = Cursor::new(&stored as &[u8]);
+        let mut decompressor = if low_memory {
+            SnappyFramedDecoder::new_low_memory(&mut cursor, CrcMode::Verify)
+        } else {
+            SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify)
+        };
+        decompressor.set_allow_oversized_chunks(true);
+        let mut output = vec!();
+        decompressor.read_to_end(&mut output).unwrap();
+        assert_eq!(MAX_UNCOMPRESSED_CHUNK + 1, output.len());
+    }
+}
+
+#[cfg(feature = "timing")]
+#[test]
+fn record_timings() {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    let compressed = large_compressed_data(10).unwrap();
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.read_to_end(&mut vec!()).unwrap();
+    let timings = decompressor.timings();
+    assert!(timings.compression > Duration::new(0, 0));
+    assert!(timings.crc > Duration::new(0, 0));
+}
+
+#[test]
+fn crc_mode_defaults_to_verify() {
+    assert_eq!(CrcMode::Verify, CrcMode::default());
+}
+
+#[test]
+fn limit_chunks_without_data() {
+    use std::io::Cursor;
+
+    use chunk::{STREAM_IDENTIFIER, append_padding_chunk};
+
+    let mut stream = STREAM_IDENTIFIER.to_vec();
+    for _ in 0..10 { append_padding_chunk(100, &mut stream); }
+    stream.extend(repeat_chunks(1, 5).into_iter().skip(10));
+
+    // Without limits, we skip all the padding.
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(b"xxxxx" as &[u8], &decompressed as &[u8]);
+
+    // The identifier and the padding make 11 chunks in a row.
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_max_skippable_chunks(Some(10));
+    assert!(decompressor.read_to_end(&mut vec!()).is_err());
+
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_max_skippable_chunks(Some(11));
+    decompressor.set_max_skippable_bytes(Some(500));
+    assert!(decompressor.read_to_end(&mut vec!()).is_err());
+}
+
+#[test]
+fn detect_missing_frames() {
+    use std::io::{Cursor, Write};
+
+    use write::{Checkpoint, SnappyFramedEncoder};
+
+    let mut stream = vec!();
+    let checkpoint = {
+        let mut encoder = SnappyFramedEncoder::new(&mut stream).unwrap();
+        encoder.set_sequence_numbers(true);
+        encoder.write_all(b"first").unwrap();
+        encoder.flush().unwrap();
+        encoder.checkpoint()
+    };
+    // Pretend that one frame was lost in transit.
+    let lost = Checkpoint{ frames_written: checkpoint.frames_written + 1,
+                           ..checkpoint };
+    {
+        let mut encoder = SnappyFramedEncoder::resume(
+            &mut stream, lost, Compression::default());
+        encoder.set_sequence_numbers(true);
+        encoder.write_all(b"third").unwrap();
+        encoder.flush().unwrap();
+    }
+
+    // By default, we ignore the sequence numbers.
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(b"firstthird" as &[u8], &decompressed as &[u8]);
+
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_check_sequence_numbers(true);
+    let mut decompressed = vec!();
+    assert!(decompressor.read_to_end(&mut decompressed).is_err());
+    assert_eq!(b"first" as &[u8], &decompressed as &[u8]);
+}
+
+#[test]
+fn read_content_size_hint() {
+    use std::io::{Cursor, Write};
+
+    use write::SnappyFramedEncoder;
+
+    let mut stream = vec!();
+    {
+        let mut encoder = SnappyFramedEncoder::new(&mut stream).unwrap();
+        encoder.write_content_size_hint(5).unwrap();
+        encoder.write_all(b"Hello").unwrap();
+        assert!(encoder.write_content_size_hint(5).is_err());
+        encoder.flush().unwrap();
+    }
+
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    assert_eq!(Some(5), decompressor.content_size_hint().unwrap());
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(b"Hello" as &[u8], &decompressed as &[u8]);
+
+    let stream = large_compressed_data(1).unwrap();
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    assert_eq!(None, decompressor.content_size_hint().unwrap());
+}
+
+#[test]
+fn zero_crcs_can_mean_unchecksummed() {
+    use std::io::Cursor;
+
+    use chunk::STREAM_IDENTIFIER;
+
+    let mut stream = STREAM_IDENTIFIER.to_vec();
+    stream.extend(data_header(0x01, 5, 0).iter().cloned());
+    stream.extend(b"hello".iter().cloned());
+    let mut bad_crc = data_header(0x01, 5, 1).to_vec();
+    bad_crc.extend(b"world".iter().cloned());
+
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    assert!(decompressor.read_to_end(&mut vec!()).is_err());
+
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::VerifyNonZero);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(b"hello" as &[u8], &decompressed as &[u8]);
+
+    // Non-zero CRCs are still checked.
+    stream.extend(bad_crc);
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::VerifyNonZero);
+    assert!(decompressor.read_to_end(&mut vec!()).is_err());
+}
+
+#[test]
+fn limit_compressed_input() {
+    use std::io::Cursor;
+
+    let stream = repeat_chunks(10, 5);
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_max_input_bytes(Some(stream.len() as u64));
+    decompressor.read_to_end(&mut vec!()).unwrap();
+
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_max_input_bytes(Some(stream.len() as u64 - 1));
+    let mut decompressed = vec!();
+    assert!(decompressor.read_to_end(&mut decompressed).is_err());
+    assert_eq!(45, decompressed.len());
+}
+
+#[test]
+fn verify_payload_checksums() {
+    use std::io::{Cursor, Write};
+
+    use write::SnappyFramedEncoder;
+
+    let mut stream = vec!();
+    {
+        let mut encoder = SnappyFramedEncoder::new(&mut stream).unwrap();
+        encoder.set_payload_checksums(true);
+        encoder.write_all(b"Hello").unwrap();
+        encoder.flush().unwrap();
+    }
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    decompressor.set_verify_payload_checksums(true);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(b"Hello" as &[u8], &decompressed as &[u8]);
+
+    // Corrupt the last byte of compressed data, which we should notice
+    // without even needing the main CRC.
+    *stream.last_mut().unwrap() ^= 0x01;
+    let mut cursor = Cursor::new(&stream as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Ignore);
+    decompressor.set_verify_payload_checksums(true);
+    let err = decompressor.read_to_end(&mut vec!()).unwrap_err();
+    assert!(err.to_string().contains("payload checksum"));
+}
+
+#[test]
+fn read_limited_lines() {
+    use std::io::Write;
+
+    use write::SnappyFramedEncoder;
+
+    let mut stream = vec!();
+    {
+        let compression = Compression::default().with_chunk_size(4);
+        let mut encoder =
+            SnappyFramedEncoder::with_compression(&mut stream, compression)
+            .unwrap();
+        encoder.write_all(b"one\r\ntwo\n\nthree is longer\nfour").unwrap();
+        encoder.flush().unwrap();
+    }
+
+    let decoder = SnappyFramedDecoder::new(&stream as &[u8], CrcMode::Verify);
+    let lines: Vec<String> =
+        decoder.limited_lines(100).map(|l| l.unwrap()).collect();
+    assert_eq!(vec!["one", "two", "", "three is longer", "four"], lines);
+
+    let decoder = SnappyFramedDecoder::new(&stream as &[u8], CrcMode::Verify);
+    let mut lines = decoder.limited_lines(5);
+    assert_eq!("one", lines.next().unwrap().unwrap());
+    assert_eq!("two", lines.next().unwrap().unwrap());
+    assert_eq!("", lines.next().unwrap().unwrap());
+    assert!(lines.next().unwrap().is_err());
+    assert!(lines.next().is_none());
+}
+
+#[test]
+fn encode_from_reader() {
+    use std::io::Cursor;
+
+    use test_helpers::*;
+
+    let input = random_data(200_000);
+    let mut encoder = SnappyFramedEncoder::with_compression(
+        &input as &[u8], Compression::default().with_chunk_size(10_000));
+    let mut compressed = vec!();
+    // Read in odd-sized pieces, to check that chunks span reads.
+    let mut buf = [0; 777];
+    loop {
+        let n = encoder.read(&mut buf).unwrap();
+        if n == 0 { break; }
+        compressed.extend_from_slice(&buf[..n]);
+    }
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decompressor = SnappyFramedDecoder::new(&mut cursor,
+                                                    CrcMode::Verify);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(input, decompressed);
+}
+
+#[test]
+fn malformed_streams_return_errors() {
+    use std::io::Cursor;
+    use std::io::Write;
+
+    use write::SnappyFramedEncoder;
+
+    let mut stream = vec!();
+    {
+        let compression = Compression::default().with_chunk_size(40);
+        let mut encoder =
+            SnappyFramedEncoder::with_compression(&mut stream, compression)
+            .unwrap();
+        encoder.write_all(&repeat_chunks(1, 50)).unwrap();
+        encoder.write_skippable_chunk(0x80, b"skip").unwrap();
+    }
+
+    // Decode `input` in every mode, returning `Err` rather than panicking.
+    fn decode_all(input: &[u8]) -> Vec<io::Result<Vec<u8>>> {
+        let mut results = vec!();
+        for &mode in &[CrcMode::Verify, CrcMode::Ignore] {
+            for &low_memory in &[false, true] {
+                let cursor = Cursor::new(input);
+                let mut decoder =
+                    SnappyFramedDecoder::with_buffers(cursor, mode, low_memory);
+                let mut output = vec!();
+                results.push(decoder.read_to_end(&mut output).map(|_| output));
+            }
+        }
+        results
+    }
+
+    let mut boundaries = vec![0];
+    while *boundaries.last().unwrap() < stream.len() {
+        let start = *boundaries.last().unwrap();
+        let (_, chunk_len) = parse_header(&stream[start..start + HEADER_SIZE]);
+        boundaries.push(start + HEADER_SIZE + chunk_len);
+    }
+
+    for len in 0..stream.len() {
+        let truncated = &stream[..len];
+        let at_chunk_boundary = boundaries.contains(&len);
+        for result in decode_all(truncated) {
+            assert_eq!(at_chunk_boundary, result.is_ok(),
+                       "truncated to {} bytes", len);
+        }
+    }
+
+    for pos in 0..stream.len() {
+        for &mask in &[0x01, 0xFF] {
+            let mut corrupt = stream.clone();
+            corrupt[pos] ^= mask;
+            // We only care that this doesn't panic.
+            decode_all(&corrupt);
+        }
+    }
+}
+
+#[test]
+fn truncated_streams_are_unexpected_eof() {
+    let stream = repeat_chunks(2, 10);
+    let mut decompressor = SnappyFramedDecoder::new(
+        &stream[..stream.len() - 1], CrcMode::Verify);
+    let err = decompressor.read_to_end(&mut vec!()).unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+    let mut corrupt = stream.clone();
+    *corrupt.last_mut().unwrap() ^= 0x01;
+    let mut decompressor = SnappyFramedDecoder::new(&corrupt as &[u8],
+                                                    CrcMode::Verify);
+    let err = decompressor.read_to_end(&mut vec!()).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+fn verify_swapped_crcs() {
+    let data = b"Hello";
+    let crc = masked_crc(data).swap_bytes();
+    let mut stream = vec![0xff, 0x06, 0x00, 0x00,
+                          0x73, 0x4e, 0x61, 0x50, 0x70, 0x59];
+    stream.extend(data_header(0x01, data.len(), crc).iter().cloned());
+    stream.extend(data.iter().cloned());
+
+    let mut decompressor = SnappyFramedDecoder::new(&stream as &[u8],
+                                                    CrcMode::Verify);
+    assert!(decompressor.read_to_end(&mut vec!()).is_err());
+
+    let mut decompressor = SnappyFramedDecoder::new(&stream as &[u8],
+                                                    CrcMode::VerifySwapped);
+    let mut decompressed = vec!();
+    decompressor.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(data as &[u8], &decompressed as &[u8]);
+}
+
+#[test]
+fn detect_crc_byte_order() {
+    use std::io::Write;
+
+    use write::SnappyFramedEncoder;
+
+    for &swapped in &[false, true] {
+        let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+        encoder.set_swapped_crcs(swapped);
+        encoder.write_all(b"first").unwrap();
+        encoder.flush().unwrap();
+        encoder.write_all(b"second").unwrap();
+        encoder.flush().unwrap();
+        let (stream, _) = encoder.into_inner();
+
+        let mut decompressor = SnappyFramedDecoder::new(&stream as &[u8],
+                                                        CrcMode::Auto);
+        let mut decompressed = vec!();
+        decompressor.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(b"firstsecond" as &[u8], &decompressed as &[u8]);
+
+        // Once we've locked onto one order, the other is rejected.
+        let mut mixed = stream.clone();
+        let mut other = SnappyFramedEncoder::new(vec!()).unwrap();
+        other.set_swapped_crcs(!swapped);
+        other.write_all(b"third").unwrap();
+        other.flush().unwrap();
+        let (other, _) = other.into_inner();
+        mixed.extend(other[STREAM_IDENTIFIER.len()..].iter().cloned());
+        let mut decompressor = SnappyFramedDecoder::new(&mixed as &[u8],
+                                                        CrcMode::Auto);
+        assert!(decompressor.read_to_end(&mut vec!()).is_err());
+    }
+}
+
+#[test]
+fn build_decoder() {
+    use std::io::Write;
+
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    let input = random_data(5000);
+    let mut stream = vec!();
+    {
+        let mut encoder = SnappyFramedEncoder::new(&mut stream).unwrap();
+        encoder.write_all(&input).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    let builder = SnappyFramedDecoder::builder()
+        .with_require_stream_identifier(true)
+        .with_min_input_buffer(256)
+        .with_max_input_buffer(1024);
+    let mut decoder = builder.build(&stream as &[u8]);
+    assert_eq!(256, decoder.memory_usage() - MAX_UNCOMPRESSED_CHUNK);
+    let clamped = builder.with_min_input_buffer(4096).build(&[] as &[u8]);
+    assert_eq!(1024, clamped.memory_usage() - MAX_UNCOMPRESSED_CHUNK);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+
+    // Streams must start with a valid identifier.
+    let headless = &stream[STREAM_IDENTIFIER.len()..];
+    let err = builder.build(headless).read_to_end(&mut vec!()).unwrap_err();
+    assert_eq!("Invalid Snappy stream identifier", err.to_string());
+    let mut bad = stream.clone();
+    bad[4] = b'X';
+    assert!(builder.build(&bad as &[u8]).read_to_end(&mut vec!()).is_err());
+    let mut output = vec!();
+    SnappyFramedDecoder::new(headless, CrcMode::Verify)
+        .read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+
+    let mut limited = builder.with_max_input_bytes(Some(20)).build(
+        &stream as &[u8]);
+    assert!(limited.read_to_end(&mut vec!()).is_err());
+}
+
+#[test]
+fn reject_oversized_chunk_lengths_early() {
+    use chunk::header;
+
+    // A data chunk claiming to be far larger than the spec allows, with
+    // no body.  We should fail without waiting for (or allocating) it.
+    for &chunk_type in &[0x00, 0x01] {
+        let mut stream = STREAM_IDENTIFIER.to_vec();
+        stream.extend(header(chunk_type, MAX_CHUNK_LEN).iter().cloned());
+        let mut decoder = SnappyFramedDecoder::new(&stream as &[u8],
+                                                   CrcMode::Verify);
+        let err = decoder.read_to_end(&mut vec!()).unwrap_err();
+        assert_eq!("Snappy chunk too large", err.to_string());
+        assert!(decoder.memory_usage() < 2 * MAX_INPUT_BUFFER);
+    }
+}
+
+#[test]
+fn lenient_chunk_size_policy() {
+    use std::io::Write;
+
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    let input = random_data(100_000);
+    let compression = Compression::default()
+        .with_oversized_chunk_size(100_000);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.write_all(&input).unwrap();
+    let stream = encoder.finish().unwrap();
+
+    let decode = |policy| {
+        let mut decoder = SnappyFramedDecoder::builder()
+            .with_chunk_size_policy(policy)
+            .build(&stream as &[u8]);
+        let mut output = vec!();
+        decoder.read_to_end(&mut output).map(|_| output)
+    };
+    assert!(decode(ChunkSizePolicy::Strict).is_err());
+    assert!(decode(ChunkSizePolicy::Lenient(99_999)).is_err());
+    assert_eq!(input, decode(ChunkSizePolicy::Lenient(100_000)).unwrap());
+    assert_eq!(input, decode(ChunkSizePolicy::Lenient(usize::MAX)).unwrap());
+}
+
+#[test]
+fn reject_unframed_snappy_immediately() {
+    use std::io::Cursor;
+
+    use raw;
+    use test_helpers::*;
+
+    // Raw Snappy data, without any framing.
+    let raw = raw::compress(&repeat_data(b"unframed ", 100_000));
+    let mut cursor = Cursor::new(&raw as &[u8]);
+    {
+        let mut decoder = SnappyFramedDecoder::builder()
+            .with_low_memory(true)
+            .with_require_stream_identifier(true)
+            .build(&mut cursor);
+        let err = decoder.read_to_end(&mut vec!()).unwrap_err();
+        assert_eq!("Invalid Snappy stream identifier", err.to_string());
+    }
+    assert_eq!(HEADER_SIZE as u64, cursor.position());
+}
+
+#[test]
+fn pass_skippable_chunks_to_handler() {
+    use std::io::{Cursor, Write};
+    use std::sync::{Arc, Mutex};
+
+    let mut encoder = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.set_sequence_numbers(true);
+    encoder.write_skippable_chunk(0x90, b"metadata").unwrap();
+    encoder.write_all(b"Hello!").unwrap();
+    let mut compressed = encoder.finish().unwrap();
+    // Padding isn't passed to the handler.
+    compressed.extend_from_slice(&[0xFE, 0x02, 0x00, 0x00, 0x00, 0x00]);
+
+    let seen = Arc::new(Mutex::new(vec!()));
+    let handler_seen = seen.clone();
+    let mut decoder = SnappyFramedDecoder::new(Cursor::new(&compressed),
+                                               CrcMode::Verify);
+    decoder.set_skippable_chunk_handler(move |chunk_type: u8, data: &[u8]| {
+        handler_seen.lock().unwrap().push((chunk_type, data.to_vec()));
+        Ok(())
+    });
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"Hello!", &output as &[u8]);
+    assert_eq!(vec!((0x90, b"metadata".to_vec()),
+                    (SEQUENCE_CHUNK_TYPE, vec!(0; 8))),
+               *seen.lock().unwrap());
+
+    // Errors from the handler stop decoding.
+    let mut decoder = SnappyFramedDecoder::new(Cursor::new(&compressed),
+                                               CrcMode::Verify);
+    decoder.set_skippable_chunk_handler(|_: u8, _: &[u8]| {
+        Err(io::Error::new(io::ErrorKind::Other, "unwanted metadata"))
+    });
+    let err = decoder.read_to_end(&mut vec!()).unwrap_err();
+    assert_eq!("unwanted metadata", err.to_string());
+}
+
+#[test]
+fn split_members_at_identifiers() {
+    use std::io::{Cursor, Write};
+
+    let mut compressed = vec!();
+    for (i, member) in [b"first", b"other"].iter().enumerate() {
+        let mut encoder = write::SnappyFramedEncoder::new(vec!()).unwrap();
+        encoder.set_sequence_numbers(true);
+        for _ in 0..i + 1 {
+            encoder.write_all(*member).unwrap();
+            encoder.flush().unwrap();
+        }
+        compressed.extend(encoder.finish().unwrap());
+    }
+
+    // By default, members are decoded as one stream.
+    let mut decoder = SnappyFramedDecoder::new(Cursor::new(&compressed),
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"firstotherother", &output as &[u8]);
+    assert!(!decoder.next_member());
+
+    // Sequence numbers restart with each member.
+    let mut decoder = SnappyFramedDecoder::new(Cursor::new(&compressed),
+                                               CrcMode::Verify);
+    decoder.set_split_members(true);
+    decoder.set_check_sequence_numbers(true);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"first", &output as &[u8]);
+    assert_eq!(0, decoder.read(&mut [0; 10]).unwrap());
+    assert!(decoder.next_member());
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"otherother", &output as &[u8]);
+    assert!(!decoder.next_member());
+}
+
+#[test]
+fn detect_trailing_garbage() {
+    use std::io::{Cursor, Write};
+
+    let mut encoder = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(b"Hello!").unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // Junk which looks like a reserved chunk is skipped by default.
+    let mut junk = compressed.clone();
+    junk.extend_from_slice(&[0x42, 0x03, 0x00, 0x00, 0x01, 0x02, 0x03]);
+    let mut decoder = SnappyFramedDecoder::new(Cursor::new(&junk),
+                                               CrcMode::Verify);
+    decoder.read_to_end(&mut vec!()).unwrap();
+
+    let mut decoder = SnappyFramedDecoder::new(Cursor::new(&junk),
+                                               CrcMode::Verify);
+    decoder.set_reject_reserved_chunks(true);
+    let err = decoder.read_to_end(&mut vec!()).unwrap_err();
+    assert_eq!("Reserved unskippable Snappy chunk", err.to_string());
+
+    // Junk too short for a header is always an error.
+    let mut junk = compressed.clone();
+    junk.extend_from_slice(&[0x01, 0x02]);
+    let mut decoder = SnappyFramedDecoder::new(Cursor::new(&junk),
+                                               CrcMode::Verify);
+    let err = decoder.read_to_end(&mut vec!()).unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+    // But we can report it as junk, and keep it for inspection.
+    let detect = |stream: &[u8]| {
+        let mut decoder = SnappyFramedDecoder::new(Cursor::new(stream),
+                                                   CrcMode::Verify);
+        decoder.set_detect_trailing_junk(true);
+        let mut output = vec!();
+        let result = decoder.read_to_end(&mut output).map(|_| output);
+        (result, decoder.trailing_junk().to_vec())
+    };
+    let (result, trailing) = detect(&junk);
+    let err = result.unwrap_err();
+    assert_eq!(format!("2 bytes of junk after Snappy stream at offset {}",
+                       compressed.len()),
+               err.to_string());
+    assert_eq!(vec!(0x01, 0x02), trailing);
+
+    // Junk which looks like a skippable chunk, followed by a partial one.
+    let mut junk = compressed.clone();
+    let skippable = [0x90, 0x02, 0x00, 0x00, 0xAA, 0xBB, 0x01];
+    junk.extend_from_slice(&skippable);
+    let (result, trailing) = detect(&junk);
+    match SnappyFramedError::from(result.unwrap_err()) {
+        SnappyFramedError::TrailingJunk{offset, len} => {
+            assert_eq!(compressed.len() as u64, offset);
+            assert_eq!(skippable.len() as u64, len);
+        }
+        other => panic!("unexpected error: {}", other)
+    }
+    assert_eq!(skippable.to_vec(), trailing);
+
+    // Unrecognized chunks are fine if data follows them, and so are our
+    // own chunk types and padding at the end.
+    let mut encoder = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.set_frame_hasher(|data: &[u8]| vec!(data.len() as u8));
+    encoder.write_all(b"Hello, ").unwrap();
+    encoder.flush().unwrap();
+    encoder.write_skippable_chunk(0x90, b"metadata").unwrap();
+    encoder.write_all(b"world!").unwrap();
+    encoder.flush().unwrap();
+    encoder.write_manifest().unwrap();
+    let mut stream = encoder.finish().unwrap();
+    stream.extend_from_slice(&[0xFE, 0x01, 0x00, 0x00, 0x00]);
+    let (result, trailing) = detect(&stream);
+    assert_eq!(b"Hello, world!".to_vec(), result.unwrap());
+    assert!(trailing.is_empty());
+}
+
+#[test]
+fn seek_within_decoder() {
+    use std::io::{Cursor, Write};
+
+    use compression::Compression;
+    use test_helpers::*;
+
+    let input = mixed_data(20_000);
+    let compression = Compression::default().with_chunk_size(1000);
+    let mut encoder =
+        write::SnappyFramedEncoder::with_compression(vec!(), compression)
+        .unwrap();
+    encoder.set_sequence_numbers(true);
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    for &low_memory in &[false, true] {
+        let mut decoder = SnappyFramedDecoder::builder()
+            .with_low_memory(low_memory)
+            .build(Cursor::new(&compressed));
+        decoder.set_check_sequence_numbers(true);
+        let mut buf = [0; 100];
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(&input[..100], &buf as &[u8]);
+
+        // Forwards, backwards to a chunk we've seen, and back to the
+        // start of the stream.
+        for &(start, len) in &[(12_345, 5000), (15_000, 10), (20, 3000),
+                               (0, 1), (input.len() - 3, 3)] {
+            assert_eq!(start as u64,
+                       decoder.seek(SeekFrom::Start(start as u64)).unwrap());
+            let mut output = vec![0; len];
+            decoder.read_exact(&mut output).unwrap();
+            assert_eq!(&input[start..start + len], &output as &[u8]);
+        }
+
+        let end = input.len() as u64;
+        assert_eq!(end - 10, decoder.seek(SeekFrom::Current(-10)).unwrap());
+        assert_eq!(end, decoder.seek(SeekFrom::Current(100)).unwrap());
+        assert_eq!(0, decoder.read(&mut buf).unwrap());
+        assert!(decoder.seek(SeekFrom::End(0)).is_err());
+        assert!(decoder.seek(SeekFrom::Current(-1_000_000)).is_err());
+
+        decoder.seek(SeekFrom::Start(0)).unwrap();
+        let mut output = vec!();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(input, output);
+    }
+}
+
+#[test]
+fn resume_after_would_block() {
+    use std::io::Write;
+
+    use compression::Compression;
+    use test_helpers::*;
+
+    /// A non-blocking source which is only ready every other time, and
+    /// then returns at most 3 bytes.
+    struct NonBlocking<'a> {
+        data: &'a [u8],
+        ready: bool
+    }
+
+    impl<'a> Read for NonBlocking<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                          "not ready"));
+            }
+            let len = min(3, buf.len());
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    let input = mixed_data(5000);
+    let compression = Compression::default().with_chunk_size(1000);
+    let mut encoder =
+        write::SnappyFramedEncoder::with_compression(vec!(), compression)
+        .unwrap();
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    for &low_memory in &[false, true] {
+        let source = NonBlocking{data: &compressed, ready: false};
+        let mut decoder = SnappyFramedDecoder::builder()
+            .with_low_memory(low_memory)
+            .build(source);
+        let mut output = vec!();
+        let mut buf = [0; 700];
+        loop {
+            match decoder.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => panic!("unexpected error: {}", err)
+            }
+        }
+        assert_eq!(input, output);
+    }
+}
 
 #[cfg(all(test, feature = "unstable"))]
 mod benches {
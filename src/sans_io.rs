@@ -0,0 +1,1133 @@
+//! State machines which parse and build Snappy framed streams without
+//! doing any I/O.  The caller feeds in bytes from wherever they come
+//! from, and takes out whatever the state machine produces.  Our `read`
+//! and `write` coders are thin wrappers around these, as are `async_io`
+//! and `stream`, so every I/O model shares a single implementation of the
+//! format and its options.
+//!
+//! ```
+//! use snappy_framed::sans_io::{ChunkDecoder, ChunkEncoder, DecoderEvent};
+//! use snappy_framed::read::CrcMode;
+//!
+//! let mut encoder = ChunkEncoder::new();
+//! encoder.write_input(b"Hello!");
+//! encoder.flush();
+//! let compressed = encoder.take_output();
+//!
+//! let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+//! decoder.push_input(&compressed);
+//! assert_eq!(Some(DecoderEvent::StreamIdentifier),
+//!            decoder.next_event().unwrap());
+//! assert_eq!(Some(DecoderEvent::Data(b"Hello!".to_vec())),
+//!            decoder.next_event().unwrap());
+//! assert_eq!(None, decoder.next_event().unwrap());
+//! decoder.finish().unwrap();
+//! ```
+
+use std::borrow::Cow;
+use std::cmp::{max, min};
+use std::io::{self, Read};
+use std::mem;
+#[cfg(test)] use std::time::Instant;
+
+use batch::BATCH_INDEX_CHUNK_TYPE;
+use chunk::*;
+use compression::{ChunkSizer, Compression};
+use consts::*;
+use error::SnappyFramedError;
+use manifest::*;
+use masked_crc::*;
+use raw::{DefaultCodec, RawCodec};
+use read::{ChunkSizePolicy, CrcMode};
+use seek::{ChunkIndex, IndexEntry};
+use write::{Checkpoint, EncoderStatus};
+#[cfg(feature = "timing")] use timing::Timings;
+
+/// Something we found in a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecoderEvent {
+    /// A stream identifier.
+    StreamIdentifier,
+    /// The decompressed contents of a data chunk.
+    Data(Vec<u8>),
+    /// Any other chunk, of the specified type, which we ignored.  Like
+    /// `read::SnappyFramedDecoder`, we skip reserved chunk types by
+    /// default.
+    Skipped(u8)
+}
+
+/// What we found in a single chunk.  Data stored uncompressed is left in
+/// the chunk, so that callers with their own buffers can avoid copying it.
+pub(crate) enum ChunkContents<'a> {
+    /// Data which we decompressed.
+    Decompressed(Vec<u8>),
+    /// Data which was stored uncompressed.
+    Stored(&'a [u8]),
+    /// A stream identifier.
+    StreamIdentifier,
+    /// Any other chunk, of the specified type.
+    Skipped(u8)
+}
+
+/// Splits a Snappy framed stream into chunks and decodes them.  This
+/// supports all of the options of `read::SnappyFramedDecoder`, which
+/// uses it to interpret each chunk.
+pub struct ChunkDecoder {
+    mode: CrcMode,
+    /// The largest chunk we're willing to decompress.
+    max_chunk_size: usize,
+    max_skippable_chunks: Option<usize>,
+    max_skippable_bytes: Option<u64>,
+    /// Consecutive chunks without data.
+    skippable_chunks: usize,
+    /// Total bytes in chunks without data.
+    skippable_bytes: u64,
+    check_sequence_numbers: bool,
+    /// The sequence number we expect to see next, if we've seen any.
+    next_sequence: Option<u64>,
+    content_size: Option<u64>,
+    max_input_bytes: Option<u64>,
+    /// Total bytes in all chunks we've decoded.
+    input_bytes: u64,
+    verify_payload_checksums: bool,
+    /// The checksum of the next data chunk, if the stream supplied one.
+    next_payload_crc: Option<u32>,
+    /// Must the next chunk be a stream identifier?
+    expect_identifier: bool,
+    /// Should we check the contents of stream identifiers?
+    strict_identifiers: bool,
+    reject_reserved_chunks: bool,
+    detect_trailing_junk: bool,
+    /// The chunks since the last data chunk, starting with one we didn't
+    /// recognize.  If the stream ends here, these are junk.
+    trailing_junk: Vec<u8>,
+    /// Where `trailing_junk` starts in the stream.
+    trailing_junk_offset: u64,
+    raw_codec: Box<dyn RawCodec + Send + Sync>,
+    #[cfg(feature = "timing")]
+    timings: Timings,
+    /// Input passed to `push_input`.
+    input: Vec<u8>,
+    /// How much of `input` we've already decoded.
+    pos: usize
+}
+
+impl ChunkDecoder {
+    /// Create a new decoder, using the CRC verification options indicated
+    /// by `mode`.
+    pub fn new(mode: CrcMode) -> ChunkDecoder {
+        ChunkDecoder{
+            mode: mode,
+            max_chunk_size: MAX_UNCOMPRESSED_CHUNK,
+            max_skippable_chunks: None,
+            max_skippable_bytes: None,
+            skippable_chunks: 0,
+            skippable_bytes: 0,
+            check_sequence_numbers: false,
+            next_sequence: None,
+            content_size: None,
+            max_input_bytes: None,
+            input_bytes: 0,
+            verify_payload_checksums: false,
+            next_payload_crc: None,
+            expect_identifier: false,
+            strict_identifiers: false,
+            reject_reserved_chunks: false,
+            detect_trailing_junk: false,
+            trailing_junk: vec!(),
+            trailing_junk_offset: 0,
+            raw_codec: Box::new(DefaultCodec),
+            #[cfg(feature = "timing")]
+            timings: Timings::default(),
+            input: vec!(),
+            pos: 0
+        }
+    }
+
+    /// Choose how large a chunk's data may be.  See
+    /// `read::SnappyFramedDecoder::set_chunk_size_policy`.
+    pub fn set_chunk_size_policy(&mut self, policy: ChunkSizePolicy) {
+        self.max_chunk_size = match policy {
+            ChunkSizePolicy::Strict => MAX_UNCOMPRESSED_CHUNK,
+            ChunkSizePolicy::Lenient(max_chunk_size) =>
+                min(max_chunk_size, MAX_CHUNK_LEN)
+        };
+    }
+
+    /// The largest amount of data we'll accept in a single chunk.
+    pub fn max_chunk_size(&self) -> usize { self.max_chunk_size }
+
+    /// Limit consecutive chunks without data.  See
+    /// `read::SnappyFramedDecoder::set_max_skippable_chunks`.
+    pub fn set_max_skippable_chunks(&mut self, limit: Option<usize>) {
+        self.max_skippable_chunks = limit;
+    }
+
+    /// Limit the total size of chunks without data.  See
+    /// `read::SnappyFramedDecoder::set_max_skippable_bytes`.
+    pub fn set_max_skippable_bytes(&mut self, limit: Option<u64>) {
+        self.max_skippable_bytes = limit;
+    }
+
+    /// Limit the total size of the compressed input.  See
+    /// `read::SnappyFramedDecoder::set_max_input_bytes`.
+    pub fn set_max_input_bytes(&mut self, limit: Option<u64>) {
+        self.max_input_bytes = limit;
+    }
+
+    /// Check payload checksums.  See
+    /// `read::SnappyFramedDecoder::set_verify_payload_checksums`.
+    pub fn set_verify_payload_checksums(&mut self, verify: bool) {
+        self.verify_payload_checksums = verify;
+    }
+
+    /// Require a valid stream identifier.  See
+    /// `read::SnappyFramedDecoder::set_require_stream_identifier`.
+    pub fn set_require_stream_identifier(&mut self, require: bool) {
+        self.expect_identifier = require && self.input_bytes == 0;
+        self.strict_identifiers = require;
+    }
+
+    /// Check sequence numbers.  See
+    /// `read::SnappyFramedDecoder::set_check_sequence_numbers`.
+    pub fn set_check_sequence_numbers(&mut self, check: bool) {
+        self.check_sequence_numbers = check;
+    }
+
+    /// Reject reserved unskippable chunks.  See
+    /// `read::SnappyFramedDecoder::set_reject_reserved_chunks`.
+    pub fn set_reject_reserved_chunks(&mut self, reject: bool) {
+        self.reject_reserved_chunks = reject;
+    }
+
+    /// Report junk at the end of the stream.  See
+    /// `read::SnappyFramedDecoder::set_detect_trailing_junk`.
+    pub fn set_detect_trailing_junk(&mut self, detect: bool) {
+        self.detect_trailing_junk = detect;
+    }
+
+    /// The bytes reported as `SnappyFramedError::TrailingJunk`.  See
+    /// `read::SnappyFramedDecoder::trailing_junk`.
+    pub fn trailing_junk(&self) -> &[u8] {
+        &self.trailing_junk
+    }
+
+    /// Decompress data chunks using `codec`.  See `raw` for details.
+    pub fn set_raw_codec<C>(&mut self, codec: C)
+        where C: RawCodec + Send + Sync + 'static
+    {
+        self.raw_codec = Box::new(codec);
+    }
+
+    /// The uncompressed length declared by the stream, if we've seen it.
+    pub fn content_size(&self) -> Option<u64> { self.content_size }
+
+    /// The number of bytes in all the chunks we've decoded, including
+    /// their headers.
+    pub fn input_bytes(&self) -> u64 { self.input_bytes }
+
+    /// Time spent decompressing and verifying data so far.  This never
+    /// includes any time spent on I/O.
+    #[cfg(feature = "timing")]
+    pub fn timings(&self) -> Timings {
+        self.timings
+    }
+
+    /// Forget the sequence numbers and content size of the previous
+    /// member of a stream.
+    pub(crate) fn start_member(&mut self) {
+        self.next_sequence = None;
+        self.content_size = None;
+    }
+
+    /// Prepare to decode a chunk which starts `input_bytes` into the
+    /// stream, after seeking there.  Any buffered input is discarded.
+    pub(crate) fn restart_at(&mut self, input_bytes: u64) {
+        self.input_bytes = input_bytes;
+        self.skippable_chunks = 0;
+        self.next_sequence = None;
+        self.next_payload_crc = None;
+        self.expect_identifier = false;
+        self.trailing_junk.clear();
+        self.input.clear();
+        self.pos = 0;
+    }
+
+    /// Append more of the stream to our input.
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.drain(..self.pos);
+        self.pos = 0;
+        self.input.extend_from_slice(data);
+    }
+
+    /// Give back the last `len` bytes passed to `push_input`, which must
+    /// not have been decoded yet.
+    pub(crate) fn unread_input(&mut self, len: usize) {
+        assert!(len <= self.buffered_len());
+        let end = self.input.len() - len;
+        self.input.truncate(end);
+    }
+
+    /// Read at most `wanted()` bytes from `source` into our input, using
+    /// `buf` as scratch space, so that we never read past the end of the
+    /// next chunk.  Returns `false` at the end of the stream.
+    pub(crate) fn read_from<R: Read>(&mut self, source: &mut R,
+                                     buf: &mut Vec<u8>) -> io::Result<bool> {
+        buf.resize(self.wanted(), 0);
+        loop {
+            match source.read(buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.push_input(&buf[..n]);
+                    return Ok(true);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    /// A new decoder for passing data chunks to `decode_data`, using our
+    /// CRC mode and chunk size limit, so that data chunks can be decoded
+    /// on other threads.  It always uses the default raw codec.
+    pub(crate) fn data_decoder(&self) -> ChunkDecoder {
+        let mut decoder = ChunkDecoder::new(self.mode);
+        decoder.max_chunk_size = self.max_chunk_size;
+        decoder
+    }
+
+    /// The number of bytes of input we're holding which haven't been
+    /// decoded yet.
+    pub fn buffered_len(&self) -> usize {
+        self.input.len() - self.pos
+    }
+
+    /// The minimum number of bytes we need before `next_event` can
+    /// return anything new.  Callers may push more than this.
+    pub fn wanted(&self) -> usize {
+        let available = self.buffered_len();
+        if available < HEADER_SIZE { return HEADER_SIZE - available; }
+        let (_, chunk_len) = parse_header(&self.input[self.pos..]);
+        (HEADER_SIZE + chunk_len).saturating_sub(available)
+    }
+
+    /// Decode the next chunk.  Returns `None` if we need more input
+    /// first.  After an error, the stream can't be decoded any further.
+    pub fn next_event(&mut self) ->
+        Result<Option<DecoderEvent>, SnappyFramedError>
+    {
+        let (chunk_type, end) = match try!(self.next_chunk_end()) {
+            Some(found) => found,
+            None => return Ok(None)
+        };
+        // Our input can't stay borrowed while we decode the chunk.
+        let input = mem::replace(&mut self.input, vec!());
+        let result = self.decode_chunk(&Chunk{
+            chunk_type: chunk_type,
+            data: &input[self.pos + HEADER_SIZE..end]
+        }).map(|contents| {
+            match contents {
+                ChunkContents::Decompressed(data) => DecoderEvent::Data(data),
+                ChunkContents::Stored(data) =>
+                    DecoderEvent::Data(data.to_vec()),
+                ChunkContents::StreamIdentifier =>
+                    DecoderEvent::StreamIdentifier,
+                ChunkContents::Skipped(chunk_type) =>
+                    DecoderEvent::Skipped(chunk_type)
+            }
+        });
+        self.input = input;
+        let event = try!(result);
+        self.pos = end;
+        Ok(Some(event))
+    }
+
+    /// Like `next_event`, but skip over everything except data chunks,
+    /// and return the type and contents of the next data chunk without
+    /// decoding it.  The caller should pass it to `decode_data` on a
+    /// decoder from `data_decoder`, perhaps on another thread.
+    pub(crate) fn next_data_chunk(&mut self) ->
+        Result<Option<(u8, Vec<u8>)>, SnappyFramedError>
+    {
+        while let Some((chunk_type, end)) = try!(self.next_chunk_end()) {
+            let start = self.pos + HEADER_SIZE;
+            let input = mem::replace(&mut self.input, vec!());
+            let result = self.frame_chunk(&Chunk{
+                chunk_type: chunk_type,
+                data: &input[start..end]
+            }).map(|contents| contents.is_none());
+            self.input = input;
+            let is_data = try!(result);
+            self.pos = end;
+            if is_data {
+                return Ok(Some((chunk_type, self.input[start..end].to_vec())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check the header of the next chunk in our input, and return its
+    /// type and where it ends, or `None` if we don't have all of it yet.
+    fn next_chunk_end(&self) ->
+        Result<Option<(u8, usize)>, SnappyFramedError>
+    {
+        if self.buffered_len() < HEADER_SIZE { return Ok(None); }
+        let (chunk_type, chunk_len) = parse_header(&self.input[self.pos..]);
+        try!(self.check_header(chunk_type, chunk_len));
+        let end = self.pos + HEADER_SIZE + chunk_len;
+        Ok(if end > self.input.len() { None } else { Some((chunk_type, end)) })
+    }
+
+    /// Check that the stream didn't end in the middle of a chunk, or with
+    /// trailing junk.  Call this when there's no more input.
+    pub fn finish(&mut self) -> Result<(), SnappyFramedError> {
+        if self.buffered_len() > 0 {
+            let rest = self.input[self.pos..].to_vec();
+            return Err(self.truncated(&rest));
+        }
+        self.end_of_stream()
+    }
+
+    /// Fail if a chunk with this header should be rejected before we
+    /// buffer it: anything but a stream identifier when we require one,
+    /// or a data chunk which couldn't hold `max_chunk_size` bytes or
+    /// fewer, so that a corrupt length can't make us allocate a large
+    /// buffer.
+    pub(crate) fn check_header(&self, chunk_type: u8, chunk_len: usize) ->
+        Result<(), SnappyFramedError>
+    {
+        if self.expect_identifier &&
+            (chunk_type, chunk_len) != parse_header(&STREAM_IDENTIFIER)
+        {
+            return Err(SnappyFramedError::BadIdentifier);
+        }
+        let max_len = match chunk_type {
+            0x00 => CRC_SIZE + max_compressed_len(self.max_chunk_size),
+            0x01 => CRC_SIZE + self.max_chunk_size,
+            _ => MAX_CHUNK_LEN
+        };
+        if chunk_len > max_len {
+            return Err(SnappyFramedError::ChunkTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Interpret a complete chunk, enforcing all of our limits and
+    /// checks, and return whatever it contains.
+    pub(crate) fn decode_chunk<'a>(&mut self, chunk: &Chunk<'a>) ->
+        Result<ChunkContents<'a>, SnappyFramedError>
+    {
+        if let Some(contents) = try!(self.frame_chunk(chunk)) {
+            return Ok(contents);
+        }
+        Ok(match try!(self.decode_data(chunk)) {
+            Cow::Owned(data) => ChunkContents::Decompressed(data),
+            Cow::Borrowed(data) => ChunkContents::Stored(data)
+        })
+    }
+
+    /// Check a complete chunk against our limits, and interpret it unless
+    /// it's a data chunk.  Data chunks return `None`, and should then be
+    /// passed to `decode_data`.  Chunks must be passed to us in order.
+    pub(crate) fn frame_chunk<'a>(&mut self, chunk: &Chunk<'a>) ->
+        Result<Option<ChunkContents<'a>>, SnappyFramedError>
+    {
+        let chunk_len = chunk.data.len();
+        self.input_bytes += (HEADER_SIZE + chunk_len) as u64;
+        if let Some(limit) = self.max_input_bytes {
+            if self.input_bytes > limit {
+                return Err(SnappyFramedError::LimitExceeded(
+                    "Too many bytes of Snappy input"));
+            }
+        }
+        if self.detect_trailing_junk { self.track_junk(chunk); }
+        self.expect_identifier = false;
+        match chunk.chunk_type {
+            // Data.
+            0x00 | 0x01 => {
+                if let Some(expected) = self.next_payload_crc.take() {
+                    if masked_crc(chunk.data) != expected {
+                        return Err(SnappyFramedError::Corrupt(
+                            "Invalid Snappy payload checksum"));
+                    }
+                }
+                self.skippable_chunks = 0;
+                return Ok(None);
+            }
+
+            // Reserved unskippable chunks.
+            0x02...0x7F => {
+                if self.reject_reserved_chunks {
+                    return Err(SnappyFramedError::Corrupt(
+                        "Reserved unskippable Snappy chunk"));
+                }
+            }
+            // Frame sequence numbers.
+            SEQUENCE_CHUNK_TYPE if self.check_sequence_numbers => {
+                if chunk_len != 8 {
+                    return Err(SnappyFramedError::Corrupt(
+                        "Malformed Snappy sequence number"));
+                }
+                let sequence = read_u64(chunk.data);
+                match self.next_sequence {
+                    Some(expected) if sequence != expected => {
+                        return Err(SnappyFramedError::MissingFrames{
+                            expected: expected,
+                            found: sequence
+                        });
+                    }
+                    _ => {}
+                }
+                self.next_sequence = Some(sequence.wrapping_add(1));
+            }
+            // Checksum of the next data chunk.
+            PAYLOAD_CRC_CHUNK_TYPE if self.verify_payload_checksums => {
+                if chunk_len != CRC_SIZE {
+                    return Err(SnappyFramedError::Corrupt(
+                        "Malformed Snappy payload checksum"));
+                }
+                self.next_payload_crc = Some(try!(chunk.crc()));
+            }
+            // Declared uncompressed length.
+            CONTENT_SIZE_CHUNK_TYPE => {
+                if chunk_len != 8 {
+                    return Err(SnappyFramedError::Corrupt(
+                        "Malformed Snappy content size"));
+                }
+                self.content_size = Some(read_u64(chunk.data));
+            }
+            // Reserved skippable chunks.
+            0x80...0xFD => {}
+            // Padding.
+            0xFE => {}
+            // Stream identifier.
+            0xFF => {
+                if self.strict_identifiers &&
+                    chunk.data != &STREAM_IDENTIFIER[HEADER_SIZE..]
+                {
+                    return Err(SnappyFramedError::BadIdentifier);
+                }
+            }
+        }
+        try!(self.skipped_chunk(chunk_len));
+        Ok(Some(if chunk.chunk_type == 0xFF {
+            ChunkContents::StreamIdentifier
+        } else {
+            ChunkContents::Skipped(chunk.chunk_type)
+        }))
+    }
+
+    /// Decompress and verify a data chunk which `frame_chunk` has already
+    /// seen.  This only depends on our CRC mode, chunk size limit and raw
+    /// codec.  Data which was stored uncompressed is borrowed from the
+    /// chunk.
+    pub(crate) fn decode_data<'a>(&mut self, chunk: &Chunk<'a>) ->
+        Result<Cow<'a, [u8]>, SnappyFramedError>
+    {
+        let crc = try!(chunk.crc());
+        if chunk.chunk_type == 0x01 {
+            let data = &chunk.data[CRC_SIZE..];
+            if data.len() > self.max_chunk_size {
+                return Err(SnappyFramedError::ChunkTooLarge);
+            }
+            try!(timed!(self.timings.crc,
+                        verify_crc(&mut self.mode, crc, data)));
+            return Ok(Cow::Borrowed(data));
+        }
+        let compressed = &chunk.data[CRC_SIZE..];
+        match uncompressed_len(compressed) {
+            Some(len) if len <= self.max_chunk_size => {}
+            _ => return Err(SnappyFramedError::ChunkTooLarge)
+        }
+        let data = timed!(self.timings.compression,
+                          self.raw_codec.uncompress(compressed));
+        let data = try!(data.ok_or(
+            SnappyFramedError::Corrupt("Corrupt Snappy chunk")));
+        try!(timed!(self.timings.crc,
+                    verify_crc(&mut self.mode, crc, &data)));
+        Ok(Cow::Owned(data))
+    }
+
+    /// Remember `chunk` if it might turn out to be trailing junk.  Data
+    /// chunks clear anything we were holding on to.
+    fn track_junk(&mut self, chunk: &Chunk) {
+        let chunk_len = chunk.data.len();
+        if chunk.chunk_type <= 0x01 {
+            self.trailing_junk.clear();
+        } else if !self.trailing_junk.is_empty() ||
+            !is_recognized(chunk.chunk_type)
+        {
+            if self.trailing_junk.is_empty() {
+                self.trailing_junk_offset =
+                    self.input_bytes - (HEADER_SIZE + chunk_len) as u64;
+            }
+            self.trailing_junk.extend_from_slice(
+                &header(chunk.chunk_type, chunk_len));
+            self.trailing_junk.extend_from_slice(chunk.data);
+        }
+    }
+
+    /// Record that we skipped a chunk of `chunk_len` bytes without
+    /// finding any data, and enforce our limits.
+    fn skipped_chunk(&mut self, chunk_len: usize) ->
+        Result<(), SnappyFramedError>
+    {
+        self.skippable_chunks += 1;
+        self.skippable_bytes += (HEADER_SIZE + chunk_len) as u64;
+        if let Some(limit) = self.max_skippable_chunks {
+            if self.skippable_chunks > limit {
+                return Err(SnappyFramedError::LimitExceeded(
+                    "Too many consecutive Snappy chunks without data"));
+            }
+        }
+        if let Some(limit) = self.max_skippable_bytes {
+            if self.skippable_bytes > limit {
+                return Err(SnappyFramedError::LimitExceeded(
+                    "Too many bytes of Snappy chunks without data"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The error for a stream which ended part way through a chunk,
+    /// leaving `rest` undecoded.  If we're detecting trailing junk,
+    /// that's what this is.
+    pub(crate) fn truncated(&mut self, rest: &[u8]) -> SnappyFramedError {
+        if !self.detect_trailing_junk { return SnappyFramedError::Truncated; }
+        if self.trailing_junk.is_empty() {
+            self.trailing_junk_offset = self.input_bytes;
+        }
+        self.trailing_junk.extend_from_slice(rest);
+        self.trailing_junk_error()
+    }
+
+    /// Check that the stream didn't end with trailing junk.
+    pub(crate) fn end_of_stream(&self) -> Result<(), SnappyFramedError> {
+        if self.trailing_junk.is_empty() {
+            Ok(())
+        } else {
+            Err(self.trailing_junk_error())
+        }
+    }
+
+    fn trailing_junk_error(&self) -> SnappyFramedError {
+        SnappyFramedError::TrailingJunk{
+            offset: self.trailing_junk_offset,
+            len: self.trailing_junk.len() as u64
+        }
+    }
+}
+
+/// Is `chunk_type` one that might reasonably follow the last data chunk in
+/// a stream: padding, a stream identifier, or one of our own skippable
+/// chunk types?
+fn is_recognized(chunk_type: u8) -> bool {
+    match chunk_type {
+        0xFE | 0xFF => true,
+        BATCH_INDEX_CHUNK_TYPE | SEQUENCE_CHUNK_TYPE | MANIFEST_CHUNK_TYPE |
+        CONTENT_SIZE_CHUNK_TYPE | PAYLOAD_CRC_CHUNK_TYPE => true,
+        _ => false
+    }
+}
+
+/// Compresses data into a Snappy framed stream.  This supports all of the
+/// chunking and framing options of `write::SnappyFramedEncoder`, which
+/// uses it to build each chunk.
+pub struct ChunkEncoder {
+    chunk_sizer: ChunkSizer,
+    max_frame_size: Option<usize>,
+    min_savings_percent: Option<u8>,
+    /// Input which doesn't yet fill a chunk.
+    pending: Vec<u8>,
+    alignment: Option<usize>,
+    sequence_numbers: bool,
+    payload_checksums: bool,
+    swapped_crcs: bool,
+    frame_hasher: Option<Box<dyn FrameHasher + Send + Sync>>,
+    raw_codec: Box<dyn RawCodec + Send + Sync>,
+    manifest: DigestManifest,
+    index: Option<ChunkIndex>,
+    /// What we've produced so far, including output not yet taken.  This
+    /// is never poisoned, because we never fail.
+    status: EncoderStatus,
+    /// Compressed chunks waiting to be taken.
+    output: Vec<u8>,
+    #[cfg(feature = "timing")]
+    timings: Timings
+}
+
+impl ChunkEncoder {
+    /// Create a new encoder.
+    pub fn new() -> ChunkEncoder {
+        ChunkEncoder::with_compression(Compression::default())
+    }
+
+    /// Create a new encoder using the options in `compression`.
+    pub fn with_compression(compression: Compression) -> ChunkEncoder {
+        let mut encoder =
+            ChunkEncoder::resume(Checkpoint::default(), compression);
+        encoder.write_stream_identifier();
+        encoder
+    }
+
+    /// Resume encoding a stream after `checkpoint`.  Unlike `new`, we
+    /// don't begin with a stream identifier.  See
+    /// `write::SnappyFramedEncoder::resume`.
+    pub fn resume(checkpoint: Checkpoint, compression: Compression) ->
+        ChunkEncoder
+    {
+        ChunkEncoder{
+            chunk_sizer: ChunkSizer::new(&compression),
+            max_frame_size: compression.max_frame_size(),
+            min_savings_percent: compression.min_savings_percent(),
+            pending: vec!(),
+            alignment: None,
+            sequence_numbers: false,
+            payload_checksums: false,
+            swapped_crcs: false,
+            frame_hasher: None,
+            raw_codec: Box::new(DefaultCodec),
+            manifest: DigestManifest::new(),
+            index: None,
+            status: EncoderStatus{
+                frames_written: checkpoint.frames_written,
+                bytes_written: checkpoint.output_offset,
+                bytes_consumed: checkpoint.input_offset,
+                poisoned: false
+            },
+            output: vec!(),
+            #[cfg(feature = "timing")]
+            timings: Timings::default()
+        }
+    }
+
+    /// Write sequence numbers.  See
+    /// `write::SnappyFramedEncoder::set_sequence_numbers`.
+    pub fn set_sequence_numbers(&mut self, enable: bool) {
+        self.sequence_numbers = enable;
+    }
+
+    /// Align chunks to multiples of `alignment` bytes.  See
+    /// `write::SnappyFramedEncoder::set_alignment`.
+    pub fn set_alignment(&mut self, alignment: Option<usize>) ->
+        Result<(), SnappyFramedError>
+    {
+        if let Some(alignment) = alignment {
+            if alignment < HEADER_SIZE || alignment > MAX_CHUNK_LEN {
+                return Err(SnappyFramedError::InvalidInput(
+                    "Snappy chunk alignment must be between 4 and 16777215"));
+            }
+        }
+        self.alignment = alignment;
+        Ok(())
+    }
+
+    /// Write CRCs in swapped byte order.  See
+    /// `write::SnappyFramedEncoder::set_swapped_crcs`.
+    pub fn set_swapped_crcs(&mut self, swapped: bool) {
+        self.swapped_crcs = swapped;
+    }
+
+    /// Write payload checksums.  See
+    /// `write::SnappyFramedEncoder::set_payload_checksums`.
+    pub fn set_payload_checksums(&mut self, enable: bool) {
+        self.payload_checksums = enable;
+    }
+
+    /// Record a digest of each data chunk in our `manifest`.  See
+    /// `write::SnappyFramedEncoder::set_frame_hasher`.
+    pub fn set_frame_hasher<H>(&mut self, hasher: H)
+        where H: FrameHasher + Send + Sync + 'static
+    {
+        self.frame_hasher = Some(Box::new(hasher));
+    }
+
+    /// Compress data chunks using `codec`.  See `raw` for details.
+    pub fn set_raw_codec<C>(&mut self, codec: C)
+        where C: RawCodec + Send + Sync + 'static
+    {
+        self.raw_codec = Box::new(codec);
+    }
+
+    /// The digests recorded since `set_frame_hasher` was called.
+    pub fn manifest(&self) -> &DigestManifest {
+        &self.manifest
+    }
+
+    /// Record the location of each data chunk in our `index`.  See
+    /// `write::SnappyFramedEncoder::set_build_index`.
+    pub fn set_build_index(&mut self, enable: bool) {
+        self.index = if enable {
+            Some(self.index.take().unwrap_or_default())
+        } else {
+            None
+        };
+    }
+
+    /// The index built since `set_build_index` was enabled, if it is.
+    pub fn index(&self) -> Option<&ChunkIndex> {
+        self.index.as_ref()
+    }
+
+    /// What we've encoded so far.  `bytes_written` includes output which
+    /// hasn't been taken yet.
+    pub fn status(&self) -> EncoderStatus {
+        self.status
+    }
+
+    /// Time spent compressing and checksumming data so far.
+    #[cfg(feature = "timing")]
+    pub fn timings(&self) -> Timings {
+        self.timings
+    }
+
+    /// Compress `data`.  Each time we've received a full chunk of input,
+    /// we compress it and add it to our output.
+    pub fn write_input(&mut self, data: &[u8]) {
+        let mut rest = data;
+        while !rest.is_empty() {
+            let taken = self.take_input(rest);
+            rest = &rest[taken..];
+        }
+        self.observe_write(data.len());
+    }
+
+    /// Take as much of `data` as fits in the current chunk, compress the
+    /// chunk if that fills it, and return how much we took.  This lets
+    /// callers write our output before giving us more.
+    pub(crate) fn take_input(&mut self, data: &[u8]) -> usize {
+        let chunk_size = self.chunk_sizer.chunk_size();
+        if self.pending.is_empty() && data.len() >= chunk_size {
+            // Compress full chunks straight from the caller's buffer.
+            self.write_data(&data[..chunk_size]);
+            return chunk_size;
+        }
+        let wanted = chunk_size.saturating_sub(self.pending.len());
+        let taken = min(wanted, data.len());
+        self.pending.extend_from_slice(&data[..taken]);
+        if self.pending.len() >= chunk_size { self.flush(); }
+        taken
+    }
+
+    /// Record that the caller wrote `len` bytes at once, for adaptive
+    /// chunk sizing.
+    pub(crate) fn observe_write(&mut self, len: usize) {
+        self.chunk_sizer.observe(len);
+    }
+
+    /// Compress any input which doesn't fill a whole chunk, so that our
+    /// output contains everything written so far.
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() { return; }
+        let pending = mem::replace(&mut self.pending, vec!());
+        self.write_data(&pending);
+        self.pending = pending;
+        self.pending.clear();
+    }
+
+    /// Write a skippable chunk of type `chunk_type` containing `data`,
+    /// after compressing any buffered input.  `chunk_type` must be between
+    /// `0x80` and `0xFD`, and `data` must fit in a chunk.
+    pub fn write_skippable_chunk(&mut self, chunk_type: u8, data: &[u8]) ->
+        Result<(), SnappyFramedError>
+    {
+        if chunk_type < 0x80 || chunk_type > 0xFD {
+            return Err(SnappyFramedError::InvalidInput(
+                "Snappy skippable chunk types must be between 0x80 and 0xFD"));
+        }
+        if data.len() > MAX_CHUNK_LEN {
+            return Err(SnappyFramedError::InvalidInput(
+                "Snappy skippable chunk too large"));
+        }
+        self.flush();
+        self.align_chunk(HEADER_SIZE + data.len());
+        self.append_chunk(&header(chunk_type, data.len()), data);
+        Ok(())
+    }
+
+    /// Begin the stream.
+    pub(crate) fn write_stream_identifier(&mut self) {
+        self.append_chunk(&STREAM_IDENTIFIER, &[]);
+    }
+
+    /// Measure the write rate using `clock`, for adaptive chunk sizing.
+    #[cfg(test)]
+    pub(crate) fn set_clock(&mut self, clock: fn() -> Instant) {
+        self.chunk_sizer.set_clock(clock);
+    }
+
+    /// How much input we compress as a single chunk.
+    pub fn chunk_size(&self) -> usize { self.chunk_sizer.chunk_size() }
+
+    /// The number of bytes of input which haven't been compressed yet.
+    pub fn buffered_len(&self) -> usize { self.pending.len() }
+
+    /// The number of bytes currently allocated for our buffers.
+    pub fn memory_usage(&self) -> usize {
+        self.pending.capacity() + self.output.capacity()
+    }
+
+    /// Does our output contain anything?
+    pub fn has_output(&self) -> bool { !self.output.is_empty() }
+
+    /// Our compressed output so far, without taking it.
+    pub fn output(&self) -> &[u8] { &self.output }
+
+    /// Discard the first `len` bytes of our output, once the caller has
+    /// written them.
+    pub fn consume_output(&mut self, len: usize) {
+        self.output.drain(..len);
+    }
+
+    /// Take our compressed output so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.output, vec!())
+    }
+
+    /// Write `data` as one chunk, or as several if that would exceed our
+    /// maximum frame size.
+    fn write_data(&mut self, data: &[u8]) {
+        match self.max_frame_size {
+            None => self.write_chunk(data),
+            Some(max_frame_size) => {
+                let mut remaining = data;
+                while !remaining.is_empty() {
+                    let written =
+                        self.write_bounded_chunk(remaining, max_frame_size);
+                    remaining = &remaining[written..];
+                }
+            }
+        }
+    }
+
+    /// Write `data` as a single chunk.
+    fn write_chunk(&mut self, data: &[u8]) {
+        let compressed = timed!(self.timings.compression,
+                                self.raw_codec.compress(data));
+        if self.worth_compressing(compressed.len(), data.len()) {
+            self.write_data_chunk(0x00, &compressed, data)
+        } else {
+            self.write_data_chunk(0x01, data, data)
+        }
+    }
+
+    /// Does compressing `len` bytes to `compressed_len` bytes save enough
+    /// to meet our `min_savings_percent`?  By default, it's enough for the
+    /// compressed data to be smaller, as the spec recommends.
+    fn worth_compressing(&self, compressed_len: usize, len: usize) -> bool {
+        match self.min_savings_percent {
+            None => compressed_len < len,
+            Some(percent) => {
+                compressed_len as u64 * 100 <=
+                    len as u64 * (100 - percent as u64)
+            }
+        }
+    }
+
+    /// Write as much of `data` as we can fit into a single chunk of at
+    /// most `max_frame_size` bytes, and return the number of bytes
+    /// written.
+    fn write_bounded_chunk(&mut self, data: &[u8], max_frame_size: usize) ->
+        usize
+    {
+        let max_payload = max_frame_size - frame_overhead();
+        let mut len = data.len();
+        loop {
+            let compressed = timed!(self.timings.compression,
+                                    self.raw_codec.compress(&data[..len]));
+            let worth_it = self.worth_compressing(compressed.len(), len);
+            let must_compress = len > max_payload;
+            if compressed.len() <= max_payload && (worth_it || must_compress) {
+                self.write_data_chunk(0x00, &compressed, &data[..len]);
+                return len;
+            } else if len <= max_payload {
+                self.write_data_chunk(0x01, &data[..len], &data[..len]);
+                return len;
+            }
+            // Guess how much input will fit, assuming the compression
+            // ratio stays about the same, but always make progress towards
+            // a length we can store uncompressed.
+            let estimate = (len as u64 * max_payload as u64 * 9 /
+                            (compressed.len() as u64 * 10)) as usize;
+            len = min(max(estimate, max_payload), len - 1);
+        }
+    }
+
+    /// Write `payload` as a chunk of type `chunk_type`, containing the
+    /// uncompressed `data`.
+    fn write_data_chunk(&mut self, chunk_type: u8, payload: &[u8],
+                        data: &[u8]) {
+        let mut crc = timed!(self.timings.crc, masked_crc(data));
+        if self.swapped_crcs { crc = crc.swap_bytes(); }
+        let header_and_crc = data_header(chunk_type, payload.len(), crc);
+        // Keep any metadata chunks together with the chunk they describe.
+        let mut len = header_and_crc.len() + payload.len();
+        if self.sequence_numbers { len += HEADER_SIZE + 8; }
+        if self.payload_checksums { len += HEADER_SIZE + CRC_SIZE; }
+        self.align_chunk(len);
+        if self.sequence_numbers {
+            let mut sequence = vec!();
+            push_u64(&mut sequence, self.status.frames_written);
+            let sequence_header = header(SEQUENCE_CHUNK_TYPE, sequence.len());
+            self.append_chunk(&sequence_header, &sequence);
+        }
+        if self.payload_checksums {
+            let mut hasher = MaskedCrcHasher::new();
+            hasher.update(&header_and_crc[HEADER_SIZE..]);
+            hasher.update(payload);
+            let crc = hasher.finalize();
+            let checksum = [crc as u8, (crc >> 8) as u8, (crc >> 16) as u8,
+                            (crc >> 24) as u8];
+            let checksum_header = header(PAYLOAD_CRC_CHUNK_TYPE,
+                                         checksum.len());
+            self.append_chunk(&checksum_header, &checksum);
+        }
+        self.append_chunk(&header_and_crc, payload);
+        if let Some(ref mut index) = self.index {
+            if !data.is_empty() {
+                let chunk_len = header_and_crc.len() + payload.len();
+                index.entries.push(IndexEntry{
+                    compressed_offset:
+                        self.status.bytes_written - chunk_len as u64,
+                    uncompressed_offset: self.status.bytes_consumed,
+                    uncompressed_len: data.len() as u64
+                });
+            }
+        }
+        if let Some(ref mut hasher) = self.frame_hasher {
+            self.manifest.entries.push(ManifestEntry{
+                uncompressed_offset: self.status.bytes_consumed,
+                uncompressed_len: data.len() as u64,
+                digest: hasher.digest(data)
+            });
+        }
+        self.status.frames_written += 1;
+        self.status.bytes_consumed += data.len() as u64;
+    }
+
+    /// If we're aligning chunks, write any padding needed before `len`
+    /// bytes of chunks.  We need to either fill the space before the next
+    /// boundary exactly, or leave enough room for more padding.
+    fn align_chunk(&mut self, len: usize) {
+        let alignment = match self.alignment {
+            Some(alignment) => alignment,
+            None => return
+        };
+        let offset = (self.status.bytes_written % alignment as u64) as usize;
+        if offset == 0 { return; }
+        let space = alignment - offset;
+        if len == space || len + HEADER_SIZE <= space { return; }
+        // Padding can't be smaller than a header, so if there isn't room
+        // for one, pad out to the boundary after next.
+        let gap = if space >= HEADER_SIZE { space } else { space + alignment };
+        let mut padding = vec!();
+        append_padding_chunk(gap, &mut padding);
+        self.append_chunk(&padding, &[])
+    }
+
+    /// Add a chunk's header and body to our output.
+    fn append_chunk(&mut self, header: &[u8], body: &[u8]) {
+        self.output.extend_from_slice(header);
+        self.output.extend_from_slice(body);
+        self.status.bytes_written += (header.len() + body.len()) as u64;
+    }
+}
+
+impl Default for ChunkEncoder {
+    fn default() -> ChunkEncoder { ChunkEncoder::new() }
+}
+
+#[test]
+fn sans_io_round_trip() {
+    use std::io::Write;
+    use test_helpers::*;
+    use write;
+
+    let input = mixed_data(100_000);
+
+    let mut expected = write::SnappyFramedEncoder::new(vec!()).unwrap();
+    expected.write_all(&input).unwrap();
+    let expected = expected.finish().unwrap();
+
+    let mut encoder = ChunkEncoder::new();
+    let mut compressed = vec!();
+    for piece in input.chunks(7_000) {
+        encoder.write_input(piece);
+        compressed.extend(encoder.take_output());
+    }
+    encoder.flush();
+    compressed.extend(encoder.take_output());
+    assert_eq!(expected, compressed);
+
+    let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+    let mut output = vec!();
+    for piece in compressed.chunks(1000) {
+        decoder.push_input(piece);
+        while let Some(event) = decoder.next_event().unwrap() {
+            if let DecoderEvent::Data(data) = event {
+                output.extend(data);
+            }
+        }
+    }
+    decoder.finish().unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn sans_io_decoder_errors() {
+    let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+    decoder.push_input(&STREAM_IDENTIFIER[..6]);
+    assert_eq!(None, decoder.next_event().unwrap());
+    assert_eq!(4, decoder.wanted());
+    match decoder.finish() {
+        Err(SnappyFramedError::Truncated) => {}
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+    decoder.push_input(&[0x01, 0xFF, 0xFF, 0x01]);
+    match decoder.next_event() {
+        Err(SnappyFramedError::ChunkTooLarge) => {}
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn sans_io_options() {
+    let mut encoder = ChunkEncoder::new();
+    encoder.set_sequence_numbers(true);
+    encoder.write_input(b"first");
+    encoder.flush();
+    let mut compressed = encoder.take_output();
+    let end_of_first = compressed.len();
+    encoder.write_input(b"second");
+    encoder.flush();
+    encoder.take_output();
+    encoder.write_input(b"third");
+    encoder.flush();
+    compressed.extend(encoder.take_output());
+    compressed.extend_from_slice(b"junk");
+
+    let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+    decoder.set_check_sequence_numbers(true);
+    decoder.push_input(&compressed);
+    while decoder.input_bytes() < end_of_first as u64 {
+        decoder.next_event().unwrap();
+    }
+    match decoder.next_event() {
+        Err(SnappyFramedError::MissingFrames{expected: 1, found: 2}) => {}
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    let mut decoder = ChunkDecoder::new(CrcMode::Verify);
+    decoder.set_detect_trailing_junk(true);
+    decoder.push_input(&compressed);
+    while decoder.buffered_len() > 4 {
+        decoder.next_event().unwrap();
+    }
+    match decoder.finish() {
+        Err(SnappyFramedError::TrailingJunk{len: 4, ..}) => {}
+        other => panic!("unexpected result: {:?}", other)
+    }
+    assert_eq!(b"junk", decoder.trailing_junk());
+}
@@ -0,0 +1,952 @@
+//! Random access to Snappy framed streams, using an index of where each
+//! data chunk's contents appear in the uncompressed stream.
+//!
+//! Building the index requires reading the whole stream once, but only
+//! the chunk headers and the start of each data chunk need to be parsed.
+//! After that, any range of the uncompressed data can be read by
+//! decompressing just the chunks which contain it.
+//!
+//! ```
+//! use std::io::{Cursor, Read, Write};
+//! use snappy_framed::read::CrcMode;
+//! use snappy_framed::seek::SeekableDecoder;
+//! use snappy_framed::write::SnappyFramedEncoder;
+//!
+//! let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+//! encoder.write_all(b"Hello, world!").unwrap();
+//! let compressed = encoder.finish().unwrap();
+//!
+//! let mut decoder =
+//!     SeekableDecoder::new(Cursor::new(compressed), CrcMode::Verify)
+//!     .unwrap();
+//! decoder.seek_to_uncompressed_offset(7).unwrap();
+//! let mut output = vec!();
+//! decoder.read_to_end(&mut output).unwrap();
+//! assert_eq!(b"world!" as &[u8], &output as &[u8]);
+//! ```
+//!
+//! ### Index files
+//!
+//! An index can be saved to a sidecar file (conventionally with a `.szi`
+//! extension) using `ChunkIndex::write_to`, and loaded again using
+//! `ChunkIndex::read_from`, so that a stream only needs to be indexed
+//! once.  Encoders can also build an index as they go; see
+//! `SnappyFramedEncoder::set_build_index`.  The format is:
+//!
+//! - The 8 bytes of `INDEX_MAGIC`.
+//! - The format version, currently `INDEX_VERSION`, as a little-endian
+//!   `u64`.
+//! - The number of entries, as a little-endian `u64`.
+//! - For each entry, its `compressed_offset`, `uncompressed_offset` and
+//!   `uncompressed_len`, each as a little-endian `u64`.
+//! - The masked CRC-32C of everything above, as a little-endian `u32`.
+//!
+//! ### Concurrent access
+//!
+//! `SeekableDecoder` needs `&mut` access to its source.  For servers
+//! which want several threads reading from the same file at once,
+//! `PositionalDecoder` reads using `ReadAt` instead, so it can be shared
+//! between threads.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+
+use buffer::copy_output;
+use chunk::*;
+use consts::*;
+use error::SnappyFramedError;
+use masked_crc::masked_crc;
+use read::CrcMode;
+use sans_io::{ChunkDecoder, DecoderEvent};
+
+/// Appears at the front of all index files.
+pub const INDEX_MAGIC: [u8; 8] = *b"sNaPpYiX";
+
+/// The version of the index file format written by `ChunkIndex::write_to`.
+pub const INDEX_VERSION: u64 = 1;
+
+/// The size of an index file's header, and of each entry.
+const INDEX_HEADER_SIZE: usize = 24;
+const INDEX_ENTRY_SIZE: usize = 24;
+
+fn mismatch() -> io::Error {
+    SnappyFramedError::Corrupt("Snappy index does not match stream").into()
+}
+
+fn malformed_index() -> io::Error {
+    SnappyFramedError::Corrupt("Malformed Snappy index file").into()
+}
+
+/// The location of a single data chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IndexEntry {
+    /// The offset of the chunk's header in the compressed stream.
+    pub compressed_offset: u64,
+    /// The offset of the chunk's data in the uncompressed stream.
+    pub uncompressed_offset: u64,
+    /// The length of the chunk's uncompressed data.
+    pub uncompressed_len: u64
+}
+
+/// The locations of all the data chunks in a stream, in order.  Chunks
+/// containing no data are left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ChunkIndex {
+    /// One entry for each data chunk.
+    pub entries: Vec<IndexEntry>
+}
+
+impl ChunkIndex {
+    /// Create an empty index.
+    pub fn new() -> ChunkIndex {
+        ChunkIndex::default()
+    }
+
+    /// Build an index by reading the stream in `source` from its current
+    /// position to the end.  Compressed offsets are relative to where we
+    /// started.  Nothing is decompressed and CRCs aren't checked, so
+    /// corrupt chunks won't be detected until they're read.
+    pub fn scan<R: Read>(source: R) -> io::Result<ChunkIndex> {
+        ChunkIndex::scan_with(source, &mut ChunkDecoder::new(CrcMode::Verify))
+    }
+
+    /// Like `scan`, but check the stream's framing using `state`, so
+    /// that any limits and checks it's configured with apply.
+    pub fn scan_with<R: Read>(mut source: R, state: &mut ChunkDecoder) ->
+        io::Result<ChunkIndex>
+    {
+        let mut index = ChunkIndex::new();
+        let mut buf = vec!();
+        loop {
+            let (chunk_type, data) = match try!(state.next_data_chunk()) {
+                Some(chunk) => chunk,
+                None => {
+                    if try!(state.read_from(&mut source, &mut buf)) {
+                        continue;
+                    }
+                    try!(state.finish());
+                    return Ok(index);
+                }
+            };
+            let len = match chunk_type {
+                0x00 if data.len() > CRC_SIZE => {
+                    try!(uncompressed_len(&data[CRC_SIZE..])
+                         .ok_or(SnappyFramedError::Corrupt(
+                             "Corrupt Snappy chunk")))
+                }
+                _ if data.len() >= CRC_SIZE => data.len() - CRC_SIZE,
+                _ => {
+                    return Err(SnappyFramedError::Corrupt(
+                        "Snappy CRC truncated").into());
+                }
+            };
+            if len > state.max_chunk_size() {
+                return Err(SnappyFramedError::ChunkTooLarge.into());
+            }
+            if len > 0 {
+                let uncompressed_offset = index.uncompressed_len();
+                index.entries.push(IndexEntry{
+                    compressed_offset: state.input_bytes() -
+                        (HEADER_SIZE + data.len()) as u64,
+                    uncompressed_offset: uncompressed_offset,
+                    uncompressed_len: len as u64
+                });
+            }
+        }
+    }
+
+    /// The total length of the uncompressed stream.
+    pub fn uncompressed_len(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| {
+            entry.uncompressed_offset + entry.uncompressed_len
+        })
+    }
+
+    /// Write this index to `dest`, in the format described in the module
+    /// documentation.
+    pub fn write_to<W: Write>(&self, mut dest: W) -> io::Result<()> {
+        let mut encoded = Vec::with_capacity(
+            INDEX_HEADER_SIZE + self.entries.len() * INDEX_ENTRY_SIZE +
+                CRC_SIZE);
+        encoded.extend(INDEX_MAGIC.iter().cloned());
+        push_u64(&mut encoded, INDEX_VERSION);
+        push_u64(&mut encoded, self.entries.len() as u64);
+        for entry in &self.entries {
+            push_u64(&mut encoded, entry.compressed_offset);
+            push_u64(&mut encoded, entry.uncompressed_offset);
+            push_u64(&mut encoded, entry.uncompressed_len);
+        }
+        let crc = masked_crc(&encoded);
+        encoded.extend([crc as u8, (crc >> 8) as u8, (crc >> 16) as u8,
+                        (crc >> 24) as u8].iter().cloned());
+        dest.write_all(&encoded)
+    }
+
+    /// Read an index written by `write_to` from `source`.  We check that
+    /// the entries describe consecutive ranges of uncompressed data,
+    /// starting at 0, but not that they match any particular stream.
+    pub fn read_from<R: Read>(mut source: R) -> io::Result<ChunkIndex> {
+        let mut encoded = vec!();
+        try!(source.read_to_end(&mut encoded));
+        if encoded.len() < INDEX_HEADER_SIZE + CRC_SIZE ||
+            encoded[..INDEX_MAGIC.len()] != INDEX_MAGIC
+        {
+            return Err(malformed_index());
+        }
+        let (body, crc) = encoded.split_at(encoded.len() - CRC_SIZE);
+        let crc = crc.iter().rev().fold(0, |acc, &b| acc << 8 | b as u32);
+        if masked_crc(body) != crc {
+            return Err(malformed_index());
+        }
+        if read_u64(&body[8..16]) != INDEX_VERSION {
+            return Err(SnappyFramedError::Corrupt(
+                "Unsupported Snappy index file version").into());
+        }
+        let count = read_u64(&body[16..24]);
+        let entries = &body[INDEX_HEADER_SIZE..];
+        if count != (entries.len() / INDEX_ENTRY_SIZE) as u64 ||
+            entries.len() % INDEX_ENTRY_SIZE != 0
+        {
+            return Err(malformed_index());
+        }
+
+        let mut index = ChunkIndex::new();
+        for entry in entries.chunks(INDEX_ENTRY_SIZE) {
+            let entry = IndexEntry{
+                compressed_offset: read_u64(&entry[0..8]),
+                uncompressed_offset: read_u64(&entry[8..16]),
+                uncompressed_len: read_u64(&entry[16..24])
+            };
+            // The first entry must start at 0, and the rest must follow
+            // on from the one before.
+            let consecutive =
+                entry.uncompressed_offset == index.uncompressed_len() &&
+                index.entries.last().map_or(true, |last| {
+                    entry.compressed_offset > last.compressed_offset
+                });
+            if !consecutive || entry.uncompressed_len == 0 {
+                return Err(malformed_index());
+            }
+            index.entries.push(entry);
+        }
+        Ok(index)
+    }
+
+    /// Find the position in `entries` of the chunk containing
+    /// `uncompressed_offset`, if any.
+    pub fn find(&self, uncompressed_offset: u64) -> Option<usize> {
+        let found = self.entries.binary_search_by(|entry| {
+            entry.uncompressed_offset.cmp(&uncompressed_offset)
+        });
+        match found {
+            Ok(i) => Some(i),
+            Err(0) => None,
+            Err(i) => {
+                let entry = &self.entries[i - 1];
+                let end = entry.uncompressed_offset + entry.uncompressed_len;
+                if uncompressed_offset < end { Some(i - 1) } else { None }
+            }
+        }
+    }
+}
+
+/// Decode any part of a Snappy framed stream stored in a seekable
+/// `source`, such as a file.
+pub struct SeekableDecoder<R: Read + Seek> {
+    source: R,
+    index: ChunkIndex,
+    /// Decodes each chunk we load.
+    state: ChunkDecoder,
+    /// The position in our index of the chunk in `output`, if any.
+    current: Option<usize>,
+    /// Where the next read from `source` will start, if we know.
+    source_pos: Option<u64>,
+    /// Scratch space for reading from `source`.
+    input: Vec<u8>,
+    /// The decompressed data from chunk `current`.
+    output: Vec<u8>,
+    /// Other recently used chunks and their data, most recent first.
+    cache: VecDeque<(usize, Vec<u8>)>,
+    cache_capacity: usize,
+    /// Our position in the uncompressed stream.
+    pos: u64
+}
+
+impl<R: Read + Seek> SeekableDecoder<R> {
+    /// Index the whole of `source`, and prepare to decode it using the
+    /// CRC verification options indicated by `mode`.
+    pub fn new(source: R, mode: CrcMode) -> io::Result<Self> {
+        SeekableDecoder::with_chunk_decoder(source, ChunkDecoder::new(mode))
+    }
+
+    /// Index the whole of `source`, and prepare to decode it, using
+    /// `state` to check the stream and decode each chunk.
+    pub fn with_chunk_decoder(mut source: R, mut state: ChunkDecoder) ->
+        io::Result<Self>
+    {
+        try!(source.seek(SeekFrom::Start(0)));
+        let index = try!(ChunkIndex::scan_with(&mut source, &mut state));
+        let mut decoder = SeekableDecoder::with_index(source,
+                                                      CrcMode::Verify, index);
+        decoder.state = state;
+        Ok(decoder)
+    }
+
+    /// Decode `source` using an `index` built earlier.  Compressed
+    /// offsets in `index` must be relative to the start of `source`.
+    pub fn with_index(source: R, mode: CrcMode, index: ChunkIndex) -> Self {
+        SeekableDecoder{
+            source: source,
+            index: index,
+            state: ChunkDecoder::new(mode),
+            current: None,
+            source_pos: None,
+            input: vec!(),
+            output: vec!(),
+            cache: VecDeque::new(),
+            cache_capacity: 0,
+            pos: 0
+        }
+    }
+
+    /// Get our index.
+    pub fn index(&self) -> &ChunkIndex {
+        &self.index
+    }
+
+    /// The state machine which decodes each chunk.  Use this to set any
+    /// of the decoding options it supports.
+    pub fn chunk_decoder_mut(&mut self) -> &mut ChunkDecoder {
+        &mut self.state
+    }
+
+    /// Keep the decompressed data of up to `chunks` recently read chunks,
+    /// besides the one we're reading, so that reading them again doesn't
+    /// mean decompressing them again.  This helps when many small reads
+    /// jump around the same region of the stream.  The least recently
+    /// used chunk is discarded first.  Defaults to 0.
+    pub fn set_cache_capacity(&mut self, chunks: usize) {
+        self.cache_capacity = chunks;
+        self.cache.truncate(chunks);
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    /// Get a mutable reference to our source.  We'll seek it back into
+    /// position before reading from it again.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.source_pos = None;
+        &mut self.source
+    }
+
+    /// Unwrap this decoder, returning our source.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Our position in the uncompressed stream.
+    pub fn uncompressed_offset(&self) -> u64 {
+        self.pos
+    }
+
+    /// Move to `offset` in the uncompressed stream.  Nothing is read
+    /// until the next call to `read`, and seeking past the end of the
+    /// stream is allowed, in which case `read` returns no data.
+    pub fn seek_to_uncompressed_offset(&mut self, offset: u64) ->
+        io::Result<()>
+    {
+        self.pos = offset;
+        Ok(())
+    }
+
+    /// Make the chunk at position `i` in our index current, taking it
+    /// from our cache if we can, and caching the chunk it replaces.
+    fn select_chunk(&mut self, i: usize) -> io::Result<()> {
+        let cached = self.cache.iter().position(|&(cached, _)| cached == i)
+            .map(|found| self.cache.remove(found).expect("chunk is cached"));
+        if let Some(current) = self.current.take() {
+            if self.cache_capacity > 0 {
+                let output = mem::replace(&mut self.output, vec!());
+                self.cache.push_front((current, output));
+                self.cache.truncate(self.cache_capacity);
+            }
+        }
+        match cached {
+            Some((_, output)) => {
+                self.output = output;
+                self.current = Some(i);
+                Ok(())
+            }
+            None => self.load_chunk(i)
+        }
+    }
+
+    /// Load and decompress the chunk at position `i` in our index.
+    fn load_chunk(&mut self, i: usize) -> io::Result<()> {
+        let entry = self.index.entries[i];
+        self.current = None;
+        // If we fail part way through, we won't know where `source` is.
+        let source_pos = self.source_pos.take();
+        if source_pos != Some(entry.compressed_offset) {
+            try!(self.source.seek(SeekFrom::Start(entry.compressed_offset)));
+        }
+        self.state.restart_at(entry.compressed_offset);
+        loop {
+            match try!(self.state.next_event()) {
+                Some(DecoderEvent::Data(data)) => {
+                    self.output = data;
+                    break;
+                }
+                Some(_) => return Err(mismatch()),
+                None => {}
+            }
+            if !try!(self.state.read_from(&mut self.source, &mut self.input)) {
+                try!(self.state.finish());
+                return Err(SnappyFramedError::Truncated.into());
+            }
+        }
+        self.source_pos = Some(self.state.input_bytes());
+
+        if self.output.len() as u64 != entry.uncompressed_len {
+            return Err(mismatch());
+        }
+        self.current = Some(i);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let i = match self.index.find(self.pos) {
+            Some(i) => i,
+            None => return Ok(0)
+        };
+        if self.current != Some(i) { try!(self.select_chunk(i)); }
+        let mut start = (self.pos - self.index.entries[i].uncompressed_offset)
+            as usize;
+        let copied = copy_output(&self.output, &mut start, buf);
+        self.pos += copied as u64;
+        Ok(copied)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecoder<R> {
+    /// Seek within the uncompressed stream.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                try!(self.seek_to_uncompressed_offset(offset));
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.index.uncompressed_len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset)
+        };
+        let target = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match target {
+            Some(target) => {
+                try!(self.seek_to_uncompressed_offset(target));
+                Ok(target)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+/// A source which can be read at any offset without changing any shared
+/// state, like Unix's `pread`.  Implementations must allow concurrent
+/// calls from several threads.
+pub trait ReadAt {
+    /// Read up to `buf.len()` bytes starting at `offset`, returning the
+    /// number of bytes read.  This returns 0 only at the end of the
+    /// source (or if `buf` is empty).
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+}
+
+#[cfg(unix)]
+impl ReadAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        ::std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+}
+
+#[cfg(windows)]
+impl ReadAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        // This moves the file pointer, but doesn't depend on it.
+        ::std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len() as u64 { return Ok(0); }
+        let mut pos = offset as usize;
+        Ok(copy_output(self, &mut pos, buf))
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (self as &[u8]).read_at(buf, offset)
+    }
+}
+
+impl<'a, T: ReadAt + ?Sized> ReadAt for &'a T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+}
+
+/// Fill `buf` from `source`, starting at `offset`.
+fn read_exact_at<S: ReadAt + ?Sized>(source: &S, mut buf: &mut [u8],
+                                     mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match source.read_at(buf, offset) {
+            Ok(0) => return Err(SnappyFramedError::Truncated.into()),
+            Ok(n) => {
+                let rest = buf;
+                buf = &mut rest[n..];
+                offset += n as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+/// Decode any part of an indexed Snappy framed stream using positional
+/// reads, so that many threads can read from the same decoder at once.
+/// Each call decompresses the chunks it needs into fresh buffers.
+///
+/// ```
+/// use std::io::Write;
+/// use std::sync::Arc;
+/// use std::thread;
+/// use snappy_framed::read::CrcMode;
+/// use snappy_framed::seek::{ChunkIndex, PositionalDecoder, ReadAt};
+/// use snappy_framed::write::SnappyFramedEncoder;
+///
+/// let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+/// encoder.write_all(b"Hello, world!").unwrap();
+/// let compressed = encoder.finish().unwrap();
+///
+/// let index = ChunkIndex::scan(&compressed as &[u8]).unwrap();
+/// let decoder =
+///     Arc::new(PositionalDecoder::new(compressed, CrcMode::Verify, index));
+/// let threads: Vec<_> = (0..2).map(|i| {
+///     let decoder = decoder.clone();
+///     thread::spawn(move || {
+///         let mut buf = [0; 5];
+///         decoder.read_exact_at(&mut buf, i * 7).unwrap();
+///         buf
+///     })
+/// }).collect();
+/// let words: Vec<_> =
+///     threads.into_iter().map(|t| t.join().unwrap()).collect();
+/// assert_eq!(vec!(*b"Hello", *b"world"), words);
+/// ```
+pub struct PositionalDecoder<S: ReadAt> {
+    source: S,
+    /// Holds the options used to decode each chunk.
+    state: ChunkDecoder,
+    index: ChunkIndex
+}
+
+impl<S: ReadAt> PositionalDecoder<S> {
+    /// Decode `source` using `index`, verifying CRCs according to `mode`.
+    /// Compressed offsets in `index` must be relative to the start of
+    /// `source`.
+    pub fn new(source: S, mode: CrcMode, index: ChunkIndex) -> Self {
+        PositionalDecoder{
+            source: source,
+            state: ChunkDecoder::new(mode),
+            index: index
+        }
+    }
+
+    /// Get our index.
+    pub fn index(&self) -> &ChunkIndex {
+        &self.index
+    }
+
+    /// The state machine whose CRC mode and chunk size limit are used to
+    /// decode each chunk.  Data chunks are always decompressed using the
+    /// default raw codec.
+    pub fn chunk_decoder_mut(&mut self) -> &mut ChunkDecoder {
+        &mut self.state
+    }
+
+    /// Get a reference to our source.
+    pub fn get_ref(&self) -> &S {
+        &self.source
+    }
+
+    /// Unwrap this decoder, returning our source.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// Decompress the chunk at position `i` in our index.
+    fn decode_chunk_at(&self, i: usize) -> io::Result<Vec<u8>> {
+        let entry = self.index.entries[i];
+        let mut header = [0; HEADER_SIZE];
+        try!(read_exact_at(&self.source, &mut header,
+                           entry.compressed_offset));
+        let (chunk_type, chunk_len) = parse_header(&header);
+        if chunk_type > 0x01 { return Err(mismatch()); }
+        let mut state = self.state.data_decoder();
+        try!(state.check_header(chunk_type, chunk_len));
+        let mut input = vec![0; chunk_len];
+        try!(read_exact_at(&self.source, &mut input,
+                           entry.compressed_offset + HEADER_SIZE as u64));
+
+        let output = try!(state.decode_data(&Chunk{
+            chunk_type: chunk_type,
+            data: &input
+        })).into_owned();
+        if output.len() as u64 != entry.uncompressed_len {
+            return Err(mismatch());
+        }
+        Ok(output)
+    }
+
+    /// Fill `buf` with uncompressed data starting at `offset`, failing if
+    /// the stream ends first.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) ->
+        io::Result<()>
+    {
+        read_exact_at(self, buf, offset)
+    }
+}
+
+impl<S: ReadAt> ReadAt for PositionalDecoder<S> {
+    /// Read uncompressed data starting at `offset`.  We fill as much of
+    /// `buf` as we can, decompressing several chunks if necessary.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let pos = offset + filled as u64;
+            let i = match self.index.find(pos) {
+                Some(i) => i,
+                None => break
+            };
+            let data = try!(self.decode_chunk_at(i));
+            let start = (pos - self.index.entries[i].uncompressed_offset)
+                as usize;
+            let to_copy = min(data.len() - start, buf.len() - filled);
+            buf[filled..filled + to_copy]
+                .copy_from_slice(&data[start..start + to_copy]);
+            filled += to_copy;
+        }
+        Ok(filled)
+    }
+}
+
+#[test]
+fn read_ranges_using_index() {
+    use std::io::{Cursor, Write};
+
+    use compression::Compression;
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    let input = mixed_data(20_000);
+    let compression = Compression::default().with_chunk_size(1000);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.set_sequence_numbers(true);
+    encoder.write_all(&input).unwrap();
+    encoder.write_skippable_chunk(0x90, b"metadata").unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut decoder =
+        SeekableDecoder::new(Cursor::new(&compressed), CrcMode::Verify)
+        .unwrap();
+    assert_eq!(46, decoder.index().entries.len());
+    assert_eq!(input.len() as u64, decoder.index().uncompressed_len());
+
+    for &(start, len) in &[(0, 10), (999, 2), (12_345, 5000),
+                           (input.len() - 3, 3)] {
+        decoder.seek_to_uncompressed_offset(start as u64).unwrap();
+        let mut output = vec!();
+        (&mut decoder).take(len as u64).read_to_end(&mut output).unwrap();
+        assert_eq!(&input[start..start + len], &output as &[u8]);
+    }
+
+    // Seek relative to the end, and past it.
+    let end = input.len() as u64;
+    assert_eq!(end - 5, decoder.seek(SeekFrom::End(-5)).unwrap());
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(&input[input.len() - 5..], &output as &[u8]);
+    decoder.seek(SeekFrom::Current(10)).unwrap();
+    assert_eq!(0, decoder.read(&mut [0; 10]).unwrap());
+    assert!(decoder.seek(SeekFrom::Current(-1_000_000)).is_err());
+
+    // Read everything in order.
+    decoder.seek(SeekFrom::Start(0)).unwrap();
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn index_file_round_trip() {
+    use std::io::{Cursor, Write};
+
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    let input = random_data(200_000);
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.set_build_index(true);
+    encoder.set_payload_checksums(true);
+    encoder.write_all(&input).unwrap();
+    encoder.flush().unwrap();
+    let index = encoder.index().unwrap().clone();
+    let compressed = encoder.finish().unwrap();
+
+    // Building the index while encoding is the same as scanning.
+    assert_eq!(4, index.entries.len());
+    assert_eq!(index, ChunkIndex::scan(&compressed as &[u8]).unwrap());
+
+    let mut file = vec!();
+    index.write_to(&mut file).unwrap();
+    assert_eq!(INDEX_HEADER_SIZE + 4 * INDEX_ENTRY_SIZE + CRC_SIZE,
+               file.len());
+    let loaded = ChunkIndex::read_from(&file as &[u8]).unwrap();
+    assert_eq!(index, loaded);
+
+    let mut decoder = SeekableDecoder::with_index(
+        Cursor::new(&compressed), CrcMode::Verify, loaded);
+    decoder.seek(SeekFrom::Start(150_000)).unwrap();
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(&input[150_000..], &output as &[u8]);
+
+    // Corruption and unknown versions are detected.
+    let mut corrupt = file.clone();
+    corrupt[30] ^= 1;
+    assert!(ChunkIndex::read_from(&corrupt as &[u8]).is_err());
+    assert!(ChunkIndex::read_from(&file[..file.len() - 1]).is_err());
+    let mut future = vec!();
+    ChunkIndex::new().write_to(&mut future).unwrap();
+    future[8] = 2;
+    let crc_at = future.len() - CRC_SIZE;
+    let crc = masked_crc(&future[..crc_at]);
+    future.truncate(crc_at);
+    future.extend([crc as u8, (crc >> 8) as u8, (crc >> 16) as u8,
+                   (crc >> 24) as u8].iter().cloned());
+    let err = ChunkIndex::read_from(&future as &[u8]).unwrap_err();
+    assert_eq!("Unsupported Snappy index file version", err.to_string());
+
+    // So are indexes which don't start at the beginning of the data.
+    let mut shifted = index.clone();
+    for entry in &mut shifted.entries {
+        entry.uncompressed_offset += 1000;
+    }
+    let mut file = vec!();
+    shifted.write_to(&mut file).unwrap();
+    let err = ChunkIndex::read_from(&file as &[u8]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+fn read_ranges_concurrently() {
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::thread;
+
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    let input = mixed_data(160_000);
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let index = ChunkIndex::scan(&compressed as &[u8]).unwrap();
+
+    let input = Arc::new(input);
+    let decoder =
+        Arc::new(PositionalDecoder::new(compressed, CrcMode::Verify, index));
+    let threads: Vec<_> = (0..8).map(|i| {
+        let (input, decoder) = (input.clone(), decoder.clone());
+        thread::spawn(move || {
+            // Ranges spanning several chunks, from different threads.
+            let start = i * 40_000;
+            let mut buf = vec![0; 70_000];
+            decoder.read_exact_at(&mut buf, start as u64).unwrap();
+            assert_eq!(&input[start..start + buf.len()], &buf as &[u8]);
+        })
+    }).collect();
+    for thread in threads { thread.join().unwrap(); }
+
+    // Short reads at the end of the stream.
+    let mut buf = [0; 100];
+    let end = input.len() as u64;
+    assert_eq!(10, decoder.read_at(&mut buf, end - 10).unwrap());
+    assert_eq!(0, decoder.read_at(&mut buf, end).unwrap());
+    assert!(decoder.read_exact_at(&mut buf, end - 10).is_err());
+}
+
+#[test]
+fn index_using_chunk_decoder_options() {
+    use std::io::{Cursor, Write};
+
+    use write::SnappyFramedEncoder;
+
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(b"Hello, world!").unwrap();
+    let compressed = encoder.finish().unwrap();
+    let headless = compressed[STREAM_IDENTIFIER.len()..].to_vec();
+
+    let mut state = ChunkDecoder::new(CrcMode::Verify);
+    state.set_require_stream_identifier(true);
+    let mut decoder =
+        SeekableDecoder::with_chunk_decoder(Cursor::new(compressed), state)
+        .unwrap();
+    decoder.seek_to_uncompressed_offset(7).unwrap();
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"world!" as &[u8], &output as &[u8]);
+
+    let mut state = ChunkDecoder::new(CrcMode::Verify);
+    state.set_require_stream_identifier(true);
+    assert!(SeekableDecoder::with_chunk_decoder(Cursor::new(headless), state)
+            .is_err());
+}
+
+#[test]
+fn retry_after_read_errors() {
+    use std::io::{Cursor, Write};
+
+    use compression::Compression;
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    /// Fails one read part way through the stream.
+    struct Unreliable {
+        inner: Cursor<Vec<u8>>,
+        fail_at: Option<u64>
+    }
+
+    impl Read for Unreliable {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if let Some(fail_at) = self.fail_at {
+                let pos = self.inner.position();
+                if pos + buf.len() as u64 > fail_at {
+                    self.fail_at = None;
+                    // Consume some input before failing.
+                    let partial = (fail_at - pos) as usize;
+                    try!(self.inner.read(&mut buf[..partial]));
+                    return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                              "unreliable"));
+                }
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Unreliable {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    let input = mixed_data(10_000);
+    let compression = Compression::default().with_chunk_size(1000);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let index = ChunkIndex::scan(&compressed as &[u8]).unwrap();
+
+    // Fail in the middle of the second chunk, after reading the first.
+    let fail_at = index.entries[1].compressed_offset + 10;
+    let source = Unreliable{
+        inner: Cursor::new(compressed),
+        fail_at: Some(fail_at)
+    };
+    let mut decoder = SeekableDecoder::with_index(source, CrcMode::Verify,
+                                                  index);
+    let mut buf = vec![0; 1000];
+    decoder.read_exact(&mut buf).unwrap();
+    assert!(decoder.read(&mut buf).is_err());
+    let mut output = buf;
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn cache_recently_read_chunks() {
+    use std::io::{Cursor, Write};
+
+    use compression::Compression;
+    use test_helpers::*;
+    use write::SnappyFramedEncoder;
+
+    /// Counts the bytes read from it.
+    struct Counting {
+        inner: Cursor<Vec<u8>>,
+        bytes_read: usize
+    }
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = try!(self.inner.read(buf));
+            self.bytes_read += n;
+            Ok(n)
+        }
+    }
+
+    impl Seek for Counting {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    let input = mixed_data(10_000);
+    let compression = Compression::default().with_chunk_size(1000);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let source = Counting{inner: Cursor::new(compressed), bytes_read: 0};
+    let mut decoder = SeekableDecoder::new(source, CrcMode::Verify).unwrap();
+    decoder.set_cache_capacity(2);
+
+    // Bounce between three chunks.  Once each has been read, we never
+    // need to read it again.
+    let mut buf = [0; 10];
+    let mut bytes_read = vec!();
+    for &offset in &[500, 2500, 4500, 500, 2500, 4500, 510] {
+        decoder.seek_to_uncompressed_offset(offset).unwrap();
+        decoder.read_exact(&mut buf).unwrap();
+        let offset = offset as usize;
+        assert_eq!(&input[offset..offset + 10], &buf);
+        bytes_read.push(decoder.get_ref().bytes_read);
+    }
+    assert!(bytes_read[0] < bytes_read[1] && bytes_read[1] < bytes_read[2]);
+    assert!(bytes_read[3..].iter().all(|&n| n == bytes_read[2]));
+
+    // With a smaller cache, the least recently used chunk is dropped.
+    decoder.set_cache_capacity(1);
+    decoder.seek_to_uncompressed_offset(4500).unwrap();
+    decoder.read_exact(&mut buf).unwrap();
+    assert_eq!(bytes_read[2], decoder.get_ref().bytes_read);
+    decoder.seek_to_uncompressed_offset(2500).unwrap();
+    decoder.read_exact(&mut buf).unwrap();
+    assert!(decoder.get_ref().bytes_read > bytes_read[2]);
+}
@@ -0,0 +1,149 @@
+//! Types with the same constructors and methods as the framed encoder and
+//! decoder in the `snap` crate, so that you can switch between the two
+//! crates by changing your imports:
+//!
+//! ```
+//! use std::io::{Read, Write};
+//! use snappy_framed::snap_compat::read::FrameDecoder;
+//! use snappy_framed::snap_compat::write::FrameEncoder;
+//!
+//! let mut encoder = FrameEncoder::new(vec!());
+//! encoder.write_all(b"Hello!").unwrap();
+//! let compressed = encoder.into_inner().unwrap();
+//!
+//! let mut output = vec!();
+//! FrameDecoder::new(&compressed as &[u8]).read_to_end(&mut output).unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+//! ```
+//!
+//! The one difference is that `FrameEncoder::into_inner` returns an
+//! `io::Error` on failure, not an `IntoInnerError`.
+
+/// A decoder matching `snap::read::FrameDecoder`.
+pub mod read {
+    use std::io::{self, Read};
+
+    use read::{CrcMode, SnappyFramedDecoder};
+
+    /// Decompresses a stream, verifying CRCs.
+    pub struct FrameDecoder<R: Read> {
+        decoder: SnappyFramedDecoder<R>
+    }
+
+    impl<R: Read> FrameDecoder<R> {
+        /// Create a new decoder reading from `rdr`.
+        pub fn new(rdr: R) -> FrameDecoder<R> {
+            FrameDecoder{
+                decoder: SnappyFramedDecoder::new(rdr, CrcMode::Verify)
+            }
+        }
+
+        /// Get a reference to the underlying reader.
+        pub fn get_ref(&self) -> &R {
+            self.decoder.get_ref()
+        }
+
+        /// Get a mutable reference to the underlying reader.
+        pub fn get_mut(&mut self) -> &mut R {
+            self.decoder.get_mut()
+        }
+
+        /// Unwrap this decoder, returning the underlying reader.
+        pub fn into_inner(self) -> R {
+            self.decoder.into_inner()
+        }
+    }
+
+    impl<R: Read> Read for FrameDecoder<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.decoder.read(buf)
+        }
+    }
+}
+
+/// An encoder matching `snap::write::FrameEncoder`.
+pub mod write {
+    use std::io::{self, Write};
+
+    use chunk::STREAM_IDENTIFIER;
+    use compression::Compression;
+    use write::{Checkpoint, SnappyFramedEncoder};
+
+    /// Compresses a stream.  Unlike `SnappyFramedEncoder`, we don't write
+    /// anything to the destination until we're first written to or
+    /// flushed, so creating an encoder can't fail.
+    pub struct FrameEncoder<W: Write> {
+        /// Our destination, before we've written the stream identifier.
+        dest: Option<W>,
+        /// Our encoder, after we've written the stream identifier.
+        encoder: Option<SnappyFramedEncoder<W>>
+    }
+
+    impl<W: Write> FrameEncoder<W> {
+        /// Create a new encoder writing to `wtr`.
+        pub fn new(wtr: W) -> FrameEncoder<W> {
+            FrameEncoder{dest: Some(wtr), encoder: None}
+        }
+
+        /// Get a reference to the underlying writer.
+        pub fn get_ref(&self) -> &W {
+            match self.encoder {
+                Some(ref encoder) => encoder.get_ref(),
+                None => self.dest.as_ref().unwrap()
+            }
+        }
+
+        /// Get a mutable reference to the underlying writer.
+        pub fn get_mut(&mut self) -> &mut W {
+            match self.encoder {
+                Some(ref mut encoder) => encoder.get_mut(),
+                None => self.dest.as_mut().unwrap()
+            }
+        }
+
+        /// Flush all data, and return the underlying writer.
+        pub fn into_inner(mut self) -> io::Result<W> {
+            try!(self.flush());
+            let (dest, _) = self.encoder.take().unwrap().into_inner();
+            Ok(dest)
+        }
+
+        /// Write the stream identifier, if we haven't already, and return
+        /// our encoder.
+        fn encoder(&mut self) -> io::Result<&mut SnappyFramedEncoder<W>> {
+            if self.encoder.is_none() {
+                let dest = self.dest.as_mut().unwrap();
+                try!(dest.write_all(&STREAM_IDENTIFIER));
+                let checkpoint = Checkpoint{
+                    input_offset: 0,
+                    output_offset: STREAM_IDENTIFIER.len() as u64,
+                    frames_written: 0
+                };
+                self.encoder = Some(SnappyFramedEncoder::resume(
+                    self.dest.take().unwrap(), checkpoint,
+                    Compression::default()));
+            }
+            Ok(self.encoder.as_mut().unwrap())
+        }
+    }
+
+    impl<W: Write> Write for FrameEncoder<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            try!(self.encoder()).write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            try!(self.encoder()).flush()
+        }
+    }
+}
+
+#[test]
+fn empty_stream_has_identifier() {
+    use chunk::STREAM_IDENTIFIER;
+
+    let encoder = write::FrameEncoder::new(vec!());
+    assert!(encoder.get_ref().is_empty());
+    assert_eq!(&STREAM_IDENTIFIER as &[u8],
+               &encoder.into_inner().unwrap() as &[u8]);
+}
@@ -0,0 +1,240 @@
+//! A temporary buffer which stores data in compressed form, spilling it to
+//! a temporary file once it grows too large to keep in memory.  This is
+//! useful for things like the spill paths of external sorts and joins.
+//!
+//! ```
+//! use std::io::{Read, Write};
+//! use snappy_framed::spill::SpillBuffer;
+//!
+//! let mut buffer = SpillBuffer::new(1024 * 1024).unwrap();
+//! buffer.write_all(b"Hello!").unwrap();
+//! assert!(!buffer.is_spilled());
+//!
+//! let mut output = vec!();
+//! buffer.into_reader().unwrap().read_to_end(&mut output).unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
+//! ```
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chunk::max_frame_size_for_payload;
+use consts::*;
+use read::{CrcMode, SnappyFramedDecoder};
+use write::SnappyFramedEncoder;
+
+/// Used to give each temporary file a unique name.
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary file, which is deleted when dropped.
+struct TempFile {
+    path: PathBuf,
+    file: File
+}
+
+impl TempFile {
+    fn create_in(dir: &Path) -> io::Result<TempFile> {
+        loop {
+            let name = format!("snappy_framed_spill_{}_{}.sz", process::id(),
+                               NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed));
+            let path = dir.join(name);
+            let opened = OpenOptions::new().read(true).write(true)
+                .create_new(true).open(&path);
+            match opened {
+                Ok(file) => return Ok(TempFile{path: path, file: file}),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err)
+            }
+        }
+    }
+}
+
+impl Read for TempFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Where a `SpillBuffer` stores its compressed data.
+struct SpillDest {
+    threshold: usize,
+    dir: PathBuf,
+    memory: Vec<u8>,
+    disk: Option<BufWriter<TempFile>>
+}
+
+impl Write for SpillDest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut disk) = self.disk {
+            return disk.write(buf);
+        }
+        if self.memory.len() + buf.len() <= self.threshold {
+            self.memory.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        // Don't take any of `buf` until we've spilled successfully, so
+        // that a caller who retries doesn't store it twice.
+        let file = try!(TempFile::create_in(&self.dir));
+        let capacity = max_frame_size_for_payload(MAX_UNCOMPRESSED_CHUNK);
+        let mut disk = BufWriter::with_capacity(capacity, file);
+        try!(disk.write_all(&self.memory));
+        try!(disk.write_all(buf));
+        self.memory = vec!();
+        self.disk = Some(disk);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.disk {
+            Some(ref mut disk) => disk.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+/// Accepts writes, and stores them compressed, in memory until they exceed
+/// a threshold, and in a temporary file after that.  The temporary file
+/// is deleted when the buffer, or the reader returned by `into_reader`, is
+/// dropped.
+pub struct SpillBuffer {
+    encoder: SnappyFramedEncoder<SpillDest>
+}
+
+impl SpillBuffer {
+    /// Create a new buffer which spills to the system's temporary
+    /// directory once it holds more than `threshold` bytes of compressed
+    /// data.
+    pub fn new(threshold: usize) -> io::Result<SpillBuffer> {
+        SpillBuffer::in_dir(env::temp_dir(), threshold)
+    }
+
+    /// Create a new buffer which spills to a temporary file in `dir` once
+    /// it holds more than `threshold` bytes of compressed data.
+    pub fn in_dir<P: Into<PathBuf>>(dir: P, threshold: usize) ->
+        io::Result<SpillBuffer>
+    {
+        let dest = SpillDest{
+            threshold: threshold,
+            dir: dir.into(),
+            memory: vec!(),
+            disk: None
+        };
+        Ok(SpillBuffer{encoder: try!(SnappyFramedEncoder::new(dest))})
+    }
+
+    /// Has our data been spilled to disk?
+    pub fn is_spilled(&self) -> bool {
+        self.encoder.get_ref().disk.is_some()
+    }
+
+    /// Finish writing, and return a reader which decompresses everything
+    /// we were given.
+    pub fn into_reader(self) -> io::Result<SpillReader> {
+        let dest = try!(self.encoder.finish());
+        let source = match dest.disk {
+            None => SpillSource::Memory(Cursor::new(dest.memory)),
+            Some(disk) => {
+                let mut file = try!(disk.into_inner());
+                try!(file.file.seek(SeekFrom::Start(0)));
+                SpillSource::Disk(file)
+            }
+        };
+        Ok(SpillReader{
+            decoder: SnappyFramedDecoder::new(source, CrcMode::Verify)
+        })
+    }
+}
+
+impl Write for SpillBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Where a `SpillReader` reads its compressed data from.
+enum SpillSource {
+    Memory(Cursor<Vec<u8>>),
+    Disk(TempFile)
+}
+
+impl Read for SpillSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            SpillSource::Memory(ref mut cursor) => cursor.read(buf),
+            SpillSource::Disk(ref mut file) => file.read(buf)
+        }
+    }
+}
+
+/// Decompresses the contents of a `SpillBuffer`.
+pub struct SpillReader {
+    decoder: SnappyFramedDecoder<SpillSource>
+}
+
+impl Read for SpillReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf)
+    }
+}
+
+#[test]
+fn spill_to_disk_and_clean_up() {
+    use test_helpers::*;
+
+    let input = random_data(200_000);
+    let mut buffer = SpillBuffer::new(1000).unwrap();
+    buffer.write_all(&input[..500]).unwrap();
+    assert!(!buffer.is_spilled());
+    buffer.write_all(&input[500..]).unwrap();
+    assert!(buffer.is_spilled());
+
+    let mut reader = buffer.into_reader().unwrap();
+    let path = match reader.decoder.get_ref() {
+        &SpillSource::Disk(ref file) => file.path.clone(),
+        _ => panic!("expected data on disk")
+    };
+    assert!(path.exists());
+    let mut output = vec!();
+    reader.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+    drop(reader);
+    assert!(!path.exists());
+}
+
+#[test]
+fn failed_spill_takes_nothing() {
+    let mut dest = SpillDest{
+        threshold: 10,
+        dir: env::temp_dir().join("snappy_framed_no_such_dir"),
+        memory: vec!(),
+        disk: None
+    };
+    assert_eq!(5, dest.write(b"Hello").unwrap());
+    assert!(dest.write(b", world!").is_err());
+    assert_eq!(b"Hello" as &[u8], &dest.memory as &[u8]);
+    assert!(dest.disk.is_none());
+}
@@ -0,0 +1,225 @@
+//! Streams of decoded frames, for async pipelines which process records
+//! rather than bytes.  Requires the `stream` feature.
+//!
+//! Each item is the decompressed contents of one data chunk, so a stream
+//! written using `datagram` or the `tokio_codec` encoder yields the
+//! original payloads.  Chunks written by `write::SnappyFramedEncoder`
+//! hold up to one chunk's worth of whatever was written, without regard
+//! to record boundaries.
+
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_io::AsyncRead;
+use std::cmp::max;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use read::CrcMode;
+use sans_io::{ChunkDecoder, DecoderEvent};
+
+/// How much we try to read from an `AsyncRead` at once.
+const READ_SIZE: usize = 16 * 1024;
+
+/// Return the next decoded frame from `decoder`, or call `fill` to get
+/// more input.  `fill` returns `false` at the end of the input.
+fn poll_next_frame<F>(decoder: &mut ChunkDecoder, done: &mut bool, mut fill: F)
+                      -> Poll<Option<io::Result<Bytes>>>
+    where F: FnMut(&mut ChunkDecoder) -> Poll<io::Result<bool>>
+{
+    while !*done {
+        match decoder.next_event() {
+            Ok(Some(DecoderEvent::Data(data))) =>
+                return Poll::Ready(Some(Ok(Bytes::from(data)))),
+            Ok(Some(_)) => continue,
+            Ok(None) => {}
+            Err(err) => {
+                *done = true;
+                return Poll::Ready(Some(Err(err.into())));
+            }
+        }
+        match fill(decoder) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Ok(true)) => {}
+            Poll::Ready(Ok(false)) => {
+                *done = true;
+                if let Err(err) = decoder.finish() {
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+            }
+            Poll::Ready(Err(err)) => {
+                *done = true;
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
+    }
+    Poll::Ready(None)
+}
+
+/// A stream of frames decoded from an `AsyncRead`.  Ends after the first
+/// error.
+///
+/// ```
+/// use snappy_framed::datagram::encode_datagram;
+/// use snappy_framed::read::CrcMode;
+/// use snappy_framed::stream::ReadFrames;
+///
+/// let compressed = encode_datagram(b"Hello!", true).unwrap();
+/// let frames = ReadFrames::new(&compressed as &[u8], CrcMode::Verify);
+/// // `frames` can now be polled by any async runtime.
+/// # let _ = frames;
+/// ```
+pub struct ReadFrames<R: AsyncRead + Unpin> {
+    source: R,
+    decoder: ChunkDecoder,
+    /// Space for reading from `source`.
+    input: Vec<u8>,
+    /// Have we reached the end of our input, or an error?
+    done: bool
+}
+
+impl<R: AsyncRead + Unpin> ReadFrames<R> {
+    /// Decode frames from `source`, using the CRC verification options
+    /// indicated by `mode`.
+    pub fn new(source: R, mode: CrcMode) -> Self {
+        ReadFrames{source: source, decoder: ChunkDecoder::new(mode),
+                   input: vec!(), done: false}
+    }
+
+    /// Unwrap this stream, returning our source.
+    pub fn into_inner(self) -> R { self.source }
+}
+
+impl<R: AsyncRead + Unpin> Stream for ReadFrames<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<Option<io::Result<Bytes>>>
+    {
+        let this = self.get_mut();
+        let source = &mut this.source;
+        let input = &mut this.input;
+        poll_next_frame(&mut this.decoder, &mut this.done, |decoder| {
+            input.resize(max(decoder.wanted(), READ_SIZE), 0);
+            match Pin::new(&mut *source).poll_read(cx, input) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Ok(n)) => {
+                    decoder.push_input(&input[..n]);
+                    Poll::Ready(Ok(n > 0))
+                }
+                Poll::Ready(Err(ref err))
+                    if err.kind() == io::ErrorKind::Interrupted =>
+                    Poll::Ready(Ok(true)),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err))
+            }
+        })
+    }
+}
+
+/// A stream of frames decoded from a stream of compressed `Bytes`, which
+/// may be split at any point.  Ends after the first error.
+pub struct StreamFrames<S: Stream<Item = Bytes> + Unpin> {
+    source: S,
+    decoder: ChunkDecoder,
+    /// Have we reached the end of our input, or an error?
+    done: bool
+}
+
+impl<S: Stream<Item = Bytes> + Unpin> StreamFrames<S> {
+    /// Decode frames from `source`, using the CRC verification options
+    /// indicated by `mode`.
+    pub fn new(source: S, mode: CrcMode) -> Self {
+        StreamFrames{source: source, decoder: ChunkDecoder::new(mode),
+                     done: false}
+    }
+
+    /// Unwrap this stream, returning our source.
+    pub fn into_inner(self) -> S { self.source }
+}
+
+impl<S: Stream<Item = Bytes> + Unpin> Stream for StreamFrames<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<Option<io::Result<Bytes>>>
+    {
+        let this = self.get_mut();
+        let source = &mut this.source;
+        poll_next_frame(&mut this.decoder, &mut this.done, |decoder| {
+            Pin::new(&mut *source).poll_next(cx).map(|data| {
+                Ok(data.map(|data| decoder.push_input(&data)).is_some())
+            })
+        })
+    }
+}
+
+/// Collect all the frames in `frames`.
+#[cfg(test)]
+fn collect_frames<S>(mut frames: S) -> io::Result<Vec<Vec<u8>>>
+    where S: Stream<Item = io::Result<Bytes>> + Unpin
+{
+    use test_helpers::*;
+
+    let mut result = vec!();
+    while let Some(frame) = poll_until_ready(|cx| {
+        Pin::new(&mut frames).poll_next(cx)
+    }) {
+        result.push(try!(frame).to_vec());
+    }
+    Ok(result)
+}
+
+#[test]
+fn frames_from_async_read() {
+    use datagram::encode_datagram;
+    use error::SnappyFramedError;
+    use test_helpers::*;
+
+    let payloads = vec!(b"Hello!".to_vec(), random_data(65_536),
+                        repeat_data(b"compressible ", 1000));
+    let mut compressed = vec!();
+    for (i, payload) in payloads.iter().enumerate() {
+        compressed.extend(encode_datagram(payload, i == 0).unwrap());
+    }
+
+    let source = Stuttering::new(&compressed as &[u8], 1000);
+    let frames = ReadFrames::new(source, CrcMode::Verify);
+    assert_eq!(payloads, collect_frames(frames).unwrap());
+
+    let truncated = &compressed[..compressed.len() - 1];
+    let frames = ReadFrames::new(truncated, CrcMode::Verify);
+    let err = collect_frames(frames).unwrap_err();
+    assert_eq!(SnappyFramedError::Truncated.to_string(), err.to_string());
+}
+
+#[test]
+fn frames_from_stream_of_bytes() {
+    use datagram::encode_datagram;
+    use test_helpers::*;
+
+    /// A stream of `Bytes` from an iterator.
+    struct Pieces(::std::vec::IntoIter<Bytes>);
+
+    impl Stream for Pieces {
+        type Item = Bytes;
+
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context) ->
+            Poll<Option<Bytes>>
+        {
+            Poll::Ready(self.get_mut().0.next())
+        }
+    }
+
+    let payloads = vec!(random_data(1000), b"Hello!".to_vec());
+    let mut compressed = vec!();
+    for payload in &payloads {
+        compressed.extend(encode_datagram(payload, true).unwrap());
+    }
+    let pieces: Vec<Bytes> = compressed.chunks(7)
+        .map(|piece| Bytes::from(piece.to_vec()))
+        .collect();
+
+    let frames = StreamFrames::new(Pieces(pieces.into_iter()),
+                                   CrcMode::Verify);
+    assert_eq!(payloads, collect_frames(frames).unwrap());
+}
@@ -1,9 +1,15 @@
 //! Helper functions for our unit tests.
 
+#[cfg(feature = "futures-io")]
+use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+#[cfg(feature = "futures-io")] use std::cmp::min;
 use std::convert::AsRef;
 use std::fs::File;
 use std::io::{self, Read};
 use std::path::Path;
+#[cfg(feature = "futures-io")] use std::pin::Pin;
+#[cfg(feature = "futures-io")]
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
 /// Read a test file into memory.
 pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
@@ -19,3 +25,135 @@ pub fn repeat_data(data: &[u8], n: usize) -> Vec<u8> {
     for _ in 0..n { result.extend(data.iter().cloned()); }
     result
 }
+
+/// Generate `len` bytes of poorly-compressible pseudo-random data.
+pub fn random_data(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_F491u32;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect()
+}
+
+/// Generate `len` bytes of random data followed by `len / 10` copies of
+/// some compressible text, so that streams contain both stored and
+/// compressed chunks.
+pub fn mixed_data(len: usize) -> Vec<u8> {
+    let mut data = random_data(len);
+    data.extend(repeat_data(b"compressible ", len / 10));
+    data
+}
+
+/// A waker which does nothing, because our tests poll in a loop.
+#[cfg(feature = "futures-io")]
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(0 as *const (), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable =
+        RawWakerVTable::new(clone, noop, noop, noop);
+
+    unsafe { Waker::from_raw(clone(0 as *const ())) }
+}
+
+/// Run `f` to completion, polling it whenever it asks.  Our test sources
+/// and destinations never wait for anything, so we don't need a real
+/// executor.
+#[cfg(feature = "futures-io")]
+pub fn poll_until_ready<T, F>(mut f: F) -> T
+    where F: FnMut(&mut Context) -> Poll<T>
+{
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(value) = f(&mut cx) { return value; }
+    }
+}
+
+/// Poll `f` just once.
+#[cfg(feature = "futures-io")]
+pub fn poll_once<T, F>(f: F) -> Poll<T>
+    where F: FnOnce(&mut Context) -> Poll<T>
+{
+    let waker = noop_waker();
+    f(&mut Context::from_waker(&waker))
+}
+
+/// Wraps a source or destination, returning `Pending` before every
+/// other call and transferring at most `limit` bytes at a time.
+#[cfg(feature = "futures-io")]
+pub struct Stuttering<T> {
+    pub inner: T,
+    limit: usize,
+    ready: bool
+}
+
+#[cfg(feature = "futures-io")]
+impl<T> Stuttering<T> {
+    pub fn new(inner: T, limit: usize) -> Stuttering<T> {
+        Stuttering{inner: inner, limit: limit, ready: false}
+    }
+
+    fn stutter(&mut self, cx: &mut Context) -> bool {
+        self.ready = !self.ready;
+        if !self.ready { cx.waker().wake_by_ref(); }
+        self.ready
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: AsyncRead + Unpin> AsyncRead for Stuttering<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) ->
+        Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        if !this.stutter(cx) { return Poll::Pending; }
+        let len = min(this.limit, buf.len());
+        Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len])
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: AsyncBufRead + Unpin> AsyncBufRead for Stuttering<T> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<&[u8]>>
+    {
+        let this = self.get_mut();
+        if !this.stutter(cx) { return Poll::Pending; }
+        let limit = this.limit;
+        Pin::new(&mut this.inner).poll_fill_buf(cx).map(|result| {
+            result.map(|buf| &buf[..min(limit, buf.len())])
+        })
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<T: AsyncWrite + Unpin> AsyncWrite for Stuttering<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) ->
+        Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        if !this.stutter(cx) { return Poll::Pending; }
+        let len = min(this.limit, buf.len());
+        Pin::new(&mut this.inner).poll_write(cx, &buf[..len])
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) ->
+        Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,39 @@
+//! Optional instrumentation recording where our coders spend their time.
+//! This is only available when the `timing` feature is enabled, since
+//! reading the clock adds overhead to every chunk.
+
+#[cfg(feature = "timing")] use std::time::Duration;
+
+/// Wall-clock time spent in each stage of encoding or decoding a stream.
+/// Comparing `io` to the other stages shows whether a slow pipeline is
+/// limited by the CPU or by its source or destination.
+#[cfg(feature = "timing")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Timings {
+    /// Time spent compressing or decompressing data.
+    pub compression: Duration,
+    /// Time spent computing or verifying checksums.
+    pub crc: Duration,
+    /// Time spent reading from our source or writing to our destination.
+    pub io: Duration
+}
+
+/// Evaluate `$e`, adding the time it takes to `$total` if the `timing`
+/// feature is enabled.
+#[cfg(feature = "timing")]
+macro_rules! timed {
+    ($total:expr, $e:expr) => {{
+        let start = ::std::time::Instant::now();
+        let result = $e;
+        $total += start.elapsed();
+        result
+    }}
+}
+
+/// Evaluate `$e`, adding the time it takes to `$total` if the `timing`
+/// feature is enabled.
+#[cfg(not(feature = "timing"))]
+macro_rules! timed {
+    ($total:expr, $e:expr) => { $e }
+}
@@ -0,0 +1,191 @@
+//! An implementation of `tokio_util::codec::{Encoder, Decoder}`, for
+//! message-oriented network services.  Requires the `tokio-codec`
+//! feature.
+//!
+//! Each payload passed to the encoder is written as a single data chunk,
+//! so it must be no longer than `MAX_UNCOMPRESSED_CHUNK` bytes, and the
+//! decoder yields the contents of each data chunk as a separate frame.
+//! The first payload is preceded by a stream identifier, so the whole
+//! connection is a valid Snappy framed stream.
+//!
+//! ```
+//! extern crate bytes;
+//! extern crate snappy_framed;
+//! extern crate tokio_util;
+//!
+//! use bytes::{Bytes, BytesMut};
+//! use snappy_framed::tokio_codec::SnappyFramedCodec;
+//! use tokio_util::codec::{Decoder, Encoder};
+//!
+//! # fn main() {
+//! let mut codec = SnappyFramedCodec::default();
+//! let mut buf = BytesMut::new();
+//! codec.encode(Bytes::from(b"Hello!".to_vec()), &mut buf).unwrap();
+//! let frame = codec.decode(&mut buf).unwrap().unwrap();
+//! assert_eq!(b"Hello!" as &[u8], &frame as &[u8]);
+//! # }
+//! ```
+
+use bytes::{Bytes, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+use chunk::*;
+use consts::*;
+use read::CrcMode;
+use sans_io::{ChunkContents, ChunkDecoder};
+
+/// Encodes payloads as data chunks, and decodes data chunks into frames.
+pub struct SnappyFramedCodec {
+    /// Checks and decodes incoming chunks.
+    state: ChunkDecoder,
+    /// Have we written the stream identifier yet?
+    wrote_identifier: bool
+}
+
+impl SnappyFramedCodec {
+    /// Create a new codec, using the CRC verification options indicated
+    /// by `mode` when decoding.
+    pub fn new(mode: CrcMode) -> Self {
+        SnappyFramedCodec{
+            state: ChunkDecoder::new(mode),
+            wrote_identifier: false
+        }
+    }
+
+    /// The state machine which decodes incoming chunks.  Use this to set
+    /// any of the decoding options it supports.
+    pub fn chunk_decoder_mut(&mut self) -> &mut ChunkDecoder {
+        &mut self.state
+    }
+}
+
+impl Default for SnappyFramedCodec {
+    fn default() -> Self {
+        SnappyFramedCodec::new(CrcMode::default())
+    }
+}
+
+impl Decoder for SnappyFramedCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        while src.len() >= HEADER_SIZE {
+            let (chunk_type, chunk_len) = parse_header(&src[..HEADER_SIZE]);
+            try!(self.state.check_header(chunk_type, chunk_len));
+            if src.len() < HEADER_SIZE + chunk_len {
+                src.reserve(HEADER_SIZE + chunk_len - src.len());
+                break;
+            }
+            let chunk = src.split_to(HEADER_SIZE + chunk_len);
+            let contents = try!(self.state.decode_chunk(&Chunk{
+                chunk_type: chunk_type,
+                data: &chunk[HEADER_SIZE..]
+            }));
+            // Empty data chunks are empty payloads, so we return them.
+            match contents {
+                ChunkContents::Decompressed(data) =>
+                    return Ok(Some(Bytes::from(data))),
+                ChunkContents::Stored(_) => {
+                    return Ok(Some(chunk.freeze()
+                                   .slice(HEADER_SIZE + CRC_SIZE..)));
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like `decode`, but fails if `src` ends with a partial chunk.
+    fn decode_eof(&mut self, src: &mut BytesMut) ->
+        io::Result<Option<Bytes>>
+    {
+        match try!(self.decode(src)) {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => {
+                try!(self.state.end_of_stream());
+                Ok(None)
+            }
+            None => Err(self.state.truncated(src).into())
+        }
+    }
+}
+
+impl<'a> Encoder<&'a [u8]> for SnappyFramedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &'a [u8], dst: &mut BytesMut) ->
+        io::Result<()>
+    {
+        if item.len() > MAX_UNCOMPRESSED_CHUNK {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Snappy frame payload too large"));
+        }
+        let mut out = vec!();
+        if !self.wrote_identifier {
+            out.extend_from_slice(&STREAM_IDENTIFIER);
+            self.wrote_identifier = true;
+        }
+        append_data_chunk(item, &mut out);
+        dst.extend_from_slice(&out);
+        Ok(())
+    }
+}
+
+impl Encoder<Bytes> for SnappyFramedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.encode(&item as &[u8], dst)
+    }
+}
+
+#[test]
+fn codec_round_trip() {
+    use read::SnappyFramedDecoder;
+    use std::io::Read;
+    use test_helpers::*;
+
+    let payloads = vec!(b"Hello!".to_vec(), random_data(65_536),
+                        repeat_data(b"compressible ", 1000), vec!());
+    let mut codec = SnappyFramedCodec::new(CrcMode::Verify);
+    let mut buf = BytesMut::new();
+    for payload in &payloads {
+        codec.encode(payload as &[u8], &mut buf).unwrap();
+    }
+    assert!(codec.encode(&random_data(65_537) as &[u8], &mut buf).is_err());
+
+    // The whole buffer is a valid stream.
+    let mut decoder = SnappyFramedDecoder::new(&buf as &[u8],
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(payloads.concat(), output);
+
+    // Feed the decoder a few bytes at a time, as a network would.
+    let mut decoder = SnappyFramedCodec::new(CrcMode::Verify);
+    let mut input = BytesMut::new();
+    let mut frames = vec!();
+    for piece in buf.chunks(1000) {
+        input.extend_from_slice(piece);
+        while let Some(frame) = decoder.decode(&mut input).unwrap() {
+            frames.push(frame.to_vec());
+        }
+    }
+    assert_eq!(None, decoder.decode_eof(&mut input).unwrap());
+    assert_eq!(payloads, frames);
+}
+
+#[test]
+fn codec_detects_truncation() {
+    use error::SnappyFramedError;
+
+    let mut codec = SnappyFramedCodec::default();
+    let mut buf = BytesMut::new();
+    codec.encode(b"Hello!" as &[u8], &mut buf).unwrap();
+    let mut truncated = BytesMut::from(&buf[..buf.len() - 1]);
+    assert_eq!(None, codec.decode(&mut truncated).unwrap());
+    let err = codec.decode_eof(&mut truncated).unwrap_err();
+    assert_eq!(SnappyFramedError::Truncated.to_string(), err.to_string());
+}
@@ -1,17 +1,28 @@
 //! Implementations of `Write` using Snappy compression.
 
-use snappy;
+use std::cmp::min;
+use std::fs::File;
 use std::io::{self, Write};
+use std::path::Path;
 
+use chunk::*;
+use compression::Compression;
+use config::Config;
 use consts::*;
-use masked_crc::*;
-
-/// Appears at the front of all Snappy framed streams.
-const STREAM_IDENTIFIER: [u8; 10] =
-    [0xFF, 0x06, 0x00, 0x00, 0x73, 0x4E, 0x61, 0x50, 0x70, 0x59];
+use manifest::*;
+use raw::RawCodec;
+use read::CrcMode;
+use sans_io::{ChunkDecoder, ChunkEncoder, DecoderEvent};
+use seek::ChunkIndex;
+#[cfg(feature = "timing")] use timing::Timings;
 
 /// Encode a stream containing Snappy-compressed frames.
 ///
+/// Small writes are buffered until we have enough input to fill a chunk,
+/// so always call `finish` or `flush` when you're done writing.  See
+/// `FlushMode` for how `flush` treats buffered input.  If the encoder is
+/// dropped, any buffered input is written, but errors can only be logged.
+///
 /// ```
 /// use std::io::{Cursor, Read, Write};
 /// use snappy_framed::read::{CrcMode, SnappyFramedDecoder};
@@ -35,49 +46,802 @@ const STREAM_IDENTIFIER: [u8; 10] =
 /// assert_eq!(b"Hello!" as &[u8], &output as &[u8]);
 /// ```
 pub struct SnappyFramedEncoder<W: Write> {
-    dest: W
+    /// Our destination.  This is only `None` after `into_inner` has taken
+    /// it, so that `drop` knows there's nothing left to do.
+    dest: Option<W>,
+    /// Builds our chunks, and holds most of our options.  Its output is
+    /// made up of chunks which a non-blocking destination wasn't ready
+    /// for, which are written before anything else.
+    state: ChunkEncoder,
+    flush_mode: FlushMode,
+    /// The status of `state` when we last wrote its output, or tried to.
+    status: EncoderStatus,
+    retry_policy: Option<Box<dyn RetryPolicy + Send + Sync>>,
+    /// Time spent on I/O.  `state` records the rest.
+    #[cfg(feature = "timing")]
+    timings: Timings
+}
+
+/// Decides whether to retry after a write to an encoder's destination
+/// fails.  Since we keep track of how much data has been written, a
+/// retried write never duplicates or drops data.
+///
+/// This is implemented for closures, so you can write:
+///
+/// ```
+/// use std::io::ErrorKind;
+/// use std::thread::sleep;
+/// use std::time::Duration;
+/// use snappy_framed::write::SnappyFramedEncoder;
+///
+/// let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+/// encoder.set_retry_policy(|err: &std::io::Error, attempt: u32| {
+///     if err.kind() == ErrorKind::TimedOut && attempt <= 3 {
+///         sleep(Duration::from_millis(10 << attempt));
+///         true
+///     } else {
+///         false
+///     }
+/// });
+/// ```
+pub trait RetryPolicy {
+    /// Called when a write fails with `err`.  `attempt` is 1 for the first
+    /// failure, and increases with each consecutive failure.  Return
+    /// `true` to retry the write, after waiting if desired, or `false` to
+    /// return the error.
+    fn should_retry(&mut self, err: &io::Error, attempt: u32) -> bool;
+}
+
+impl<F> RetryPolicy for F where F: FnMut(&io::Error, u32) -> bool {
+    fn should_retry(&mut self, err: &io::Error, attempt: u32) -> bool {
+        self(err, attempt)
+    }
+}
+
+impl SnappyFramedEncoder<File> {
+    /// Create the file at `path`, truncating it if it exists, and prepare
+    /// to encode data into it.  We already buffer a chunk's worth of
+    /// input, and write each chunk in one call, so there's no need for a
+    /// `BufWriter`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        SnappyFramedEncoder::new(try!(File::create(path)))
+    }
+}
+
+impl SnappyFramedEncoder<Vec<u8>> {
+    /// Configure a new encoder, using options which can't be changed once
+    /// we've started writing.  The builder can create encoders for any
+    /// kind of writer; it's only defined here so that the compiler
+    /// doesn't need to know the writer's type yet.
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use snappy_framed::write::SnappyFramedEncoder;
+    ///
+    /// let mut encoder = SnappyFramedEncoder::builder()
+    ///     .with_chunk_size(16 * 1024)
+    ///     .with_min_savings_percent(10)
+    ///     .build(vec!())
+    ///     .unwrap();
+    /// encoder.write_all(b"Hello!").unwrap();
+    /// ```
+    pub fn builder() -> EncoderBuilder {
+        EncoderBuilder::default()
+    }
+}
+
+/// An alias for `SnappyFramedEncoder`, matching the naming used by the
+/// `snap` crate.
+pub type FrameEncoder<W> = SnappyFramedEncoder<W>;
+
+/// What an encoder knows about the data it has written to its
+/// destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct EncoderStatus {
+    /// The number of data chunks that were written completely.
+    pub frames_written: u64,
+    /// The number of bytes written by the stream identifier and all
+    /// complete data chunks, including any which a non-blocking
+    /// destination hasn't accepted yet.  If `poisoned` is set, the
+    /// destination may contain a partial chunk after this point.
+    pub bytes_written: u64,
+    /// The number of uncompressed bytes stored in complete data chunks.
+    pub bytes_consumed: u64,
+    /// Did writing to the destination fail?  If so, the destination
+    /// probably contains a partial chunk, and the encoder will refuse to
+    /// write any more data.
+    pub poisoned: bool
+}
+
+/// A position in an encoded stream, at a chunk boundary, from which
+/// encoding can be resumed later using `SnappyFramedEncoder::resume`.
+/// The default is the start of a stream, before the stream identifier.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    /// The number of uncompressed bytes which have been encoded.  When
+    /// resuming, start writing input from this offset.
+    pub input_offset: u64,
+    /// The number of bytes of encoded output.  When resuming, the
+    /// destination must be truncated to this length, and positioned at
+    /// its end.
+    pub output_offset: u64,
+    /// The number of data chunks written so far.
+    pub frames_written: u64
+}
+
+/// What `flush` does with buffered input which doesn't yet fill a chunk.
+/// Defaults to `Sync`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FlushMode {
+    /// Compress any buffered input into a chunk and write it before
+    /// flushing our destination, so that a reader on the other end of a
+    /// pipe sees everything written so far.  This is like `flate2`'s sync
+    /// flush, and it may make the output slightly larger.
+    Sync,
+    /// Just flush our destination, and keep buffering input until it
+    /// fills a chunk.  Call `flush_buffer` to write buffered input
+    /// explicitly, and be sure to do so before discarding the encoder.
+    Forward
+}
+
+impl Default for FlushMode {
+    fn default() -> FlushMode {
+        FlushMode::Sync
+    }
+}
+
+/// Encoder settings which match other implementations, so that chunk
+/// boundaries and output sizes line up with what they would produce.
+/// Pass these to `EncoderBuilder::with_preset`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Preset {
+    /// The largest chunks allowed by the spec, storing chunks uncompressed
+    /// when compression doesn't help.  This is our default.
+    Spec,
+    /// 32 KiB chunks, stored uncompressed unless compression saves at
+    /// least 15%, as historically used by `snappy-java`.
+    SnappyJava,
+    /// The same settings as `Spec`, but with CRCs in the byte order used
+    /// by the Python and Node.js implementations.
+    PythonSnappy
+}
+
+/// Options for creating a `SnappyFramedEncoder`.  See
+/// `SnappyFramedEncoder::builder`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EncoderBuilder {
+    compression: Compression,
+    swapped_crcs: bool,
+    stream_identifier: bool
+}
+
+impl EncoderBuilder {
+    /// Replace all our options with those for `preset`, except for
+    /// `with_stream_identifier`.
+    pub fn with_preset(self, preset: Preset) -> Self {
+        let defaults = EncoderBuilder{
+            stream_identifier: self.stream_identifier,
+            ..EncoderBuilder::default()
+        };
+        match preset {
+            Preset::Spec => defaults,
+            Preset::SnappyJava => {
+                defaults.with_chunk_size(32 * 1024)
+                    .with_min_savings_percent(15)
+            }
+            Preset::PythonSnappy => defaults.with_swapped_crcs(true)
+        }
+    }
+
+    /// Use the chunking options in `compression`, replacing any set
+    /// earlier.
+    pub fn with_compression(self, compression: Compression) -> Self {
+        EncoderBuilder{compression: compression, ..self}
+    }
+
+    /// Split input into chunks of at most `chunk_size` bytes.  See
+    /// `Compression::with_chunk_size`.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        let compression = self.compression.with_chunk_size(chunk_size);
+        EncoderBuilder{compression: compression, ..self}
+    }
+
+    /// Store chunks uncompressed unless compression saves at least
+    /// `percent` percent.  See `Compression::with_min_savings_percent`.
+    pub fn with_min_savings_percent(self, percent: u8) -> Self {
+        let compression = self.compression.with_min_savings_percent(percent);
+        EncoderBuilder{compression: compression, ..self}
+    }
+
+    /// Write CRCs in the byte order used by the Node.js and Python
+    /// implementations.  See `SnappyFramedEncoder::set_swapped_crcs`.
+    /// Defaults to `false`.
+    pub fn with_swapped_crcs(self, swapped: bool) -> Self {
+        EncoderBuilder{swapped_crcs: swapped, ..self}
+    }
+
+    /// Should we begin by writing the stream identifier?  Turn this off
+    /// to append more chunks to an existing stream.  Defaults to `true`.
+    pub fn with_stream_identifier(self, enable: bool) -> Self {
+        EncoderBuilder{stream_identifier: enable, ..self}
+    }
+
+    /// Create an encoder writing to `dest`.
+    pub fn build<W: Write>(self, dest: W) ->
+        io::Result<SnappyFramedEncoder<W>>
+    {
+        let mut encoder = SnappyFramedEncoder::resume(
+            dest, Checkpoint::default(), self.compression);
+        encoder.set_swapped_crcs(self.swapped_crcs);
+        if self.stream_identifier {
+            try!(encoder.write_header());
+        }
+        Ok(encoder)
+    }
+}
+
+impl Default for EncoderBuilder {
+    fn default() -> EncoderBuilder {
+        EncoderBuilder{
+            compression: Compression::default(),
+            swapped_crcs: false,
+            stream_identifier: true
+        }
+    }
 }
 
 impl<W: Write> SnappyFramedEncoder<W> {
     /// Create a new encoder wrapping the specified `dest`.
     pub fn new(dest: W) -> io::Result<Self> {
-        let mut encoder = SnappyFramedEncoder{dest: dest};
+        SnappyFramedEncoder::with_compression(dest, Compression::default())
+    }
+
+    /// Create a new encoder wrapping the specified `dest`, and using the
+    /// encoder settings in `config`.
+    pub fn with_config(dest: W, config: &Config) -> io::Result<Self> {
+        SnappyFramedEncoder::with_compression(dest, config.compression)
+    }
+
+    /// Create a new encoder wrapping the specified `dest`, and using the
+    /// options in `compression`.
+    pub fn with_compression(dest: W, compression: Compression) ->
+        io::Result<Self>
+    {
+        let mut encoder = SnappyFramedEncoder::resume(
+            dest, Checkpoint::default(), compression);
         try!(encoder.write_header());
         Ok(encoder)
     }
 
+    /// Resume encoding a stream which was interrupted after `checkpoint`
+    /// was taken.  `dest` must contain exactly `checkpoint.output_offset`
+    /// bytes of previous output, and be positioned at the end, so that we
+    /// can append to it.  You should then write the original input,
+    /// starting at `checkpoint.input_offset`.
+    ///
+    /// ```
+    /// use std::fs::OpenOptions;
+    /// use std::io::{Seek, SeekFrom, Write};
+    /// use snappy_framed::Compression;
+    /// use snappy_framed::write::{Checkpoint, SnappyFramedEncoder};
+    ///
+    /// fn resume_file(path: &str, checkpoint: Checkpoint, input: &[u8]) ->
+    ///     std::io::Result<()>
+    /// {
+    ///     let mut file = try!(OpenOptions::new().write(true).open(path));
+    ///     try!(file.set_len(checkpoint.output_offset));
+    ///     try!(file.seek(SeekFrom::Start(checkpoint.output_offset)));
+    ///     let mut encoder = SnappyFramedEncoder::resume(
+    ///         file, checkpoint, Compression::default());
+    ///     try!(encoder.write_all(&input[checkpoint.input_offset as usize..]));
+    ///     encoder.flush()
+    /// }
+    /// ```
+    pub fn resume(dest: W, checkpoint: Checkpoint, compression: Compression)
+                  -> Self
+    {
+        let state = ChunkEncoder::resume(checkpoint, compression);
+        SnappyFramedEncoder{
+            dest: Some(dest),
+            status: state.status(),
+            state: state,
+            flush_mode: FlushMode::Sync,
+            retry_policy: None,
+            #[cfg(feature = "timing")]
+            timings: Timings::default()
+        }
+    }
+
+    /// Record our position after the last complete chunk, so that we can
+    /// resume encoding from there if we're interrupted.  Buffered input
+    /// isn't included, so call `flush` first if you need to be sure that
+    /// everything written so far has reached the disk.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint{
+            input_offset: self.status.bytes_consumed,
+            output_offset: self.status.bytes_written,
+            frames_written: self.status.frames_written
+        }
+    }
+
     fn write_header(&mut self) -> io::Result<()> {
-        try!(self.dest.write_all(&STREAM_IDENTIFIER));
+        self.state.write_stream_identifier();
+        self.write_chunks()
+    }
+
+    /// Consult `policy` before giving up on a failed write to our
+    /// destination.  By default, we never retry.
+    pub fn set_retry_policy<P>(&mut self, policy: P)
+        where P: RetryPolicy + Send + Sync + 'static
+    {
+        self.retry_policy = Some(Box::new(policy));
+    }
+
+    /// Precede each data chunk with a skippable chunk of type
+    /// `SEQUENCE_CHUNK_TYPE`, containing the number of data chunks written
+    /// before it as a little-endian `u64`.  A decoder with
+    /// `set_check_sequence_numbers` enabled will then report an error if
+    /// any frames go missing in transit.  Other decoders ignore these
+    /// chunks.  Defaults to `false`.
+    pub fn set_sequence_numbers(&mut self, enable: bool) {
+        self.state.set_sequence_numbers(enable);
+    }
+
+    /// Choose what `flush` does with buffered input.  Defaults to
+    /// `FlushMode::Sync`.
+    pub fn set_flush_mode(&mut self, mode: FlushMode) {
+        self.flush_mode = mode;
+    }
+
+    /// Insert padding chunks so that no chunk crosses a multiple of
+    /// `alignment` bytes from the start of the stream, unless it's too big
+    /// to fit between two of them, in which case it starts on one.  This
+    /// allows storage layers to split the stream into aligned blocks for
+    /// ranged reads.  `alignment` must be between `HEADER_SIZE` and
+    /// `MAX_CHUNK_LEN`.  Defaults to `None`.
+    pub fn set_alignment(&mut self, alignment: Option<usize>) ->
+        io::Result<()>
+    {
+        self.state.set_alignment(alignment).map_err(|err| err.into())
+    }
+
+    /// Write CRCs in the byte order expected by the Node.js and Python
+    /// implementations, instead of the order used by Java and `snzip`.
+    /// Our decoder can read these using `CrcMode::VerifySwapped`.
+    /// Defaults to `false`.
+    pub fn set_swapped_crcs(&mut self, swapped: bool) {
+        self.state.set_swapped_crcs(swapped);
+    }
+
+    /// Precede each data chunk with a skippable chunk of type
+    /// `PAYLOAD_CRC_CHUNK_TYPE`, containing the masked CRC-32C of the data
+    /// chunk's contents as stored, in little-endian order.  This allows
+    /// storage layers to detect corruption without decompressing
+    /// anything, and it can be checked by a decoder with
+    /// `set_verify_payload_checksums` enabled.  Other decoders ignore
+    /// these chunks.  Defaults to `false`.
+    pub fn set_payload_checksums(&mut self, enable: bool) {
+        self.state.set_payload_checksums(enable);
+    }
+
+    /// Compute a digest of each data chunk's uncompressed contents using
+    /// `hasher`, and record it in our `manifest`.
+    pub fn set_frame_hasher<H>(&mut self, hasher: H)
+        where H: FrameHasher + Send + Sync + 'static
+    {
+        self.state.set_frame_hasher(hasher);
+    }
+
+    /// Compress data chunks using `codec`, instead of the Snappy
+    /// implementation we were built with.  See `raw` for details.
+    pub fn set_raw_codec<C>(&mut self, codec: C)
+        where C: RawCodec + Send + Sync + 'static
+    {
+        self.state.set_raw_codec(codec);
+    }
+
+    /// The digests recorded for each data chunk written since
+    /// `set_frame_hasher` was called.  This can be stored separately from
+    /// the stream, or appended to it using `write_manifest`.
+    pub fn manifest(&self) -> &DigestManifest {
+        self.state.manifest()
+    }
+
+    /// Record the location of each data chunk written from now on in our
+    /// `index`, which can be saved as an index file for use with
+    /// `seek::SeekableDecoder`.  Offsets are relative to the start of the
+    /// stream, including any output before a checkpoint we resumed from.
+    /// Defaults to `false`.
+    pub fn set_build_index(&mut self, enable: bool) {
+        self.state.set_build_index(enable);
+    }
+
+    /// The index built since `set_build_index` was enabled, if it is.
+    pub fn index(&self) -> Option<&ChunkIndex> {
+        self.state.index()
+    }
+
+    /// Write our `manifest` to the stream as one or more skippable chunks
+    /// of type `MANIFEST_CHUNK_TYPE`.  This is normally done after writing
+    /// all the data.
+    pub fn write_manifest(&mut self) -> io::Result<()> {
+        let payloads = self.state.manifest().to_chunk_payloads();
+        for payload in payloads {
+            try!(self.write_skippable_chunk(MANIFEST_CHUNK_TYPE, &payload));
+        }
         Ok(())
     }
+
+    /// The number of bytes currently allocated for our buffers.
+    pub fn memory_usage(&self) -> usize {
+        self.state.memory_usage()
+    }
+
+    /// Time spent in each stage of encoding so far.
+    #[cfg(feature = "timing")]
+    pub fn timings(&self) -> Timings {
+        Timings{io: self.timings.io, ..self.state.timings()}
+    }
+
+    /// Write a skippable chunk of type `chunk_type`, which must be between
+    /// `0x80` and `0xFD`, containing `data`.  Decoders ignore these
+    /// chunks, so they can be used to store application metadata.
+    pub fn write_skippable_chunk(&mut self, chunk_type: u8, data: &[u8]) ->
+        io::Result<()>
+    {
+        try!(self.check_poisoned());
+        try!(self.state.write_skippable_chunk(chunk_type, data));
+        self.write_chunks()
+    }
+
+    /// Declare that the stream will contain `len` bytes of uncompressed
+    /// data, by writing a skippable chunk of type `CONTENT_SIZE_CHUNK_TYPE`
+    /// containing `len` as a little-endian `u64`.  Decoders can use this
+    /// to preallocate buffers or report progress.  This must be called
+    /// before writing any data.
+    pub fn write_content_size_hint(&mut self, len: u64) -> io::Result<()> {
+        if self.state.status().bytes_consumed > 0 ||
+            self.state.buffered_len() > 0
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Snappy content size must be written before any data"));
+        }
+        let mut payload = vec!();
+        push_u64(&mut payload, len);
+        self.write_skippable_chunk(CONTENT_SIZE_CHUNK_TYPE, &payload)
+    }
+
+    /// Information about what we've written so far.
+    pub fn status(&self) -> EncoderStatus {
+        self.status
+    }
+
+    /// Has a write to our destination failed?
+    pub fn is_poisoned(&self) -> bool {
+        self.status.poisoned
+    }
+
+    /// Get a reference to our destination.
+    pub fn get_ref(&self) -> &W {
+        self.dest.as_ref().expect("encoder destination already taken")
+    }
+
+    /// Get a mutable reference to our destination.  Writing to it
+    /// directly will corrupt our stream.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.dest_mut()
+    }
+
+    /// Write any buffered input, whatever our `FlushMode`, and flush our
+    /// destination.  If this fails because flushing the destination
+    /// failed, it can be called again.  Errors writing data poison the
+    /// encoder, as usual.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        try!(self.flush_buffer());
+        try!(self.check_poisoned());
+        try!(self.write_unwritten());
+        self.dest_mut().flush()
+    }
+
+    /// Write any buffered input, flush our destination, and return it.
+    /// Once this succeeds, the stream is complete.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.try_finish());
+        let (dest, _) = self.into_inner();
+        Ok(dest)
+    }
+
+    /// Unwrap this encoder, returning the destination and our final status.
+    /// This does not flush the destination, and it works even if the
+    /// encoder has been poisoned by an earlier error, so that callers can
+    /// inspect or repair the destination.  Any buffered input which hasn't
+    /// been flushed is discarded, as are any chunks which a non-blocking
+    /// destination hasn't accepted yet.
+    pub fn into_inner(mut self) -> (W, EncoderStatus) {
+        let dest = self.dest.take().expect("encoder destination already taken");
+        (dest, self.status)
+    }
+
+    fn dest_mut(&mut self) -> &mut W {
+        self.dest.as_mut().expect("encoder destination already taken")
+    }
+
+    /// Compress any buffered input and write it as a chunk, without
+    /// flushing our destination.  The next chunk will start with the next
+    /// byte written, which is useful for aligning chunks with records.
+    pub fn flush_buffer(&mut self) -> io::Result<()> {
+        if self.state.buffered_len() == 0 { return Ok(()); }
+        try!(self.check_poisoned());
+        self.state.flush();
+        self.write_chunks()
+    }
+
+    /// Write the chunks we've just built, poisoning the encoder if we
+    /// fail.  If our destination is non-blocking and isn't ready, we keep
+    /// whatever it didn't accept, and write it later.
+    fn write_chunks(&mut self) -> io::Result<()> {
+        match self.write_unwritten() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
+    }
+
+    /// Write any chunks which our destination wasn't ready for earlier.
+    /// If it still isn't ready, this fails with `WouldBlock`, and can be
+    /// called again later.
+    fn write_unwritten(&mut self) -> io::Result<()> {
+        let (written, result) = {
+            let dest = self.dest.as_mut()
+                .expect("encoder destination already taken");
+            let output = self.state.output();
+            let mut rest = output;
+            let result = timed!(self.timings.io,
+                                write_with_retry(dest, &mut self.retry_policy,
+                                                 &mut rest));
+            (output.len() - rest.len(), result)
+        };
+        self.state.consume_output(written);
+        match result {
+            Err(ref err) if err.kind() != io::ErrorKind::WouldBlock => {
+                self.status.poisoned = true;
+            }
+            _ => self.status = self.state.status()
+        }
+        result
+    }
+
+    /// The number of compressed bytes which a non-blocking destination
+    /// hasn't accepted yet.  These will be written by the next call to
+    /// `write` or `flush`.
+    pub fn unwritten_len(&self) -> usize {
+        self.state.output().len()
+    }
+
+    /// Fail if an earlier write error left our output in an unknown state.
+    fn check_poisoned(&self) -> io::Result<()> {
+        if self.status.poisoned {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "Snappy encoder unusable after earlier write error"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Like `write_all`, but consults `policy` on errors.  We advance `buf`
+/// past whatever we write, so if a non-blocking destination returns
+/// `WouldBlock`, the caller knows what's left.
+fn write_with_retry<W: Write>(
+    dest: &mut W, policy: &mut Option<Box<dyn RetryPolicy + Send + Sync>>,
+    buf: &mut &[u8]) -> io::Result<()>
+{
+    let mut attempt = 0;
+    while buf.len() > 0 {
+        match dest.write(buf) {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                          "failed to write whole buffer"));
+            }
+            Ok(written) => {
+                *buf = &buf[written..];
+                attempt = 0;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Err(err);
+                }
+                attempt += 1;
+                let retry = match *policy {
+                    Some(ref mut policy) => policy.should_retry(&err, attempt),
+                    None => false
+                };
+                if !retry { return Err(err); }
+            }
+        }
+    }
+    Ok(())
 }
 
 impl<W: Write> Write for SnappyFramedEncoder<W> {
+    /// If our destination is non-blocking and can't accept a chunk, we
+    /// hold on to it, accept no more input than we've already compressed,
+    /// and return `WouldBlock` until the destination is ready again.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        for data in buf.chunks(MAX_UNCOMPRESSED_CHUNK) {
-            let compressed = snappy::compress(data);
+        try!(self.check_poisoned());
+        try!(self.write_unwritten());
+
+        // Hand our input over a chunk at a time, and stop once our
+        // destination stops keeping up.
+        let mut input = buf;
+        while !input.is_empty() {
+            let taken = self.state.take_input(input);
+            input = &input[taken..];
+            match self.write_unwritten() {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => return Err(err)
+            }
+        }
+
+        let consumed = buf.len() - input.len();
+        self.state.observe_write(consumed);
+        self.status = self.state.status();
+        Ok(consumed)
+    }
 
-            let mut header_and_crc = [0; HEADER_SIZE+CRC_SIZE];
-            let chunk_len = CRC_SIZE + compressed.len();
-            let crc = masked_crc(&data);
-            header_and_crc[0] = 0;
-            header_and_crc[1] = ((chunk_len & 0x0000FF)      ) as u8;
-            header_and_crc[2] = ((chunk_len & 0x00FF00) >>  8) as u8;
-            header_and_crc[3] = ((chunk_len & 0xFF0000) >> 16) as u8;
-            header_and_crc[4] = ((crc & 0x000000FF)      ) as u8;
-            header_and_crc[5] = ((crc & 0x0000FF00) >>  8) as u8;
-            header_and_crc[6] = ((crc & 0x00FF0000) >> 16) as u8;
-            header_and_crc[7] = ((crc & 0xFF000000) >> 24) as u8;
-            try!(self.dest.write_all(&header_and_crc));
+    fn flush(&mut self) -> io::Result<()> {
+        if self.flush_mode == FlushMode::Sync {
+            try!(self.flush_buffer());
+        }
+        try!(self.write_unwritten());
+        self.dest_mut().flush()
+    }
+}
 
-            try!(self.dest.write_all(&compressed));
+impl<W: Write> Drop for SnappyFramedEncoder<W> {
+    /// Write any buffered input, so that it isn't lost if the caller
+    /// forgets to call `finish`.  We can't report errors from here, so we
+    /// log them instead.
+    fn drop(&mut self) {
+        if self.dest.is_none() || self.status.poisoned { return; }
+        let result = self.flush_buffer().and_then(|()| self.write_unwritten());
+        if let Err(err) = result {
+            error!("Snappy encoder could not write buffered data: {}", err);
+        }
+    }
+}
+
+/// Decode a Snappy framed stream which is written to us a piece at a
+/// time, and write the decompressed data to `dest`.  This is useful when
+/// compressed data arrives via callbacks.
+///
+/// ```
+/// use std::io::Write;
+/// use snappy_framed::read::CrcMode;
+/// use snappy_framed::write::{SnappyFramedDecoder, SnappyFramedEncoder};
+///
+/// let mut compressed = vec!();
+/// {
+///     let mut encoder = SnappyFramedEncoder::new(&mut compressed).unwrap();
+///     encoder.write_all(b"Hello!").unwrap();
+///     encoder.flush().unwrap();
+/// }
+///
+/// let mut decoder = SnappyFramedDecoder::new(vec!(), CrcMode::Verify);
+/// for piece in compressed.chunks(3) {
+///     decoder.write_all(piece).unwrap();
+/// }
+/// assert_eq!(b"Hello!" as &[u8], &decoder.finish().unwrap() as &[u8]);
+/// ```
+pub struct SnappyFramedDecoder<W: Write> {
+    dest: W,
+    /// Interprets the chunks written to us.
+    state: ChunkDecoder,
+    /// The contents of the last data chunk.
+    output: Vec<u8>,
+    /// How much of `output` we've written to `dest`.  If `dest` fails
+    /// part way through, we write the rest next time, so that nothing is
+    /// written twice.
+    written: usize
+}
+
+impl<W: Write> SnappyFramedDecoder<W> {
+    /// Create a new decoder writing to `dest`, and using the CRC
+    /// verification options indicated by `mode`.
+    pub fn new(dest: W, mode: CrcMode) -> Self {
+        SnappyFramedDecoder{
+            dest: dest,
+            state: ChunkDecoder::new(mode),
+            output: vec!(),
+            written: 0
+        }
+    }
+
+    /// Get a reference to our destination.
+    pub fn get_ref(&self) -> &W {
+        &self.dest
+    }
+
+    /// Get a mutable reference to our destination.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.dest
+    }
+
+    /// The state machine which interprets our input.  Use this to set any
+    /// of the decoding options it supports.
+    pub fn chunk_decoder_mut(&mut self) -> &mut ChunkDecoder {
+        &mut self.state
+    }
+
+    /// Write any decoded data which our destination hasn't accepted yet.
+    fn write_output(&mut self) -> io::Result<()> {
+        while self.written < self.output.len() {
+            match self.dest.write(&self.output[self.written..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write whole buffer"));
+                }
+                Ok(n) => self.written += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the stream ended at a chunk boundary, and return our
+    /// destination.  This does not flush the destination.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.write_output());
+        try!(self.state.finish());
+        Ok(self.dest)
+    }
+}
+
+impl<W: Write> Write for SnappyFramedDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.write_output());
+        // Take one chunk at a time, so we know where each one started.
+        let mut taken = 0;
+        loop {
+            match self.state.next_event() {
+                Ok(Some(DecoderEvent::Data(data))) => {
+                    self.output = data;
+                    self.written = 0;
+                    if let Err(err) = self.write_output() {
+                        return if taken > 0 { Ok(taken) } else { Err(err) };
+                    }
+                }
+                Ok(Some(_)) => {}
+                Ok(None) if taken == buf.len() => return Ok(taken),
+                Ok(None) => {
+                    let wanted = min(self.state.wanted(), buf.len() - taken);
+                    self.state.push_input(&buf[taken..taken + wanted]);
+                    taken += wanted;
+                }
+                Err(err) => {
+                    // Only take the part of `buf` before the bad chunk, so
+                    // that the caller can retry the rest and see the error
+                    // again.
+                    let bad = min(self.state.buffered_len(), taken);
+                    self.state.unread_input(bad);
+                    taken -= bad;
+                    return if taken > 0 { Ok(taken) } else { Err(err.into()) };
+                }
+            }
         }
-        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_output());
         self.dest.flush()
-    }    
+    }
 }
 
 #[test]
@@ -108,3 +872,774 @@ fn encode_example_stream() {
     // Did we survive the round-trip intact?
     assert_eq!(expected, decompressed);
 }
+
+#[test]
+fn encode_with_small_chunks() {
+    use std::io::{Cursor, Read};
+
+    use read::{CrcMode, SnappyFramedDecoder};
+    use test_helpers::*;
+
+    let input = read_file("data/arbres.txt").unwrap();
+    let compression = Compression::default().with_chunk_size(1000);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.write_all(&input).unwrap();
+    encoder.flush().unwrap();
+    let (compressed, status) = encoder.into_inner();
+    assert_eq!(4, status.frames_written);
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decoder = SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn encode_with_adaptive_chunks() {
+    use std::cell::Cell;
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    use read::{CrcMode, SnappyFramedDecoder};
+    use test_helpers::*;
+
+    // A clock which only moves when we say so.
+    thread_local! {
+        static NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+    fn now() -> Instant { NOW.with(|now| now.get().unwrap()) }
+    NOW.with(|now| now.set(Some(Instant::now())));
+
+    let input = random_data(1 << 21);
+    let compression = Compression::default().with_adaptive_chunk_size(true);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.state.set_clock(now);
+    // Until we've measured the write rate, we use small chunks.
+    encoder.write_all(&input[..1 << 20]).unwrap();
+    // Once a window has passed, the next write measures the rate, and
+    // 1 MB per 100 ms calls for larger chunks.
+    NOW.with(|now| now.set(Some(now.get().unwrap() +
+                                Duration::from_millis(100))));
+    encoder.write_all(&input[1 << 20..(1 << 20) + 1]).unwrap();
+    encoder.write_all(&input[(1 << 20) + 1..]).unwrap();
+    encoder.flush().unwrap();
+    let (compressed, _) = encoder.into_inner();
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decoder = SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify);
+    let mut frames = vec!();
+    decoder.decode_frames_into(&mut frames, usize::MAX).unwrap();
+    assert_eq!(input, frames.concat());
+
+    let sizes: Vec<usize> = frames.iter().map(|frame| frame.len()).collect();
+    assert!(sizes[..256].iter().all(|&size| size == 4096));
+    let adapted = sizes[256];
+    assert!(adapted > 4096 && adapted <= MAX_UNCOMPRESSED_CHUNK);
+    assert!(sizes[257..sizes.len() - 1].iter().all(|&size| size == adapted));
+}
+
+#[test]
+fn poison_encoder_after_write_error() {
+    use std::io::Cursor;
+
+    use consts::MAX_UNCOMPRESSED_CHUNK;
+    use test_helpers::*;
+
+    let input = random_data(MAX_UNCOMPRESSED_CHUNK + 1000);
+    // Only leave room for the identifier and part of the second chunk.
+    let mut dest = vec![0u8; 20 * 1024];
+    let mut encoder = SnappyFramedEncoder::new(Cursor::new(&mut dest[..]))
+        .unwrap();
+    encoder.write_all(&input[..10 * 1024]).unwrap();
+    encoder.flush().unwrap();
+    assert!(!encoder.is_poisoned());
+    let complete = encoder.status().bytes_written;
+
+    encoder.write_all(&input[10 * 1024..]).unwrap();
+    assert!(encoder.flush().is_err());
+    assert!(encoder.is_poisoned());
+    assert!(encoder.write_all(b"more").is_err());
+
+    let (cursor, status) = encoder.into_inner();
+    assert!(cursor.position() > complete);
+    assert_eq!(EncoderStatus{
+        frames_written: 1,
+        bytes_written: complete,
+        bytes_consumed: 10 * 1024,
+        poisoned: true
+    }, status);
+}
+
+#[test]
+fn retry_failed_writes() {
+    use read::{CrcMode, SnappyFramedDecoder};
+    use std::io::{Cursor, Read};
+
+    /// Fails every other write with a timeout, once the stream identifier
+    /// has been written.
+    struct Flaky {
+        data: Vec<u8>,
+        fail: bool
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.fail = !self.fail && self.data.len() >= 10;
+            if self.fail {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "flaky"))
+            } else {
+                let len = ::std::cmp::min(buf.len(), 7);
+                self.data.extend(buf[..len].iter().cloned());
+                Ok(len)
+            }
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let flaky = Flaky{data: vec!(), fail: true};
+    let mut encoder = SnappyFramedEncoder::new(flaky).unwrap();
+    encoder.write_all(b"Hello!").unwrap();
+    assert!(encoder.flush().is_err());
+
+    let flaky = Flaky{data: vec!(), fail: true};
+    let mut encoder = SnappyFramedEncoder::new(flaky).unwrap();
+    encoder.set_retry_policy(|err: &io::Error, attempt: u32| {
+        err.kind() == io::ErrorKind::TimedOut && attempt == 1
+    });
+    encoder.write_all(b"Hello, retried world!").unwrap();
+    encoder.flush().unwrap();
+    let (flaky, status) = encoder.into_inner();
+    assert!(!status.poisoned);
+
+    let mut cursor = Cursor::new(&flaky.data as &[u8]);
+    let mut decoder = SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"Hello, retried world!" as &[u8], &output as &[u8]);
+}
+
+#[test]
+fn resume_from_checkpoint() {
+    use std::io::{Cursor, Read};
+
+    use read::{CrcMode, SnappyFramedDecoder};
+    use test_helpers::*;
+
+    let input = random_data(200_000);
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(&input[..150_000]).unwrap();
+    encoder.flush().unwrap();
+    let checkpoint = encoder.checkpoint();
+    assert_eq!(150_000, checkpoint.input_offset);
+    let (mut compressed, _) = encoder.into_inner();
+
+    // Simulate a crash which left a partial chunk at the end.
+    compressed.extend([0x00, 0x10, 0x00].iter().cloned());
+    compressed.truncate(checkpoint.output_offset as usize);
+
+    let mut encoder = SnappyFramedEncoder::resume(compressed, checkpoint,
+                                                  Compression::default());
+    encoder.write_all(&input[checkpoint.input_offset as usize..]).unwrap();
+    encoder.flush().unwrap();
+    let (compressed, status) = encoder.into_inner();
+    assert_eq!(input.len() as u64, status.bytes_consumed);
+    assert_eq!(compressed.len() as u64, status.bytes_written);
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decoder = SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn skippable_chunks_are_ignored_by_decoder() {
+    use std::io::{Cursor, Read};
+
+    use read::{CrcMode, SnappyFramedDecoder};
+
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(b"Hello, ").unwrap();
+    encoder.write_skippable_chunk(0x80, b"metadata").unwrap();
+    encoder.write_all(b"world!").unwrap();
+    assert!(encoder.write_skippable_chunk(0x01, b"data").is_err());
+    assert!(encoder.write_skippable_chunk(0xFE, b"padding").is_err());
+    encoder.flush().unwrap();
+    let (compressed, status) = encoder.into_inner();
+    assert_eq!(compressed.len() as u64, status.bytes_written);
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decoder = SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"Hello, world!" as &[u8], &output as &[u8]);
+}
+
+#[test]
+fn limit_frame_size() {
+    use std::io::{Cursor, Read};
+    use read::{CrcMode, SnappyFramedDecoder};
+    use test_helpers::*;
+
+    let input = mixed_data(10_000);
+    let compression = Compression::default().with_max_frame_size(1000);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.write_all(&input).unwrap();
+    encoder.flush().unwrap();
+    let (compressed, _) = encoder.into_inner();
+
+    let mut rest = &compressed[STREAM_IDENTIFIER.len()..];
+    let mut chunk_types = vec!();
+    while !rest.is_empty() {
+        let (chunk_type, chunk_len) = parse_header(&rest[..HEADER_SIZE]);
+        assert!(HEADER_SIZE + chunk_len <= 1000);
+        chunk_types.push(chunk_type);
+        rest = &rest[HEADER_SIZE + chunk_len..];
+    }
+    // Random data must be stored, but the rest should compress.
+    assert!(chunk_types.contains(&0x00));
+    assert!(chunk_types.contains(&0x01));
+
+    let mut cursor = Cursor::new(&compressed as &[u8]);
+    let mut decoder = SnappyFramedDecoder::new(&mut cursor, CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn record_digest_manifest() {
+    use chunk::parse_header;
+
+    // A toy digest, for testing.
+    let hasher = |data: &[u8]| {
+        vec![data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))]
+    };
+    let compression = Compression::default().with_chunk_size(4);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.set_frame_hasher(hasher);
+    encoder.write_all(b"\x01\x02\x03\x04\x05").unwrap();
+    encoder.flush().unwrap();
+    assert_eq!(vec!(ManifestEntry{
+        uncompressed_offset: 0, uncompressed_len: 4, digest: vec![10]
+    }, ManifestEntry{
+        uncompressed_offset: 4, uncompressed_len: 1, digest: vec![5]
+    }), encoder.manifest().entries);
+
+    let manifest = encoder.manifest().clone();
+    let entry = &manifest.entries[0];
+    let mut verifier = hasher;
+    assert!(DigestManifest::verify(entry, b"\x04\x03\x02\x01", &mut verifier));
+    assert!(!DigestManifest::verify(entry, b"\x04\x03\x02\x02", &mut verifier));
+
+    encoder.write_manifest().unwrap();
+    let (compressed, _) = encoder.into_inner();
+    let payload_len = manifest.to_chunk_payloads()[0].len();
+    let trailer = &compressed[compressed.len() - HEADER_SIZE - payload_len..];
+    assert_eq!((MANIFEST_CHUNK_TYPE, payload_len),
+               parse_header(&trailer[..HEADER_SIZE]));
+    let mut decoded = DigestManifest::new();
+    decoded.extend_from_chunk_payload(&trailer[HEADER_SIZE..]).unwrap();
+    assert_eq!(manifest, decoded);
+}
+
+#[test]
+fn create_and_open_files() {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+
+    let path = env::temp_dir().join("snappy_framed_create_and_open.sz");
+    {
+        let mut encoder = SnappyFramedEncoder::create(&path).unwrap();
+        encoder.write_all(b"Hello, file!").unwrap();
+        encoder.flush().unwrap();
+    }
+    let mut output = vec!();
+    SnappyFramedDecoder::open(&path).unwrap()
+        .read_to_end(&mut output).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(b"Hello, file!" as &[u8], &output as &[u8]);
+}
+
+#[test]
+fn decode_pushed_data() {
+    use test_helpers::*;
+
+    let input = random_data(200_000);
+    let mut compressed = vec!();
+    {
+        let mut encoder = SnappyFramedEncoder::new(&mut compressed).unwrap();
+        encoder.write_all(&input).unwrap();
+        encoder.flush().unwrap();
+    }
+
+    let mut decoder = SnappyFramedDecoder::new(vec!(), CrcMode::Verify);
+    for piece in compressed.chunks(1000) {
+        decoder.write_all(piece).unwrap();
+    }
+    assert_eq!(input, decoder.finish().unwrap());
+
+    let mut decoder = SnappyFramedDecoder::new(vec!(), CrcMode::Verify);
+    decoder.write_all(&compressed[..compressed.len() - 1]).unwrap();
+    assert!(decoder.finish().is_err());
+
+    let mut corrupt = compressed.clone();
+    *corrupt.last_mut().unwrap() ^= 0x01;
+    let mut decoder = SnappyFramedDecoder::new(vec!(), CrcMode::Verify);
+    assert!(decoder.write_all(&corrupt).is_err());
+}
+
+#[test]
+fn decoding_writer_takes_nothing_after_error() {
+    let mut compressed = vec!();
+    {
+        let mut encoder = SnappyFramedEncoder::new(&mut compressed).unwrap();
+        encoder.write_all(b"Hello, ").unwrap();
+        encoder.flush().unwrap();
+        encoder.write_all(b"world!").unwrap();
+        encoder.flush().unwrap();
+    }
+    *compressed.last_mut().unwrap() ^= 0x01;
+
+    // We take everything up to the corrupt chunk, and nothing after it.
+    let mut decoder = SnappyFramedDecoder::new(vec!(), CrcMode::Verify);
+    let taken = decoder.write(&compressed).unwrap();
+    assert!(taken < compressed.len());
+    assert!(decoder.write(&compressed[taken..]).is_err());
+    assert!(decoder.write(&compressed[taken..]).is_err());
+    assert_eq!(b"Hello, " as &[u8], &decoder.finish().unwrap() as &[u8]);
+}
+
+#[test]
+fn decoding_writer_resumes_partial_output() {
+    use test_helpers::*;
+
+    /// Takes at most 1000 bytes per write, and fails every other write.
+    struct Choppy {
+        data: Vec<u8>,
+        fail: bool
+    }
+
+    impl Write for Choppy {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.fail = !self.fail;
+            if self.fail {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "choppy"))
+            } else {
+                let len = min(buf.len(), 1000);
+                self.data.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let input = mixed_data(100_000);
+    let mut compressed = SnappyFramedEncoder::new(vec!()).unwrap();
+    compressed.write_all(&input).unwrap();
+    let compressed = compressed.finish().unwrap();
+
+    // Retry after every error.  Data which reached the destination before
+    // a failure must not be written again.
+    let choppy = Choppy{data: vec!(), fail: false};
+    let mut decoder = SnappyFramedDecoder::new(choppy, CrcMode::Verify);
+    let mut rest = &compressed as &[u8];
+    while !rest.is_empty() {
+        if let Ok(taken) = decoder.write(rest) { rest = &rest[taken..]; }
+    }
+    while decoder.flush().is_err() {}
+    assert!(input == decoder.get_ref().data, "output was duplicated");
+}
+
+#[test]
+fn write_swapped_crcs() {
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.set_swapped_crcs(true);
+    encoder.write_all(b"Hello").unwrap();
+    encoder.flush().unwrap();
+    let (compressed, _) = encoder.into_inner();
+
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    assert!(decoder.read_to_end(&mut vec!()).is_err());
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::VerifySwapped);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"Hello" as &[u8], &output as &[u8]);
+}
+
+#[test]
+fn buffer_small_writes() {
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    for _ in 0..1000 {
+        encoder.write_all(b"a log line\n").unwrap();
+    }
+    assert_eq!(0, encoder.status().frames_written);
+    encoder.flush().unwrap();
+    assert_eq!(1, encoder.status().frames_written);
+    assert_eq!(11_000, encoder.status().bytes_consumed);
+
+    // Buffered input is topped up to a full chunk before we write any
+    // chunks directly from the caller's buffer.
+    let compression = Compression::default().with_chunk_size(100);
+    let mut encoder =
+        SnappyFramedEncoder::with_compression(vec!(), compression).unwrap();
+    encoder.write_all(&[b'x'; 50]).unwrap();
+    encoder.write_all(&[b'y'; 200]).unwrap();
+    assert_eq!(2, encoder.status().frames_written);
+    assert_eq!(200, encoder.status().bytes_consumed);
+    encoder.flush().unwrap();
+    let (compressed, status) = encoder.into_inner();
+    assert_eq!(3, status.frames_written);
+
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(250, output.len());
+}
+
+#[test]
+fn forward_flushes_keep_buffering() {
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.set_flush_mode(FlushMode::Forward);
+    encoder.write_all(b"Hello").unwrap();
+    encoder.flush().unwrap();
+    assert_eq!(0, encoder.status().frames_written);
+    assert_eq!(STREAM_IDENTIFIER.len(), encoder.get_ref().len());
+
+    encoder.flush_buffer().unwrap();
+    assert_eq!(1, encoder.status().frames_written);
+
+    encoder.set_flush_mode(FlushMode::Sync);
+    encoder.write_all(b", world!").unwrap();
+    encoder.flush().unwrap();
+    assert_eq!(2, encoder.status().frames_written);
+}
+
+#[test]
+fn finish_writes_buffered_input() {
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+
+    /// Fails the first flush.
+    struct FlakyFlush {
+        data: Vec<u8>,
+        flushes: u32
+    }
+
+    impl Write for FlakyFlush {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            if self.flushes == 1 {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "not yet"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    let dest = FlakyFlush{data: vec!(), flushes: 0};
+    let mut encoder = SnappyFramedEncoder::new(dest).unwrap();
+    encoder.set_flush_mode(FlushMode::Forward);
+    encoder.write_all(b"Hello").unwrap();
+    assert!(encoder.try_finish().is_err());
+    assert!(!encoder.is_poisoned());
+    let dest = encoder.finish().unwrap();
+    assert_eq!(2, dest.flushes);
+
+    let mut decoder = SnappyFramedDecoder::new(&dest.data as &[u8],
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"Hello" as &[u8], &output as &[u8]);
+}
+
+#[test]
+fn drop_writes_buffered_input() {
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+
+    let mut compressed = vec!();
+    {
+        let mut encoder = SnappyFramedEncoder::new(&mut compressed).unwrap();
+        encoder.set_flush_mode(FlushMode::Forward);
+        encoder.write_all(b"last record\n").unwrap();
+    }
+
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(b"last record\n" as &[u8], &output as &[u8]);
+
+    // `into_inner` discards buffered input instead.
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.write_all(b"discarded").unwrap();
+    let (compressed, _) = encoder.into_inner();
+    assert_eq!(STREAM_IDENTIFIER.len(), compressed.len());
+}
+
+#[test]
+fn build_encoder() {
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+    use test_helpers::*;
+
+    let input = mixed_data(1000);
+    let mut encoder = SnappyFramedEncoder::builder()
+        .with_chunk_size(1000)
+        .with_min_savings_percent(10)
+        .with_swapped_crcs(true)
+        .build(vec!())
+        .unwrap();
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // Random data is stored, and the rest is compressed.
+    let mut rest = &compressed[STREAM_IDENTIFIER.len()..];
+    let mut chunk_types = vec!();
+    while !rest.is_empty() {
+        let (chunk_type, chunk_len) = parse_header(&rest[..HEADER_SIZE]);
+        chunk_types.push(chunk_type);
+        rest = &rest[HEADER_SIZE + chunk_len..];
+    }
+    assert_eq!(0x01, chunk_types[0]);
+    assert!(chunk_types[1..].iter().all(|&t| t == 0x00));
+
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::VerifySwapped);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+
+    // Without an identifier, we can append to an existing stream.
+    let mut appended = compressed.clone();
+    SnappyFramedEncoder::builder()
+        .with_stream_identifier(false)
+        .with_swapped_crcs(true)
+        .build(&mut appended).unwrap()
+        .write_all(b"more").unwrap();
+    let mut decoder = SnappyFramedDecoder::new(&appended as &[u8],
+                                               CrcMode::VerifySwapped);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input.len() + 4, output.len());
+}
+
+#[test]
+fn store_incompressible_chunks() {
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+    use test_helpers::*;
+
+    let input = mixed_data(1000);
+    let mut encoder = SnappyFramedEncoder::builder()
+        .with_chunk_size(1000)
+        .build(vec!())
+        .unwrap();
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let (chunk_type, chunk_len) =
+        parse_header(&compressed[STREAM_IDENTIFIER.len()..][..HEADER_SIZE]);
+    assert_eq!((0x01, CRC_SIZE + 1000), (chunk_type, chunk_len));
+    assert!(compressed.len() < input.len());
+
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn presets_match_other_implementations() {
+    use test_helpers::*;
+
+    let input = repeat_data(b"compressible ", 10_000);
+    let mut encoder = SnappyFramedEncoder::builder()
+        .with_preset(Preset::SnappyJava)
+        .build(vec!())
+        .unwrap();
+    encoder.write_all(&input).unwrap();
+    encoder.flush().unwrap();
+    // 130,000 bytes in 32 KiB chunks.
+    assert_eq!(4, encoder.status().frames_written);
+
+    let spec = SnappyFramedEncoder::builder().with_preset(Preset::Spec);
+    assert_eq!(SnappyFramedEncoder::builder(), spec);
+    assert_eq!(SnappyFramedEncoder::builder().with_swapped_crcs(true),
+               spec.with_preset(Preset::PythonSnappy));
+}
+
+#[test]
+fn align_chunks_with_padding() {
+    use std::io::Read;
+    use read::SnappyFramedDecoder;
+    use test_helpers::*;
+
+    let input = mixed_data(10_000);
+    let mut encoder = SnappyFramedEncoder::builder()
+        .with_chunk_size(1000)
+        .build(vec!())
+        .unwrap();
+    encoder.set_alignment(Some(4096)).unwrap();
+    encoder.set_sequence_numbers(true);
+    for piece in input.chunks(700) {
+        encoder.write_all(piece).unwrap();
+        encoder.flush().unwrap();
+    }
+    encoder.write_skippable_chunk(0x90, &[0; 5000]).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // Every chunk fits between two boundaries or starts on one.
+    let mut pos = 0;
+    let mut padded = false;
+    while pos < compressed.len() {
+        let (chunk_type, chunk_len) =
+            parse_header(&compressed[pos..pos + HEADER_SIZE]);
+        let end = pos + HEADER_SIZE + chunk_len;
+        assert!(pos % 4096 == 0 || pos / 4096 == (end - 1) / 4096);
+        if chunk_type == 0xFE { padded = true; }
+        pos = end;
+    }
+    assert_eq!(compressed.len(), pos);
+    assert!(padded);
+
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+}
+
+#[test]
+fn resume_after_would_block() {
+    use test_helpers::*;
+
+    /// A non-blocking destination which is only ready every other time,
+    /// and then accepts at most 1000 bytes.
+    struct NonBlocking {
+        data: Vec<u8>,
+        ready: bool
+    }
+
+    impl Write for NonBlocking {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                          "not ready"));
+            }
+            let len = min(1000, buf.len());
+            self.data.write(&buf[..len])
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let input = mixed_data(100_000);
+    let mut expected = SnappyFramedEncoder::new(vec!()).unwrap();
+    expected.write_all(&input).unwrap();
+    let expected = expected.finish().unwrap();
+
+    let dest = NonBlocking{data: vec!(), ready: true};
+    let mut encoder = SnappyFramedEncoder::new(dest).unwrap();
+    let mut remaining = &input[..];
+    while !remaining.is_empty() {
+        match encoder.write(&remaining[..min(7000, remaining.len())]) {
+            Ok(written) => remaining = &remaining[written..],
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => panic!("unexpected error: {}", err)
+        }
+    }
+    loop {
+        match encoder.flush() {
+            Ok(()) => break,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => panic!("unexpected error: {}", err)
+        }
+    }
+    assert!(!encoder.is_poisoned());
+    assert_eq!(0, encoder.unwritten_len());
+    assert_eq!(expected.len() as u64, encoder.status().bytes_written);
+    assert_eq!(expected, encoder.finish().unwrap().data);
+}
+
+#[test]
+fn custom_raw_codec() {
+    use raw::DefaultCodec;
+    use read::SnappyFramedDecoder;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_helpers::*;
+
+    /// Counts the blocks passed to the default codec.
+    struct Counting(Arc<AtomicUsize>);
+
+    impl RawCodec for Counting {
+        fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            DefaultCodec.compress(data)
+        }
+
+        fn uncompress(&mut self, compressed: &[u8]) -> Option<Vec<u8>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            DefaultCodec.uncompress(compressed)
+        }
+    }
+
+    let input = repeat_data(b"compressible ", 10_000);
+    let compressed_count = Arc::new(AtomicUsize::new(0));
+    let mut encoder = SnappyFramedEncoder::new(vec!()).unwrap();
+    encoder.set_raw_codec(Counting(compressed_count.clone()));
+    encoder.write_all(&input).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert_eq!(2, compressed_count.load(Ordering::SeqCst));
+
+    let uncompressed_count = Arc::new(AtomicUsize::new(0));
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    decoder.set_raw_codec(Counting(uncompressed_count.clone()));
+    let mut output = vec!();
+    decoder.read_to_end(&mut output).unwrap();
+    assert_eq!(input, output);
+    assert_eq!(2, uncompressed_count.load(Ordering::SeqCst));
+
+    // A codec which produces garbage is caught by our CRCs.
+    struct Garbage;
+
+    impl RawCodec for Garbage {
+        fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+            DefaultCodec.compress(data)
+        }
+
+        fn uncompress(&mut self, compressed: &[u8]) -> Option<Vec<u8>> {
+            DefaultCodec.uncompress(compressed)
+                .map(|data| data.iter().map(|b| !b).collect())
+        }
+    }
+
+    let mut decoder = SnappyFramedDecoder::new(&compressed as &[u8],
+                                               CrcMode::Verify);
+    decoder.set_raw_codec(Garbage);
+    assert!(decoder.read_to_end(&mut vec!()).is_err());
+}